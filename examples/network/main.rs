@@ -24,7 +24,9 @@ use std::io;
 use rustc_serialize::json;
 use p2p3::network::bootstrap::BootstrapHandler;
 use p2p3::storage::storage_helper::GitAccess;
+use p2p3::storage::credentials::CredentialProvider;
 use std::str::FromStr;
+use std::path::PathBuf;
 use self::crust::PeerId;
 use self::rand::random;
 use p2p3::msg::Msg;
@@ -34,8 +36,8 @@ fn main() {
 
     let mut opts = Options::new();
     opts.optopt("u", "", "URL to the git repo to connect to", "URL");
-    opts.optopt("n", "", "Username", "Username");
-    opts.optopt("p", "", "Password", "Password");
+    opts.optopt("n", "", "Git username, when the remote URL does not name one", "Username");
+    opts.optopt("k", "ssh-key", "SSH private key to push with, instead of ~/.ssh/id_*", "KeyFile");
     opts.optopt("s", "", "Site id", "SiteId");
     opts.optopt("f", "", "File path to clone the git repo", "FilePath");
     opts.optopt("d", "port", "Port number", "PortNumber");
@@ -49,15 +51,14 @@ fn main() {
         return;
     };
     let git_url = matches.opt_str("u").unwrap();
-    let git_username = matches.opt_str("n").unwrap();
-    let git_password = matches.opt_str("p").unwrap();
+    let credentials = CredentialProvider::discover(matches.opt_str("n"), matches.opt_str("k").map(PathBuf::from));
     let port = matches.opt_str("d").unwrap();
     let port_number = port.parse::<u16>().unwrap();
     let local_path = matches.opt_str("f").unwrap();
     let p = env::current_dir().unwrap();
     let p2p3_url = format!("file://{}/front-end/index.html",p.display());
     let file_path = "c_code.c";
-    let git_access = GitAccess::new(git_url.clone(), local_path.clone(), file_path.to_string().clone(), git_username.clone(), git_password.clone());
+    let git_access = GitAccess::new(git_url.clone(), local_path.clone(), file_path.to_string().clone(), credentials);
 
     {
         let id: PeerId = random();
@@ -73,9 +74,17 @@ fn main() {
     };
 
     println!("Starting bootstrap");
-    let boot = BootstrapHandler::bootstrap_load();
+    let mut boot = match BootstrapHandler::bootstrap_load() {
+        Ok(boot) => boot,
+        Err(e) => {
+            println!("Bootstrap failed: {}", e);
+            return;
+        }
+    };
     let (mp,_) = MessagePasser::new();
-    boot.update_config(mp.clone());
+    if let Err(e) = boot.update_config(mp.clone()) {
+        println!("Could not update {}: {}", boot.config_file, e);
+    }
     println!("###############################");
     println!("My id is {:?}", mp.get_id());
     println!("###############################");
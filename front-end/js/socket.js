@@ -4,24 +4,107 @@ function getURLParameter(name) {
 
 var sock = new WebSocket("ws://127.0.0.1:" + portNumber +"/");
 var first_remove = true;
+// Set while the editor content is replaced wholesale, so the change events are not sent back.
+var replacing_content = false;
+var peer_files = {};
+// Runner and id of the run the run panel shows.
+var shown_run = null;
+// Path of the file in the editor, and the last run's diagnostics. Those on
+// the open file are kept in place by anchors while the buffer is edited.
+var open_path = null;
+var diagnostics = [];
+var diagnostic_anchors = [];
+// Ace mode of every language the mode list offers.
+var editor_modes = {};
 
 
 sock.onopen = function(event){
-  //TODO: connection is now open
+  sock.send(JSON.stringify({
+    variant: "ListFiles",
+    fields: [],
+  }));
 };
 
+function renderFileTree(files) {
+  var list = document.getElementById('files');
+  list.innerHTML = "";
+  files.forEach(function(path) {
+    var item = document.createElement('li');
+    item.textContent = path;
+    item.setAttribute('data-path', path);
+    item.onclick = function() { openFile(path); };
+    list.appendChild(item);
+  });
+  renderPeerFiles();
+}
+
+function renderPeerFiles() {
+  var items = document.querySelectorAll('#files li');
+  for (var i = 0; i < items.length; i++) {
+    var path = items[i].getAttribute('data-path');
+    var count = 0;
+    for (var peer in peer_files) {
+      if (peer_files[peer] == path) {
+        count++;
+      }
+    }
+    items[i].className = count > 0 ? "peer-open" : "";
+    items[i].title = count > 0 ? count + " peer(s) editing" : "";
+  }
+}
+
+function openFile(path) {
+  sock.send(JSON.stringify({
+    variant: "OpenFile",
+    fields: [path],
+  }));
+}
+
 sock.onmessage = function(event){
   var json = event.data,
   obj = eval("(" + json + ')');
   console.log(obj);
   switch (obj.variant) {
     case "InsertString":
-      editor.setValue(obj.fields[1]); //TODO
+      replacing_content = true;
+      editor.setValue(obj.fields[1], -1); //TODO
+      replacing_content = false;
+      renderDiagnostics();
+      break;
+    case "ActiveFile":
+      open_path = obj.fields[0];
+      renderDiagnostics();
+      break;
+    case "Diagnostics":
+      diagnostics = obj.fields[0];
+      renderDiagnostics();
+      renderDiagnosticList();
+      break;
+    case "FileTree":
+      renderFileTree(obj.fields[0]);
+      break;
+    case "PeerFile":
+      var peer = obj.fields[0]._field0[0];
+      if (obj.fields[1] == "") {
+        delete peer_files[peer];
+      } else {
+        peer_files[peer] = obj.fields[1];
+      }
+      renderPeerFiles();
       break;
     case "Output":
       console.log(obj.fields[0]);
       var output = document.getElementById('output');
-      output.innerHTML = obj.fields[0];
+      output.textContent = obj.fields[0];
+      break;
+    case "BuildTargets":
+      renderBuildTargets(obj.fields[0]);
+      break;
+    case "Languages":
+      renderLanguages(obj.fields[0]);
+      break;
+    case "RunUpdate":
+      showRunUpdate(obj.fields[0], obj.fields[1], obj.fields[2]);
       break;
     case "InsertChar":
       console.log(obj);
@@ -37,10 +120,51 @@ sock.onmessage = function(event){
       break;
     case "DisableEditing":
       console.log("Disabling editing");
-      editor.setReadOnly(true);
-      editor.container.style.pointerEvents="none"
-      editor.renderer.setStyle("disabled", true)
-      editor.blur()
+      setEditable(false);
+      if (obj.fields[0] != "") {
+        document.getElementById('output').textContent = obj.fields[0];
+      }
+      break;
+    case "EnableEditing":
+      setEditable(true);
+      break;
+    case "EditRequested":
+      renderEditRequest(obj.fields[0]);
+      break;
+    case "RunRequested":
+      renderRunRequest(obj.fields[0], obj.fields[1], obj.fields[2], obj.fields[3]);
+      break;
+    case "EditRights":
+      renderEditRights(obj.fields[0]);
+      break;
+    case "Role":
+      applyRole(obj.fields[0]);
+      break;
+    case "Committed":
+      var output = document.getElementById('output');
+      output.textContent = obj.fields[0] == "" ? "Saved" : "Committed at " + obj.fields[0];
+      break;
+    case "HistoryEntries":
+      renderHistory(obj.fields[0]);
+      break;
+    case "DiffOutput":
+      var output = document.getElementById('output');
+      var pre = document.createElement('pre');
+      pre.textContent = obj.fields[0] == "" ? "No changes" : obj.fields[0];
+      output.innerHTML = "";
+      output.appendChild(pre);
+      break;
+    case "Pulled":
+      var output = document.getElementById('output');
+      if (obj.fields[0].length == 0) {
+        output.textContent = "Pulled from origin";
+      } else {
+        output.textContent = "Pulled from origin, resolve the conflicts in " + obj.fields[0].join(", ");
+      }
+      break;
+    case "ExternalChange":
+      var output = document.getElementById('output');
+      output.textContent = obj.fields[0] + " was changed outside of the session";
       break;
     case "UpdatePeerCursor":
       console.log("UpdatePeerCursor");
@@ -57,6 +181,110 @@ sock.onmessage = function(event){
 }
 
 
+function setEditable(editable) {
+  editor.setReadOnly(!editable);
+  editor.container.style.pointerEvents = editable ? "" : "none";
+  editor.renderer.setStyle("disabled", !editable);
+  if (!editable) {
+    editor.blur();
+  }
+}
+
+// Editors and owners may type, run and commit, everyone else only reads.
+function applyRole(role) {
+  var editing = role == "editor" || role == "owner";
+  setEditable(editing);
+  document.getElementById('compileButton').disabled = !editing;
+  document.getElementById('commitButton').disabled = !editing;
+  document.getElementById('buildButton').disabled = !editing;
+  document.getElementById('role').textContent = role;
+}
+
+function peerName(peer) {
+  return String(peer._field0[0]).substring(0, 12);
+}
+
+function requestEditOnClick() {
+    sock.send(JSON.stringify({
+      variant: "RequestEdit",
+      fields: [],
+    }));
+    document.getElementById('output').textContent = "Asked the editors for edit rights";
+}
+
+// An empty answer grants the rights until they are revoked.
+function grantEdit(peer) {
+    var answer = prompt("Minutes until the rights expire, empty for no limit", "");
+    if (answer === null) {
+      return;
+    }
+    var minutes = answer.trim() == "" ? null : parseInt(answer, 10);
+    sock.send(JSON.stringify({
+      variant: "GrantEdit",
+      fields: [peer, minutes],
+    }));
+}
+
+function revokeEdit(peer) {
+    sock.send(JSON.stringify({
+      variant: "RevokeEdit",
+      fields: [peer],
+    }));
+}
+
+function renderEditRequest(peer) {
+  var list = document.getElementById('requests');
+  var item = document.createElement('li');
+  item.textContent = peerName(peer) + " asks to edit ";
+  var approve = document.createElement('button');
+  approve.textContent = "Approve";
+  approve.onclick = function() { grantEdit(peer); list.removeChild(item); };
+  item.appendChild(approve);
+  var dismiss = document.createElement('button');
+  dismiss.textContent = "Dismiss";
+  dismiss.onclick = function() { list.removeChild(item); };
+  item.appendChild(dismiss);
+  list.appendChild(item);
+}
+
+// Runs the peer's code on this machine, in the sandbox, only once accepted.
+function renderRunRequest(peer, id, mode, path) {
+  var list = document.getElementById('requests');
+  var item = document.createElement('li');
+  item.textContent = peerName(peer) + " asks to run " + path + " (" + mode + ") here ";
+  var answer = function(variant) {
+    sock.send(JSON.stringify({
+      variant: variant,
+      fields: [peer, id],
+    }));
+    list.removeChild(item);
+  };
+  var accept = document.createElement('button');
+  accept.textContent = "Accept";
+  accept.onclick = function() { answer("AcceptRun"); };
+  item.appendChild(accept);
+  var decline = document.createElement('button');
+  decline.textContent = "Decline";
+  decline.onclick = function() { answer("DeclineRun"); };
+  item.appendChild(decline);
+  list.appendChild(item);
+}
+
+function renderEditRights(grants) {
+  var list = document.getElementById('rights');
+  list.innerHTML = "";
+  grants.forEach(function(grant) {
+    var item = document.createElement('li');
+    var until = grant.expires === null ? "until revoked" : "until " + new Date(grant.expires * 1000).toLocaleTimeString();
+    item.textContent = peerName(grant.holder) + " may edit " + until + " ";
+    var revoke = document.createElement('button');
+    revoke.textContent = "Revoke";
+    revoke.onclick = function() { revokeEdit(grant.holder); };
+    item.appendChild(revoke);
+    list.appendChild(item);
+  });
+}
+
 // Convert a WOOT operation to an ACE delta object for WOOT index i:
 function asDelta(ch, isVisible, i) {
   var start_pos = pos(i);
@@ -69,6 +297,124 @@ function asDelta(ch, isVisible, i) {
   };
 }
 
+// Stdout and stderr lines keep their stream as class, so stderr stands out.
+function appendOutputLine(stream, line) {
+  var lines = document.getElementById('runLines');
+  var span = document.createElement('span');
+  span.className = stream.toLowerCase();
+  span.textContent = line;
+  lines.appendChild(span);
+  lines.scrollTop = lines.scrollHeight;
+}
+
+function describeRun(report) {
+  var secs = (report.millis / 1000).toFixed(2) + "s";
+  var ending = report.ending;
+  var text;
+  switch (ending.variant) {
+    case "Exited":
+      text = report.step + " exited with code " + ending.fields[0];
+      break;
+    case "Killed":
+      text = report.step + " was killed by signal " + ending.fields[0] + ", it may have hit the CPU or memory limit";
+      break;
+    case "TimedOut":
+      text = report.step + " ran out of time";
+      break;
+    default:
+      text = report.step + " was cancelled";
+  }
+  text += " after " + secs;
+  if (report.truncated) {
+    text += ", some output was dropped";
+  }
+  return text;
+}
+
+function annotationType(severity) {
+  switch (severity) {
+    case "Error": return "error";
+    case "Warning": return "warning";
+    default: return "info";
+  }
+}
+
+function updateAnnotations() {
+  editor.getSession().setAnnotations(diagnostic_anchors.map(function(entry) {
+    var position = entry.anchor.getPosition();
+    return {
+      row: position.row,
+      column: position.column,
+      text: entry.diagnostic.message,
+      type: annotationType(entry.diagnostic.severity),
+    };
+  }));
+}
+
+function renderDiagnostics() {
+  diagnostic_anchors.forEach(function(entry) { entry.anchor.detach(); });
+  diagnostic_anchors = [];
+  var doc = editor.getSession().getDocument();
+  diagnostics.forEach(function(diagnostic) {
+    if (diagnostic.file != open_path) {
+      return;
+    }
+    var column = diagnostic.column === null ? 0 : diagnostic.column - 1;
+    var anchor = doc.createAnchor(diagnostic.line - 1, column);
+    anchor.on("change", updateAnnotations);
+    diagnostic_anchors.push({ anchor: anchor, diagnostic: diagnostic });
+  });
+  updateAnnotations();
+}
+
+// Every diagnostic, also those on files not open, clicking one opens its file.
+function renderDiagnosticList() {
+  var list = document.getElementById('diagnostics');
+  list.innerHTML = "";
+  diagnostics.forEach(function(diagnostic) {
+    var item = document.createElement('li');
+    item.className = annotationType(diagnostic.severity);
+    var where = diagnostic.file + ":" + diagnostic.line + (diagnostic.column === null ? "" : ":" + diagnostic.column);
+    item.textContent = where + " " + diagnostic.message;
+    item.onclick = function() {
+      if (diagnostic.file != open_path) {
+        openFile(diagnostic.file);
+      }
+    };
+    list.appendChild(item);
+  });
+}
+
+// Our runs and our peers' share the panel, a newly started run replaces
+// the one shown.
+function showRunUpdate(runner, id, event) {
+  var key = runner._field0[0] + ":" + id;
+  if (event.variant == "Started") {
+    shown_run = key;
+    document.getElementById('runTitle').textContent = peerName(runner) + " ran " + event.fields[0];
+    document.getElementById('runLines').innerHTML = "";
+    document.getElementById('runStatus').textContent = "Running...";
+    return;
+  }
+  if (key != shown_run) {
+    return;
+  }
+  switch (event.variant) {
+    case "Line":
+      appendOutputLine(event.fields[0], event.fields[1]);
+      break;
+    case "Input":
+      appendOutputLine("stdin", event.fields[0]);
+      break;
+    case "Finished":
+      document.getElementById('runStatus').textContent = describeRun(event.fields[0]);
+      break;
+    case "Failed":
+      document.getElementById('runStatus').textContent = event.fields[0];
+      break;
+  }
+}
+
 function compileOnClick() {
     sock.send(JSON.stringify({
       variant: "Compile",
@@ -76,10 +422,127 @@ function compileOnClick() {
     }));
 }
 
+// Enter sends the line, with its newline, to the running program.
+function stdinOnKey(event) {
+  if (event.keyCode !== 13) {
+    return;
+  }
+  var input = document.getElementById('stdin');
+  sock.send(JSON.stringify({
+    variant: "Stdin",
+    fields: [input.value + "\n"],
+  }));
+  input.value = "";
+}
+
+function closeStdinOnClick() {
+  sock.send(JSON.stringify({
+    variant: "CloseStdin",
+    fields: [],
+  }));
+}
+
+// Nothing to build hides the build controls.
+function renderBuildTargets(targets) {
+  var select = document.getElementById('buildTarget');
+  select.innerHTML = "";
+  targets.forEach(function(target) {
+    var option = document.createElement('option');
+    option.value = target.name;
+    option.textContent = target.name + " (" + target.command.join(" ") + ")";
+    select.appendChild(option);
+  });
+  var display = targets.length == 0 ? "none" : "";
+  select.style.display = display;
+  document.getElementById('buildButton').style.display = display;
+}
+
+function buildOnClick() {
+    var target = document.getElementById('buildTarget').value;
+    if (target == "") {
+      return;
+    }
+    sock.send(JSON.stringify({
+      variant: "Build",
+      fields: [target],
+    }));
+}
+
+function stopOnClick() {
+    sock.send(JSON.stringify({
+      variant: "CancelRun",
+      fields: [],
+    }));
+}
+
 function commitOnClick() {
     console.log("Commit clicked");
+    var message = prompt("Commit message", "");
+    if (message === null) {
+      return;
+    }
     sock.send(JSON.stringify({
       variant: "Commit",
+      fields: [message],
+    }));
+}
+
+function historyOnClick() {
+    var list = document.getElementById('history');
+    if (list.innerHTML != "") {
+      list.innerHTML = "";
+      return;
+    }
+    sock.send(JSON.stringify({
+      variant: "History",
+      fields: [],
+    }));
+}
+
+function diffAgainst(sha) {
+    sock.send(JSON.stringify({
+      variant: "Diff",
+      fields: [sha],
+    }));
+}
+
+// Reverts the selected lines if there is a selection, the whole file otherwise.
+function revertTo(sha) {
+    var range = editor.getSelectionRange();
+    var lines = null;
+    if (!range.isEmpty()) {
+      lines = [range.start.row, range.end.row + 1];
+    }
+    sock.send(JSON.stringify({
+      variant: "Revert",
+      fields: [sha, lines],
+    }));
+}
+
+function renderHistory(entries) {
+  var list = document.getElementById('history');
+  list.innerHTML = "";
+  entries.forEach(function(entry) {
+    var item = document.createElement('li');
+    var label = document.createElement('div');
+    label.textContent = entry.sha.substring(0, 7) + " " + entry.summary + " (" + entry.author + ")";
+    label.title = new Date(entry.time * 1000).toLocaleString();
+    item.appendChild(label);
+    var diff = document.createElement('button');
+    diff.textContent = "Diff";
+    diff.onclick = function() { diffAgainst(entry.sha); };
+    item.appendChild(diff);
+    var revert = document.createElement('button');
+    revert.textContent = "Revert";
+    revert.onclick = function() { revertTo(entry.sha); };
+    item.appendChild(revert);
+    list.appendChild(item);
+  });
+}
+
+function pullOnClick() {
+    sock.send(JSON.stringify({
+      variant: "Pull",
       fields: [],
     }));
 }
@@ -100,6 +563,9 @@ function delete_char_at_position(index) {
 
 editor.getSession().on('change', function(e) {
   console.log(e);
+  if (replacing_content) {
+    return;
+  }
     switch (e.action) {
       case "insert":
       var index = idx(e.start);
@@ -147,8 +613,21 @@ editor.getSession().selection.on('changeCursor', function(e) {
   }));
 });
 
+function renderLanguages(languages) {
+  var select = document.getElementById('mode');
+  select.innerHTML = '<option value=""></option>';
+  editor_modes = {};
+  languages.forEach(function(language) {
+    editor_modes[language.mode] = language.editor;
+    var option = document.createElement('option');
+    option.value = language.mode;
+    option.textContent = language.name;
+    select.appendChild(option);
+  });
+}
+
 function getSelectedMode(mode) {
-  editor.session.setMode("ace/mode/" + mode.value);
+  editor.session.setMode("ace/mode/" + (editor_modes[mode.value] || mode.value));
   sock.send(JSON.stringify({
     variant: "Mode",
     fields: [mode.value],
@@ -0,0 +1,206 @@
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use rand::random;
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json;
+use sodiumoxide::crypto::hash::sha256;
+use compile::languages::{Language, find_program};
+use compile::diagnostics::Diagnostic;
+
+/// Under the home directory, shared by every session of the user. Not the
+/// temp dir, where another user could plant programs for us to run.
+pub const CACHE_DIR: &'static str = ".p2p3/build-cache";
+// Builds kept, the least recently used go first.
+const MAX_ENTRIES: usize = 64;
+const PROGRAM: &'static str = "program";
+const DIAGNOSTICS: &'static str = "diagnostics.json";
+// Rewritten whenever the entry is stored or restored, pruning goes by its time.
+const LAST_USED: &'static str = "last-used";
+
+// Changes whenever the compiler does, e.g. after an upgrade.
+fn program_stamp(program: &str) -> String {
+    let found = find_program(program).and_then(|path| fs::metadata(&path).ok().map(|meta| (path, meta)));
+    match found {
+        Some((path, meta)) => {
+            let modified = meta.modified().ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_secs());
+            format!("{} {} {}", path.display(), meta.len(), modified)
+        },
+        None => String::new(),
+    }
+}
+
+/// Names the build of `source` with `language`: the same code compiled by
+/// the same toolchain gets the same key. None when nothing is compiled.
+pub fn key(language: &Language, source: &str) -> Option<String> {
+    language.compile.as_ref().map(|compile| {
+        let mut hashed = vec![language.mode.clone(), language.extension.clone(), program_stamp(&compile[0])];
+        hashed.extend(compile.iter().cloned());
+        hashed.extend(language.env.iter().map(|(name, value)| format!("{}={}", name, value)));
+        hashed.push(source.to_string());
+        sha256::hash(hashed.join("\0").as_bytes()).0.to_hex()
+    })
+}
+
+/// Programs compiled before, by key.
+pub struct BuildCache {
+    dir: PathBuf,
+}
+
+impl BuildCache {
+    pub fn new(dir: &Path) -> BuildCache {
+        BuildCache { dir: dir.to_path_buf() }
+    }
+
+    pub fn shared() -> BuildCache {
+        BuildCache::new(&env::home_dir().unwrap_or(env::temp_dir()).join(CACHE_DIR))
+    }
+
+    /// Copies the program built under `key` to `exe` and gives back what
+    /// the compiler said then. None if it was never built.
+    pub fn restore(&self, key: &str, exe: &Path) -> Option<Vec<Diagnostic>> {
+        let entry = self.dir.join(key);
+        let mut s = String::new();
+        if File::open(entry.join(DIAGNOSTICS)).and_then(|mut file| file.read_to_string(&mut s)).is_err() {
+            return None;
+        }
+        let diagnostics = match json::decode(&s) {
+            Ok(diagnostics) => diagnostics,
+            Err(_) => return None,
+        };
+        match fs::copy(entry.join(PROGRAM), exe) {
+            Ok(_) => {
+                let _ = touch(&entry);
+                Some(diagnostics)
+            },
+            Err(_) => None,
+        }
+    }
+
+    /// Keeps the program at `exe` under `key`. Runs from other sessions may
+    /// store the same key at once, so the entry is written aside and renamed
+    /// into place.
+    pub fn store(&self, key: &str, exe: &Path, diagnostics: &[Diagnostic]) -> io::Result<()> {
+        let entry = self.dir.join(key);
+        if entry.exists() {
+            return Ok(());
+        }
+        let n: u64 = random();
+        let staging = self.dir.join(format!(".{}-{:016x}", key, n));
+        try!(fs::create_dir_all(&staging));
+        let written = write_entry(&staging, exe, diagnostics).and_then(|_| fs::rename(&staging, &entry));
+        if written.is_err() {
+            let _ = fs::remove_dir_all(&staging);
+            if entry.exists() {
+                return Ok(());
+            }
+        }
+        try!(written);
+        self.prune(MAX_ENTRIES);
+        Ok(())
+    }
+
+    // Keeps the `keep` entries used last.
+    fn prune(&self, keep: usize) {
+        let mut entries: Vec<_> = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries.filter_map(|entry| entry.ok())
+                .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+                .filter_map(|entry| {
+                    // Entries from before LAST_USED go by their own time.
+                    fs::metadata(entry.path().join(LAST_USED)).or_else(|_| entry.metadata())
+                        .and_then(|meta| meta.modified()).ok().map(|time| (time, entry.path()))
+                })
+                .collect(),
+            Err(_) => return,
+        };
+        if entries.len() <= keep {
+            return;
+        }
+        entries.sort();
+        let excess = entries.len() - keep;
+        for &(_, ref path) in &entries[..excess] {
+            let _ = fs::remove_dir_all(path);
+        }
+    }
+}
+
+// Writing the file is what moves its modification time, std can't set it.
+fn touch(entry: &Path) -> io::Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+    let mut file = try!(File::create(entry.join(LAST_USED)));
+    file.write_all(format!("{}\n", now).as_bytes())
+}
+
+fn write_entry(staging: &Path, exe: &Path, diagnostics: &[Diagnostic]) -> io::Result<()> {
+    try!(fs::copy(exe, staging.join(PROGRAM)));
+    let encoded = try!(json::encode(&diagnostics).map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e))));
+    let mut file = try!(File::create(staging.join(DIAGNOSTICS)));
+    try!(file.write_all(encoded.as_bytes()));
+    touch(staging)
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::thread;
+    use std::time::Duration;
+    use rand::random;
+    use compile::languages::Languages;
+    use compile::diagnostics::{Diagnostic, Severity};
+
+    #[test]
+    fn unchanged_code_restores_its_build(){
+        let mut c = Languages::builtin().get("c_cpp").unwrap().clone();
+        let first = key(&c, "int main() { return 0; }").unwrap();
+        assert_eq!(key(&c, "int main() { return 0; }").unwrap(), first);
+        assert!(key(&c, "int main() { return 1; }").unwrap() != first);
+        c.env.insert("CFLAGS".to_string(), "-O2".to_string());
+        assert!(key(&c, "int main() { return 0; }").unwrap() != first);
+        assert!(key(Languages::builtin().get("python").unwrap(), "print(1)").is_none());
+
+        let n: u32 = random();
+        let dir = env::temp_dir().join(format!("p2p3_cache_{}", n));
+        let cache = BuildCache::new(&dir.join("cache"));
+        fs::create_dir_all(&dir).unwrap();
+        let exe = dir.join("main");
+        File::create(&exe).unwrap().write_all(b"program").unwrap();
+        let warning = Diagnostic { file: "a.c".to_string(), line: 2, column: Some(9), severity: Severity::Warning,
+                                   message: "unused variable 'x'".to_string() };
+        assert!(cache.restore(&first, &dir.join("copy")).is_none());
+        cache.store(&first, &exe, &[warning.clone()]).unwrap();
+        assert_eq!(cache.restore(&first, &dir.join("copy")), Some(vec![warning]));
+        let mut copied = String::new();
+        File::open(dir.join("copy")).unwrap().read_to_string(&mut copied).unwrap();
+        assert_eq!(copied, "program");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restored_builds_outlive_older_unused_ones(){
+        let n: u32 = random();
+        let dir = env::temp_dir().join(format!("p2p3_cache_{}", n));
+        let cache = BuildCache::new(&dir.join("cache"));
+        fs::create_dir_all(&dir).unwrap();
+        let exe = dir.join("main");
+        File::create(&exe).unwrap().write_all(b"program").unwrap();
+        cache.store("hot", &exe, &[]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        cache.store("cold", &exe, &[]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(cache.restore("hot", &dir.join("copy")).is_some());
+        cache.prune(1);
+        assert!(cache.restore("hot", &dir.join("copy")).is_some());
+        assert!(cache.restore("cold", &dir.join("copy")).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
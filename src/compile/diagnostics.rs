@@ -0,0 +1,276 @@
+use std::path::Path;
+use rustc_serialize::json::Json;
+
+#[derive(Clone, Copy, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+/// One compiler or interpreter complaint about a spot in a file. Lines and
+/// columns start at 1.
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: u32,
+    pub column: Option<u32>,
+    pub severity: Severity,
+    pub message: String,
+}
+
+fn severity_of(level: &str) -> Option<Severity> {
+    match level {
+        "error" | "fatal error" => Some(Severity::Error),
+        "warning" => Some(Severity::Warning),
+        "note" | "help" => Some(Severity::Note),
+        _ => None,
+    }
+}
+
+// Splits "error: expected ';'" into its severity and message.
+fn split_severity(rest: &str) -> (Severity, String) {
+    if let Some(i) = rest.find(": ") {
+        let level = &rest[..i];
+        let level = if level.starts_with("error[") { "error" } else { level };
+        if let Some(severity) = severity_of(level) {
+            return (severity, rest[i + 2..].trim().to_string());
+        }
+    }
+    (Severity::Error, rest.trim().to_string())
+}
+
+// The digits at the start of `s`, if a ':' or the end follows them.
+fn number_at(s: &str) -> Option<(u32, &str)> {
+    let digits = s.chars().take_while(|c| c.is_digit(10)).count();
+    if digits == 0 || !(s[digits..].is_empty() || s[digits..].starts_with(':')) {
+        return None;
+    }
+    s[..digits].parse().ok().map(|n| (n, if s.len() > digits { &s[digits + 1..] } else { "" }))
+}
+
+// "main.c:3:5: rest", "main.go:3: rest" or "lua: main.lua:3: rest", as gcc,
+// clang, go, lua and ruby print them.
+fn file_line(line: &str) -> Option<(String, u32, Option<u32>, String)> {
+    for (i, _) in line.match_indices(':') {
+        let file = match line[..i].rsplit(' ').next() {
+            Some(f) if f.contains('.') || f.contains('/') => f,
+            _ => continue,
+        };
+        let (number, rest) = match number_at(&line[i + 1..]) {
+            Some(found) => found,
+            None => continue,
+        };
+        let (column, rest) = match number_at(rest) {
+            Some((column, rest)) => (Some(column), rest),
+            None => (None, rest),
+        };
+        return Some((file.to_string(), number, column, rest.trim().to_string()));
+    }
+    None
+}
+
+// A rustc message in JSON, bare from --error-format=json or wrapped by
+// cargo's --message-format=json.
+fn from_json(line: &str) -> Option<Diagnostic> {
+    let json = match Json::from_str(line) {
+        Ok(json) => json,
+        Err(_) => return None,
+    };
+    let message = json.find("message").and_then(|m| if m.is_object() { Some(m) } else { None }).unwrap_or(&json);
+    let span = message.find("spans").and_then(|s| s.as_array()).and_then(|spans| {
+        spans.iter().find(|s| s.find("is_primary").and_then(|p| p.as_boolean()).unwrap_or(false))
+    });
+    match (message.find("level").and_then(|l| l.as_string()), message.find("message").and_then(|m| m.as_string()), span) {
+        (Some(level), Some(text), Some(span)) => {
+            let file = span.find("file_name").and_then(|f| f.as_string());
+            let number = span.find("line_start").and_then(|l| l.as_u64());
+            match (file, number) {
+                (Some(file), Some(number)) => Some(Diagnostic {
+                    file: file.to_string(),
+                    line: number as u32,
+                    column: span.find("column_start").and_then(|c| c.as_u64()).map(|c| c as u32),
+                    severity: severity_of(level).unwrap_or(Severity::Error),
+                    message: text.to_string(),
+                }),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+// '  File "main.py", line 3, in <module>'
+fn python_location(line: &str) -> Option<(String, u32)> {
+    if !line.starts_with("  File \"") {
+        return None;
+    }
+    let rest = &line[8..];
+    rest.find('"').and_then(|end| {
+        let after = &rest[end + 1..];
+        if !after.starts_with(", line ") {
+            return None;
+        }
+        let number: String = after[7..].chars().take_while(|c| c.is_digit(10)).collect();
+        number.parse().ok().map(|n| (rest[..end].to_string(), n))
+    })
+}
+
+/// Picks the diagnostics out of everything a compiler or program printed in
+/// `dir`. Paths come out relative to it, other files are left out.
+pub fn parse(output: &str, dir: &Path) -> Vec<Diagnostic> {
+    let mut found = vec![];
+    // rustc's "error[E0425]: ..." waiting for its "--> file:line:col" line.
+    let mut rust_message: Option<(Severity, String)> = None;
+    // Where the frames of a Python traceback point, the innermost last.
+    let mut python_frames: Vec<(String, u32)> = vec![];
+    for line in output.lines() {
+        if line.starts_with('{') {
+            if let Some(diagnostic) = from_json(line) {
+                found.push(diagnostic);
+            }
+            continue;
+        }
+        let trimmed = line.trim_left();
+        if trimmed.starts_with("--> ") {
+            if let (Some((severity, message)), Some((file, number, column, _))) =
+                (rust_message.take(), file_line(&trimmed[4..])) {
+                found.push(Diagnostic { file: file, line: number, column: column, severity: severity, message: message });
+            }
+            continue;
+        }
+        if let Some(frame) = python_location(line) {
+            python_frames.push(frame);
+            continue;
+        }
+        if line.starts_with("Traceback") {
+            python_frames.clear();
+            continue;
+        }
+        if !line.starts_with(' ') && !python_frames.is_empty() && line.contains(':') {
+            // The innermost frame may be in a library, the user wants their own.
+            if let Some((file, number)) = python_frames.drain(..).rev().find(|f| within(&f.0, dir).is_some()) {
+                found.push(Diagnostic { file: file, line: number, column: None, severity: Severity::Error,
+                                        message: line.trim().to_string() });
+            }
+            continue;
+        }
+        if line.starts_with("error") || line.starts_with("warning") {
+            let (severity, message) = split_severity(line);
+            rust_message = Some((severity, message));
+            continue;
+        }
+        // "In file included from main.c:1:" and the like have nothing to say.
+        if let Some((file, number, column, rest)) = file_line(line).into_iter().find(|f| !f.3.is_empty()) {
+            let (severity, message) = split_severity(&rest);
+            found.push(Diagnostic { file: file, line: number, column: column, severity: severity, message: message });
+        }
+    }
+    relative_to(found, dir)
+}
+
+// `file` relative to `dir`, the directory the tool ran in. None when it is
+// outside of it.
+fn within(file: &str, dir: &Path) -> Option<String> {
+    let path = Path::new(file);
+    let relative = if path.is_absolute() {
+        match path.strip_prefix(dir) {
+            Ok(relative) => relative.to_string_lossy().into_owned(),
+            Err(_) => return None,
+        }
+    } else {
+        file.trim_left_matches("./").to_string()
+    };
+    Some(relative.replace('\\', "/"))
+}
+
+// Makes the paths relative to `dir` and drops the diagnostics about files
+// outside of it.
+fn relative_to(diagnostics: Vec<Diagnostic>, dir: &Path) -> Vec<Diagnostic> {
+    diagnostics.into_iter().filter_map(|mut d| {
+        within(&d.file, dir).map(|file| {
+            d.file = file;
+            d
+        })
+    }).collect()
+}
+
+/// The character offset of a 1 based line and column in `text`.
+pub fn offset_of(text: &str, line: u32, column: Option<u32>) -> usize {
+    let mut offset = 0;
+    for (i, l) in text.split('\n').enumerate() {
+        if i + 1 == line as usize {
+            let column = column.unwrap_or(1).saturating_sub(1) as usize;
+            return offset + ::std::cmp::min(column, l.chars().count());
+        }
+        offset += l.chars().count() + 1;
+    }
+    text.chars().count()
+}
+
+/// The 1 based line and column of a character offset in `text`.
+pub fn position_of(text: &str, offset: usize) -> (u32, u32) {
+    let (mut line, mut column) = (1, 1);
+    for c in text.chars().take(offset) {
+        if c == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+    }
+    (line, column)
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn reads_the_usual_formats(){
+        let gcc = "/tmp/run/main.c: In function 'main':\n\
+                   /tmp/run/main.c:4:5: error: expected ';' before 'return'\n\
+                   /tmp/run/main.c:2:9: warning: unused variable 'x' [-Wunused-variable]\n";
+        let dir = Path::new("/tmp/run");
+        let found = parse(gcc, dir);
+        assert_eq!(found, vec![
+            Diagnostic { file: "main.c".to_string(), line: 4, column: Some(5), severity: Severity::Error,
+                         message: "expected ';' before 'return'".to_string() },
+            Diagnostic { file: "main.c".to_string(), line: 2, column: Some(9), severity: Severity::Warning,
+                         message: "unused variable 'x' [-Wunused-variable]".to_string() },
+        ]);
+
+        let rustc = "error[E0425]: cannot find value `y` in this scope\n --> src/main.rs:3:13\n  |\n";
+        assert_eq!(parse(rustc, dir), vec![Diagnostic { file: "src/main.rs".to_string(), line: 3, column: Some(13),
+                                                        severity: Severity::Error,
+                                                        message: "cannot find value `y` in this scope".to_string() }]);
+
+        let json = r#"{"reason":"compiler-message","message":{"message":"unused import","level":"warning","spans":[{"file_name":"src/lib.rs","line_start":1,"column_start":5,"is_primary":true}]}}"#;
+        assert_eq!(parse(json, dir)[0].severity, Severity::Warning);
+        assert_eq!(parse(json, dir)[0].file, "src/lib.rs");
+
+        let python = "Traceback (most recent call last):\n  File \"/tmp/run/main.py\", line 2, in <module>\n    foo()\n\
+                      NameError: name 'foo' is not defined\n";
+        let found = parse(python, dir);
+        assert_eq!((&found[0].file[..], found[0].line, &found[0].message[..]),
+                   ("main.py", 2, "NameError: name 'foo' is not defined"));
+
+        let python = "Traceback (most recent call last):\n  File \"/tmp/run/main.py\", line 3, in <module>\n    json.loads('{')\n\
+                      \x20 File \"/usr/lib/python3/json/__init__.py\", line 346, in loads\n    return _default_decoder.decode(s)\n\
+                      json.decoder.JSONDecodeError: Expecting property name\n";
+        let found = parse(python, dir);
+        assert_eq!(found.len(), 1);
+        assert_eq!((&found[0].file[..], found[0].line), ("main.py", 3));
+
+        assert!(parse("Hello World\n12:30:45: tick\n", dir).is_empty());
+    }
+
+    #[test]
+    fn offsets_and_positions_agree(){
+        let text = "ab\ncde\n";
+        assert_eq!(offset_of(text, 2, Some(2)), 4);
+        assert_eq!(position_of(text, 4), (2, 2));
+        assert_eq!(offset_of(text, 9, None), 7);
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use rustc_serialize::json::Json;
+
+/// Looked for under the home directory when no languages file is given.
+pub const LANGUAGES_FILE: &'static str = ".p2p3/languages.json";
+
+// What the editor offers before any file is read. {source} is the file the
+// buffer is written to, {exe} where a compiler should put the program and
+// {dir} the run's directory.
+#[cfg(not(windows))]
+const BUILTIN: &'static str = r#"{"languages": [
+    {"mode": "c_cpp", "name": "C", "extension": "c",
+     "compile": ["cc", "{source}", "-o", "{exe}"], "run": ["{exe}"]},
+    {"mode": "python", "name": "Python", "extension": "py", "run": ["python", "{source}"]},
+    {"mode": "ruby", "name": "Ruby", "extension": "rb", "run": ["ruby", "{source}"]},
+    {"mode": "lua", "name": "Lua", "extension": "lua", "run": ["lua", "{source}"]}
+]}"#;
+
+// cl.exe has to be on the PATH, run from a Visual Studio developer prompt.
+#[cfg(windows)]
+const BUILTIN: &'static str = r#"{"languages": [
+    {"mode": "c_cpp", "name": "C", "extension": "c",
+     "compile": ["cl.exe", "/nologo", "{source}", "/Fe{exe}"], "run": ["{exe}"]},
+    {"mode": "python", "name": "Python", "extension": "py", "run": ["python", "{source}"]},
+    {"mode": "ruby", "name": "Ruby", "extension": "rb", "run": ["ruby", "{source}"]},
+    {"mode": "lua", "name": "Lua", "extension": "lua", "run": ["lua", "{source}"]}
+]}"#;
+
+#[derive(Debug)]
+pub enum LanguageError {
+    Io(io::Error),
+    Malformed(String),
+}
+
+impl fmt::Display for LanguageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LanguageError::Io(ref e) => write!(f, "{}", e),
+            LanguageError::Malformed(ref why) => write!(f, "malformed languages file: {}", why),
+        }
+    }
+}
+
+impl From<io::Error> for LanguageError {
+    fn from(e: io::Error) -> LanguageError {
+        LanguageError::Io(e)
+    }
+}
+
+/// How to compile and run a buffer in one editor mode.
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Language {
+    // What the mode list sends, e.g. "c_cpp".
+    pub mode: String,
+    pub name: String,
+    // Ace mode to highlight with, the same as `mode` unless given.
+    pub editor: String,
+    pub extension: String,
+    // Program and arguments, skipped for interpreted languages.
+    pub compile: Option<Vec<String>>,
+    pub run: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    // Address space for the compiler and program, instead of the sandbox default.
+    pub memory_mb: Option<u64>,
+}
+
+/// The file `program` starts, looked up on the PATH unless it is a path.
+pub fn find_program(program: &str) -> Option<PathBuf> {
+    if program.contains('/') || program.contains('\\') {
+        let path = PathBuf::from(program);
+        return if path.is_file() { Some(path) } else { None };
+    }
+    let mut names = vec![program.to_string()];
+    if cfg!(windows) {
+        names.push(format!("{}.exe", program));
+    }
+    env::var_os("PATH").and_then(|paths| {
+        env::split_paths(&paths)
+            .flat_map(|dir| names.iter().map(|name| dir.join(name)).collect::<Vec<_>>())
+            .find(|path| path.is_file())
+    })
+}
+
+impl Language {
+    /// Whether the tools it needs are installed here. A command starting with
+    /// a placeholder runs what the compile step made.
+    pub fn installed(&self) -> bool {
+        self.compile.iter().chain(Some(&self.run)).all(|command| command[0].starts_with('{') || find_program(&command[0]).is_some())
+    }
+
+    /// Replaces the placeholders in `arg` with the run's paths.
+    pub fn fill(arg: &str, source: &Path, exe: &Path, dir: &Path) -> String {
+        arg.replace("{source}", &source.to_string_lossy())
+            .replace("{exe}", &exe.to_string_lossy())
+            .replace("{dir}", &dir.to_string_lossy())
+    }
+}
+
+fn field<'a>(json: &'a Json, name: &str) -> Result<&'a Json, LanguageError> {
+    json.find(name).ok_or(LanguageError::Malformed(format!("{} is missing", name)))
+}
+
+fn string_field<'a>(json: &'a Json, name: &str) -> Result<&'a str, LanguageError> {
+    try!(field(json, name)).as_string().ok_or(LanguageError::Malformed(format!("{} is not a string", name)))
+}
+
+fn command_field(json: &Json, name: &str) -> Result<Vec<String>, LanguageError> {
+    let args = try!(try!(field(json, name)).as_array()
+                    .ok_or(LanguageError::Malformed(format!("{} is not a list", name))));
+    let mut command = vec![];
+    for arg in args {
+        let arg = try!(arg.as_string().ok_or(LanguageError::Malformed(format!("{} holds a non string", name))));
+        command.push(arg.to_string());
+    }
+    if command.is_empty() {
+        return Err(LanguageError::Malformed(format!("{} is empty", name)));
+    }
+    Ok(command)
+}
+
+fn parse_language(json: &Json) -> Result<Language, LanguageError> {
+    let mode = try!(string_field(json, "mode")).to_string();
+    let mut env = BTreeMap::new();
+    if let Some(vars) = json.find("env") {
+        let vars = try!(vars.as_object().ok_or(LanguageError::Malformed("env is not an object".to_string())));
+        for (name, value) in vars {
+            let value = try!(value.as_string().ok_or(LanguageError::Malformed(format!("{} is not a string", name))));
+            env.insert(name.clone(), value.to_string());
+        }
+    }
+    Ok(Language {
+        name: json.find("name").and_then(|n| n.as_string()).unwrap_or(&mode).to_string(),
+        editor: json.find("editor").and_then(|e| e.as_string()).unwrap_or(&mode).to_string(),
+        extension: try!(string_field(json, "extension")).to_string(),
+        compile: match json.find("compile") {
+            Some(_) => Some(try!(command_field(json, "compile"))),
+            None => None,
+        },
+        run: try!(command_field(json, "run")),
+        env: env,
+        memory_mb: json.find("memory_mb").and_then(|m| m.as_u64()),
+        mode: mode,
+    })
+}
+
+/// The languages runs can use, by editor mode.
+#[derive(Clone, Debug)]
+pub struct Languages {
+    languages: Vec<Language>,
+}
+
+impl Languages {
+    pub fn builtin() -> Languages {
+        Languages::parse(BUILTIN).unwrap()
+    }
+
+    pub fn parse(s: &str) -> Result<Languages, LanguageError> {
+        let json = try!(Json::from_str(s).map_err(|e| LanguageError::Malformed(format!("{}", e))));
+        let entries = try!(try!(field(&json, "languages")).as_array()
+                           .ok_or(LanguageError::Malformed("languages is not a list".to_string())));
+        let mut languages = Languages { languages: vec![] };
+        for entry in entries {
+            languages.add(try!(parse_language(entry)));
+        }
+        Ok(languages)
+    }
+
+    /// The built in languages with the ones in `path` added, an entry for a
+    /// mode that is already known replaces it. No file just gives the built
+    /// in ones.
+    pub fn read(path: &Path) -> Result<Languages, LanguageError> {
+        let mut languages = Languages::builtin();
+        let mut file = match File::open(path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(languages),
+            Err(e) => return Err(LanguageError::Io(e)),
+        };
+        let mut s = String::new();
+        try!(file.read_to_string(&mut s));
+        for language in try!(Languages::parse(&s)).languages {
+            languages.add(language);
+        }
+        Ok(languages)
+    }
+
+    pub fn add(&mut self, language: Language) {
+        self.languages.retain(|l| l.mode != language.mode);
+        self.languages.push(language);
+    }
+
+    pub fn get(&self, mode: &str) -> Option<&Language> {
+        self.languages.iter().find(|l| l.mode == mode)
+    }
+
+    pub fn all(&self) -> Vec<Language> {
+        self.languages.clone()
+    }
+
+    /// The modes this machine can run, told to the other peers.
+    pub fn installed(&self) -> Vec<String> {
+        self.languages.iter().filter(|l| l.installed()).map(|l| l.mode.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn files_add_and_replace_languages(){
+        let mut languages = Languages::builtin();
+        assert!(languages.get("python").unwrap().compile.is_none());
+        let file = Languages::parse(r#"{"languages": [
+            {"mode": "rust", "name": "Rust", "extension": "rs",
+             "compile": ["rustc", "{source}", "-o", "{exe}"], "run": ["{exe}"], "env": {"RUST_BACKTRACE": "1"}},
+            {"mode": "python", "extension": "py", "run": ["python3", "{source}"]}
+        ]}"#).unwrap();
+        for language in file.all() {
+            languages.add(language);
+        }
+        let rust = languages.get("rust").unwrap();
+        assert_eq!(rust.editor, "rust");
+        assert_eq!(rust.env.get("RUST_BACKTRACE").map(|v| &v[..]), Some("1"));
+        assert_eq!(languages.get("python").unwrap().run[0], "python3");
+        assert_eq!(languages.all().len(), 5);
+
+        assert_eq!(Language::fill("-o{exe}", Path::new("/t/main.rs"), Path::new("/t/main"), Path::new("/t")), "-o/t/main");
+        assert!(Languages::parse(r#"{"languages": [{"mode": "go", "extension": "go", "run": []}]}"#).is_err());
+
+        let missing = Languages::parse(r#"{"languages": [{"mode": "x", "extension": "x", "run": ["p2p3-no-such-tool"]}]}"#).unwrap();
+        assert!(missing.installed().is_empty());
+    }
+}
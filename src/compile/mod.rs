@@ -1,45 +1,20 @@
-extern crate gcc;
-
-use std::process::Command;
-use std::env;
-use std::fs;
+pub mod sandbox;
+pub mod languages;
+pub mod project;
+pub mod diagnostics;
+pub mod remote;
+pub mod cache;
+
+use std::fmt;
 use std::fs::File;
 use std::io::Write;
 use std::error::Error;
-use std::path::PathBuf;
 use std::path::Path;
-use std::str::FromStr;
-
-#[derive(Clone)]
-pub enum CompileMode {
-    None,
-    C,
-    Python,
-    Ruby,
-    Lua,
-}
-
-impl FromStr for CompileMode {
-    type Err = ();
-
-    fn from_str(s: &str) -> Result<CompileMode, ()> {
-        match s {
-            "c_cpp" => Ok(CompileMode::C),
-            "python" => Ok(CompileMode::Python),
-            "ruby" => Ok(CompileMode::Ruby),
-            "lua" => Ok(CompileMode::Lua),
-            _ => Ok(CompileMode::None),
-        }
-    }
-}
-
-fn make_temp_dir() -> PathBuf {
-    let mut temp_dir_name = env::temp_dir();
-    temp_dir_name.push("P2P3");
-    fs::create_dir(temp_dir_name.as_path()).unwrap_or( () );
-    //Return
-    temp_dir_name
-}
+use std::sync::mpsc::Receiver;
+use self::sandbox::{CancelToken, Ending, Limits, RunOutput, Sandbox, Stream};
+use self::languages::Language;
+use self::diagnostics::{Diagnostic, parse};
+use self::cache::BuildCache;
 
 fn make_file(path: &Path, input: &str) -> Result<(), String>{
     let mut file = File::create(&path).unwrap_or_else(|e| panic!("Oh noooooo {}", e));
@@ -53,103 +28,178 @@ fn make_file(path: &Path, input: &str) -> Result<(), String>{
     }
 }
 
-#[allow(dead_code)]
-pub fn run_code(compile_mode: CompileMode, input: &str) -> Result<String, String> {
-    match compile_mode {
-        CompileMode::C => run_c(input),
-        CompileMode::Python => run_python(input),
-        CompileMode::Lua => run_lua(input),
-        CompileMode::Ruby => run_ruby(input),
-        CompileMode::None => return Err("Could not find suitable compiler".to_string()),
-    }
+/// How the last step of a run ended, its output went out line by line.
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct RunReport {
+    // "compile" or "run"
+    pub step: String,
+    pub ending: Ending,
+    pub millis: u64,
+    pub truncated: bool,
+    // From every step, paths relative to the repo root.
+    pub diagnostics: Vec<Diagnostic>,
 }
 
-
-#[allow(dead_code)]
-pub fn run_c(input: &str) -> Result<String, String> {
-    println!("run c code {}", input);
-    let temp_dir_name = make_temp_dir();
-    let c_file = temp_dir_name.join("temp.c");
-    let exe = temp_dir_name.join("temp.exe");
-
-    match make_file(&c_file, &input){
-        Ok(_) =>{},
-        Err(e) => return Err(e)
-    };
-
-    let output = match gcc::windows_registry::find("x86_64_msvc", "cl.exe"){
-        Some(cc) => {
-            let mut cc= cc;
-            print!("{:?}", cc);
-            cc.current_dir(temp_dir_name).arg(&c_file).arg(&format!("/link /OUT:{}", exe.to_str().unwrap())).output().unwrap() },
-        None => Command::new("cc").current_dir(temp_dir_name).arg(&c_file).arg("-o").arg(&exe).output().unwrap()
-    };
-
-    if !output.status.success() {
-        let a = output.status.code().unwrap();
-        let b = String::from_utf8(output.stderr).unwrap();
-        let c = String::from_utf8(output.stdout).unwrap();
-        print!("Compile failed with code {}: {} {}", a, b, c);
-        return Err(format!("Compile failed with code {}: {} {}", a, b, c));
+impl RunReport {
+    // Diagnostics are picked from the output, with paths relative to `dir`.
+    fn new(step: &str, out: &RunOutput, dir: &Path) -> RunReport {
+        RunReport {
+            step: step.to_string(),
+            ending: out.ending.clone(),
+            millis: out.elapsed.as_secs() * 1000 + (out.elapsed.subsec_nanos() / 1000000) as u64,
+            truncated: out.truncated,
+            diagnostics: parse(&format!("{}\n{}", out.stdout, out.stderr), dir),
+        }
     }
 
-    let run_output = Command::new(exe).output().unwrap();
-
-    if !run_output.status.success() {
-        let a = run_output.status.code().unwrap();
-        let b = String::from_utf8(run_output.stderr).unwrap();
-        return Err(format!("Run failed with code {}: {}", a, b));
+    pub fn success(&self) -> bool {
+        self.ending == Ending::Exited(0)
     }
-
-    Ok(String::from_utf8(run_output.stdout).unwrap())
 }
 
-#[allow(dead_code)]
-pub fn run_python(input: &str) -> Result<String, String> {
-    println!("run python code");
-    run_interp(input, "python")
+impl fmt::Display for RunReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.millis as f64 / 1000.0;
+        try!(match self.ending {
+            Ending::Exited(code) => write!(f, "{} exited with code {} after {:.2}s", self.step, code, secs),
+            Ending::Killed(signal) => write!(f, "{} was killed by signal {} after {:.2}s, it may have hit the CPU or memory limit",
+                                             self.step, signal, secs),
+            Ending::TimedOut => write!(f, "{} ran out of time after {:.2}s", self.step, secs),
+            Ending::Cancelled => write!(f, "{} was cancelled after {:.2}s", self.step, secs),
+        });
+        if self.truncated {
+            try!(write!(f, ", some output was dropped"));
+        }
+        Ok(())
+    }
 }
 
-#[allow(dead_code)]
-pub fn run_ruby(input: &str) -> Result<String, String> {
-    println!("run ruby code");
-    run_interp(input, "ruby")
+/// What the peers watching a run see of it, in order.
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum RunEvent {
+    // the file being run, or the build
+    Started(String),
+    Line(Stream, String),
+    // text typed for the program
+    Input(String),
+    Finished(RunReport),
+    // the run could not be started
+    Failed(String),
 }
 
-#[allow(dead_code)]
-pub fn run_lua(input: &str) -> Result<String, String> {
-    println!("run lua code");
-    run_interp(input, "lua")
+/// Where a run's output goes and where its input comes from.
+pub struct RunIo<'a> {
+    pub cancel: &'a CancelToken,
+    // Text for the program, None closes its input.
+    pub stdin: &'a Receiver<Option<String>>,
+    pub on_line: &'a Fn(Stream, &str),
 }
 
-#[allow(dead_code)]
-fn run_interp(input: &str, cmd: &str) -> Result<String, String> {
-    let temp_dir = make_temp_dir();
-    let tmp_file = temp_dir.join(format!("temp.{}", cmd));
+// Where the compile step puts the program.
+#[cfg(windows)]
+const EXE_NAME: &'static str = "main.exe";
+#[cfg(not(windows))]
+const EXE_NAME: &'static str = "main";
 
-    match make_file(&tmp_file, &input){
-        Ok(_) =>{},
-        Err(e) => return Err(e)
+/// Compiles `input`, the buffer of `path`, with `language` if it needs it,
+/// then runs it, both in a fresh sandbox, streaming their output through `io`.
+/// Code compiled before by the same toolchain reuses the cached program.
+#[allow(dead_code)]
+pub fn run_code(language: &Language, path: &str, input: &str, io: &RunIo) -> Result<RunReport, String> {
+    let mut limits = Limits::default();
+    if let Some(mb) = language.memory_mb {
+        limits.memory_bytes = mb << 20;
+    }
+    let mut sandbox = match Sandbox::new(limits) {
+        Ok(sandbox) => sandbox,
+        Err(e) => return Err(format!("Could not set up a sandbox: {}", e)),
+    };
+    let source = sandbox.dir().join(format!("main.{}", language.extension));
+    let exe = sandbox.dir().join(EXE_NAME);
+    let dir = sandbox.dir().to_path_buf();
+    let fill = |command: &[String]| -> Vec<String> {
+        command.iter().map(|arg| Language::fill(arg, &source, &exe, &dir)).collect()
+    };
+    for (name, value) in &language.env {
+        sandbox.set_env(name, &Language::fill(value, &source, &exe, &dir));
+    }
+    try!(make_file(&source, input));
+    let source_name = source.file_name().unwrap().to_string_lossy().into_owned();
+    // The tools only know the copy in the sandbox.
+    let with_path = |mut report: RunReport| {
+        report.diagnostics.retain(|d| d.file == source_name);
+        for d in &mut report.diagnostics {
+            d.file = path.to_string();
+        }
+        report
     };
 
-    match Command::new(cmd).current_dir(temp_dir).arg(&tmp_file).output(){
-        Ok(out) => {
-            if out.status.success() {
-                Ok(String::from_utf8(out.stdout).unwrap())
-            }else{
-                let a = out.status.code().unwrap();
-                let b = String::from_utf8(out.stderr).unwrap();
-                let c = String::from_utf8(out.stdout).unwrap();
-                Err(format!("{} failed with code {}: {} {}",cmd, a, b, c))
+    let mut diagnostics = vec![];
+    let cache = BuildCache::shared();
+    let key = cache::key(language, input);
+    let cached = key.as_ref().and_then(|key| cache.restore(key, &exe));
+    if let Some(cached) = cached {
+        println!("{} code is unchanged, using its cached build", language.name);
+        diagnostics = cached.into_iter().map(|mut d| {
+            d.file = path.to_string();
+            d
+        }).collect();
+    } else if let Some(ref compile) = language.compile {
+        println!("compile {} code", language.name);
+        let compile = fill(compile);
+        let output = match sandbox.run_with(&compile[0], &compile[1..], io.cancel, None, io.on_line) {
+            Ok(output) => output,
+            Err(e) => return Err(format!("Failed to run {}: {}", compile[0], e)),
+        };
+        let report = with_path(RunReport::new("compile", &output, &dir));
+        if !report.success() {
+            return Ok(report);
+        }
+        // Compilers writing elsewhere than {exe} are not cached.
+        if let Some(ref key) = key {
+            if exe.is_file() {
+                if let Err(e) = cache.store(key, &exe, &report.diagnostics) {
+                    println!("Could not cache the {} build: {}", language.name, e);
+                }
             }
+        }
+        diagnostics = report.diagnostics;
+    }
+
+    println!("run {} code", language.name);
+    let run = fill(&language.run);
+    match sandbox.run_with(&run[0], &run[1..], io.cancel, Some(io.stdin), io.on_line) {
+        Ok(out) => {
+            let mut report = with_path(RunReport::new("run", &out, &dir));
+            diagnostics.extend(report.diagnostics);
+            report.diagnostics = diagnostics;
+            Ok(report)
         },
-        Err(err) => Err(format!("Failed to run python with error: {}",err))
+        Err(e) => Err(format!("Failed to run {}: {}", run[0], e)),
     }
 }
 
 #[cfg(test)]
 mod test{
     use super::*;
+    use std::sync::Mutex;
+    use std::sync::mpsc::channel;
+    use super::sandbox::{CancelToken, Stream};
+    use super::languages::Languages;
+
+    // What the program printed to stdout, if it ran to a clean exit.
+    fn run(mode: &str, code: &str) -> Result<String, String> {
+        let language = Languages::builtin().get(mode).unwrap().clone();
+        let (_tx, rx) = channel();
+        let stdout = Mutex::new(String::new());
+        let io = RunIo {
+            cancel: &CancelToken::new(),
+            stdin: &rx,
+            on_line: &|stream, line| if stream == Stream::Stdout { stdout.lock().unwrap().push_str(line) },
+        };
+        let report = try!(run_code(&language, "hello", code, &io));
+        if report.success() { Ok(stdout.into_inner().unwrap()) } else { Err(report.to_string()) }
+    }
 
     static C_CODE: &'static str =
 "#include <stdio.h>
@@ -167,7 +217,7 @@ int main(){
 
     #[test]
     fn run_simple_c(){
-        let out = run_c(C_CODE);
+        let out = run("c_cpp", C_CODE);
 
         print!("{:?}", out);
         match out{
@@ -180,7 +230,7 @@ int main(){
 
     #[test]
     fn run_simple_py(){
-        let out = run_python(PY_CODE);
+        let out = run("python", PY_CODE);
 
         print!("{:?}", out);
         match out{
@@ -193,7 +243,7 @@ int main(){
 
     #[test]
     fn run_simple_lua(){
-        let out = run_lua(LUA_CODE);
+        let out = run("lua", LUA_CODE);
 
         print!("{:?}", out);
         match out{
@@ -206,7 +256,7 @@ int main(){
 
     #[test]
     fn run_simple_ruby(){
-        let out = run_ruby(RUBY_CODE);
+        let out = run("ruby", RUBY_CODE);
 
         print!("{:?}", out);
         match out{
@@ -0,0 +1,178 @@
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use rustc_serialize::json::Json;
+use compile::{RunIo, RunReport};
+use compile::sandbox::{Ending, Limits, Sandbox};
+
+/// Committed at the root of the repo, names the commands that build and test it.
+pub const BUILD_FILE: &'static str = ".p2p3-build";
+
+#[derive(Debug)]
+pub enum BuildError {
+    Io(io::Error),
+    Malformed(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildError::Io(ref e) => write!(f, "{}", e),
+            BuildError::Malformed(ref why) => write!(f, "malformed {}: {}", BUILD_FILE, why),
+        }
+    }
+}
+
+impl From<io::Error> for BuildError {
+    fn from(e: io::Error) -> BuildError {
+        BuildError::Io(e)
+    }
+}
+
+/// A command run in the repo's directory, such as `cargo test`.
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct BuildTarget {
+    pub name: String,
+    pub command: Vec<String>,
+}
+
+impl BuildTarget {
+    fn new(name: &str, command: &[&str]) -> BuildTarget {
+        BuildTarget { name: name.to_string(), command: command.iter().map(|a| a.to_string()).collect() }
+    }
+}
+
+impl fmt::Display for BuildTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.name, self.command.join(" "))
+    }
+}
+
+fn parse(s: &str) -> Result<Vec<BuildTarget>, BuildError> {
+    let json = try!(Json::from_str(s).map_err(|e| BuildError::Malformed(format!("{}", e))));
+    let entries = try!(try!(json.find("builds").ok_or(BuildError::Malformed("builds is missing".to_string())))
+                       .as_array().ok_or(BuildError::Malformed("builds is not a list".to_string())));
+    let mut targets = vec![];
+    for entry in entries {
+        let name = try!(entry.find("name").and_then(|n| n.as_string())
+                        .ok_or(BuildError::Malformed("a build has no name".to_string())));
+        let args = try!(entry.find("command").and_then(|c| c.as_array())
+                        .ok_or(BuildError::Malformed(format!("{} has no command list", name))));
+        let mut command = vec![];
+        for arg in args {
+            command.push(try!(arg.as_string().ok_or(BuildError::Malformed(format!("{} holds a non string", name)))).to_string());
+        }
+        if command.is_empty() {
+            return Err(BuildError::Malformed(format!("{} has an empty command", name)));
+        }
+        targets.push(BuildTarget { name: name.to_string(), command: command });
+    }
+    Ok(targets)
+}
+
+// What the repo's build files suggest when BUILD_FILE does not say.
+fn detected(root: &Path) -> Vec<BuildTarget> {
+    let mut targets = vec![];
+    if root.join("Cargo.toml").exists() {
+        targets.push(BuildTarget::new("build", &["cargo", "build"]));
+        targets.push(BuildTarget::new("test", &["cargo", "test"]));
+    }
+    if ["Makefile", "makefile", "GNUmakefile"].iter().any(|m| root.join(m).exists()) {
+        targets.push(BuildTarget::new("make", &["make"]));
+    }
+    targets
+}
+
+// Run with network before `target`, so its dependencies are there. Cargo
+// fetch only downloads, none of the repo's code is run by it.
+fn fetch_command(target: &BuildTarget) -> Option<Vec<String>> {
+    if target.command[0] == "cargo" {
+        Some(vec!["cargo".to_string(), "fetch".to_string()])
+    } else {
+        None
+    }
+}
+
+/// The builds the repo under `root` offers: those in BUILD_FILE, then the
+/// detected ones it does not name.
+pub fn targets(root: &Path) -> Result<Vec<BuildTarget>, BuildError> {
+    let mut targets = match File::open(root.join(BUILD_FILE)) {
+        Ok(mut file) => {
+            let mut s = String::new();
+            try!(file.read_to_string(&mut s));
+            try!(parse(&s))
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(BuildError::Io(e)),
+    };
+    for target in detected(root) {
+        if !targets.iter().any(|t| t.name == target.name) {
+            targets.push(target);
+        }
+    }
+    Ok(targets)
+}
+
+/// Runs `target` in the repo under `root`. The live buffers should be
+/// written out first, the build only sees what is on disk. Cargo targets
+/// fetch their dependencies first, the build itself has no network.
+pub fn build(root: &Path, target: &BuildTarget, io: &RunIo) -> Result<RunReport, String> {
+    let sandbox = match Sandbox::in_dir(Limits::build(), root) {
+        Ok(sandbox) => sandbox,
+        Err(e) => return Err(format!("Could not set up a sandbox: {}", e)),
+    };
+    // Tools print absolute paths with the links resolved.
+    let dir = fs::canonicalize(root).unwrap_or(root.to_path_buf());
+    if let Some(fetch) = fetch_command(target) {
+        let mut online = match Sandbox::in_dir(Limits::build(), root) {
+            Ok(sandbox) => sandbox,
+            Err(e) => return Err(format!("Could not set up a sandbox: {}", e)),
+        };
+        online.allow_network();
+        println!("fetch {}", fetch.join(" "));
+        // A failed fetch may still leave what is cached enough, the build says.
+        match online.run_with(&fetch[0], &fetch[1..], io.cancel, None, io.on_line) {
+            Ok(ref out) if out.ending == Ending::Cancelled => return Ok(RunReport::new("fetch", out, &dir)),
+            Ok(_) => {},
+            Err(e) => return Err(format!("Failed to run {}: {}", fetch[0], e)),
+        }
+    }
+    println!("build {}", target);
+    match sandbox.run_with(&target.command[0], &target.command[1..], io.cancel, None, io.on_line) {
+        Ok(out) => Ok(RunReport::new(&target.name, &out, &dir)),
+        Err(e) => Err(format!("Failed to run {}: {}", target.command[0], e)),
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use rand::random;
+
+    #[test]
+    fn configured_builds_come_before_detected_ones(){
+        let n: u32 = random();
+        let root = env::temp_dir().join(format!("p2p3_project_{}", n));
+        fs::create_dir_all(&root).unwrap();
+        assert!(targets(&root).unwrap().is_empty());
+
+        File::create(root.join("Cargo.toml")).unwrap();
+        File::create(root.join(BUILD_FILE)).unwrap()
+            .write_all(br#"{"builds": [{"name": "test", "command": ["cargo", "test", "--all"]}]}"#).unwrap();
+        let found = targets(&root).unwrap();
+        assert_eq!(found, vec![BuildTarget::new("test", &["cargo", "test", "--all"]), BuildTarget::new("build", &["cargo", "build"])]);
+        assert_eq!(fetch_command(&found[0]), Some(vec!["cargo".to_string(), "fetch".to_string()]));
+        assert_eq!(fetch_command(&BuildTarget::new("make", &["make"])), None);
+
+        File::create(root.join(BUILD_FILE)).unwrap().write_all(br#"{"builds": [{"name": "x", "command": []}]}"#).unwrap();
+        assert!(targets(&root).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use crust::PeerId;
+
+/// Largest buffer another peer may ask us to run, in bytes.
+pub const MAX_RUN_SOURCE: usize = 1 << 20;
+
+/// The editor modes every peer said it can run.
+pub struct Toolchains {
+    known: BTreeMap<PeerId, Vec<String>>,
+}
+
+impl Toolchains {
+    pub fn new() -> Toolchains {
+        Toolchains { known: BTreeMap::new() }
+    }
+
+    /// Replaces what `peer` told us before.
+    pub fn set(&mut self, peer: PeerId, modes: Vec<String>) {
+        self.known.insert(peer, modes);
+    }
+
+    /// The peers among `connected` that can run `mode`, in the same order on
+    /// every peer.
+    pub fn capable(&self, mode: &str, connected: &[PeerId]) -> Vec<PeerId> {
+        self.known.iter()
+            .filter(|&(peer, modes)| connected.contains(peer) && modes.iter().any(|m| m == mode))
+            .map(|(peer, _)| peer.clone())
+            .collect()
+    }
+}
+
+/// A run another peer asked us to host.
+#[derive(Clone, Debug)]
+pub struct RunRequest {
+    pub requester: PeerId,
+    pub mode: String,
+    pub path: String,
+    pub content: String,
+}
+
+/// Requests waiting for our user's consent, at most one per requester, with
+/// the run id it picked. Each requester picks its own ids, so those alone may
+/// collide.
+pub struct PendingRuns {
+    pending: BTreeMap<PeerId, (u64, RunRequest)>,
+}
+
+impl PendingRuns {
+    pub fn new() -> PendingRuns {
+        PendingRuns { pending: BTreeMap::new() }
+    }
+
+    /// False, and nothing is kept, while the requester still waits on another.
+    pub fn add(&mut self, id: u64, request: RunRequest) -> bool {
+        if self.pending.contains_key(&request.requester) {
+            return false;
+        }
+        self.pending.insert(request.requester.clone(), (id, request));
+        true
+    }
+
+    pub fn take(&mut self, requester: &PeerId, id: u64) -> Option<RunRequest> {
+        match self.pending.get(requester) {
+            Some(&(pending, _)) if pending == id => {},
+            _ => return None,
+        }
+        self.pending.remove(requester).map(|(_, request)| request)
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use crust::PeerId;
+    use rand::random;
+
+    #[test]
+    fn only_connected_peers_with_the_mode_are_capable(){
+        let a: PeerId = random();
+        let b: PeerId = random();
+        let gone: PeerId = random();
+        let mut toolchains = Toolchains::new();
+        toolchains.set(a.clone(), vec!["rust".to_string(), "python".to_string()]);
+        toolchains.set(b.clone(), vec!["python".to_string()]);
+        toolchains.set(gone.clone(), vec!["rust".to_string()]);
+        assert_eq!(toolchains.capable("rust", &[a.clone(), b.clone()]), vec![a.clone()]);
+        assert_eq!(toolchains.capable("python", &[a.clone(), b.clone()]).len(), 2);
+        toolchains.set(a.clone(), vec![]);
+        assert!(toolchains.capable("rust", &[a.clone(), b.clone(), gone.clone()]) == vec![gone.clone()]);
+    }
+
+    #[test]
+    fn requesters_keep_their_own_runs(){
+        let a: PeerId = random();
+        let b: PeerId = random();
+        let request = |requester: &PeerId, path: &str| RunRequest {
+            requester: requester.clone(), mode: "python".to_string(), path: path.to_string(), content: String::new(),
+        };
+        let mut pending = PendingRuns::new();
+        assert!(pending.add(7, request(&a, "a.py")));
+        assert!(pending.add(7, request(&b, "b.py")));
+        assert_eq!(pending.take(&a, 7).unwrap().path, "a.py");
+        assert!(pending.take(&a, 7).is_none());
+        assert_eq!(pending.take(&b, 7).unwrap().path, "b.py");
+    }
+
+    #[test]
+    fn one_pending_run_per_requester(){
+        let a: PeerId = random();
+        let request = |path: &str| RunRequest {
+            requester: a.clone(), mode: "python".to_string(), path: path.to_string(), content: String::new(),
+        };
+        let mut pending = PendingRuns::new();
+        assert!(pending.add(1, request("a.py")));
+        assert!(!pending.add(2, request("b.py")));
+        assert!(pending.take(&a, 2).is_none());
+        assert_eq!(pending.take(&a, 1).unwrap().path, "a.py");
+        assert!(pending.add(2, request("b.py")));
+    }
+}
@@ -0,0 +1,524 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use rand::random;
+
+// Output past this is dropped, per stream, a print loop would fill our memory otherwise.
+const MAX_OUTPUT: usize = 1 << 20;
+const POLL_MS: u64 = 20;
+// How long the output may go quiet after the run ended before its readers are
+// given up on. A child that escaped the process group can hold the pipes open.
+const READER_GRACE_MS: u64 = 500;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    pub wall: Duration,
+    pub cpu_secs: u64,
+    pub memory_bytes: u64,
+    // New processes the run may start. The kernel counts RLIMIT_NPROC over
+    // all processes of the user, so this is added to those already running.
+    pub processes: u64,
+}
+
+impl Limits {
+    pub fn default() -> Limits {
+        Limits {
+            wall: Duration::from_secs(300),
+            cpu_secs: 60,
+            memory_bytes: 512 << 20,
+            processes: 256,
+        }
+    }
+
+    /// For project builds, compilers and test suites need more of everything.
+    pub fn build() -> Limits {
+        Limits {
+            wall: Duration::from_secs(30 * 60),
+            cpu_secs: 20 * 60,
+            memory_bytes: 8 << 30,
+            processes: 1024,
+        }
+    }
+}
+
+/// Shared between the run and whoever may want to stop it.
+#[derive(Clone, Debug)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken { cancelled: Arc::new(AtomicBool::new(false)) }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum Ending {
+    Exited(i32),
+    // Signal number, CPU and memory limits end a run this way.
+    Killed(i32),
+    TimedOut,
+    Cancelled,
+}
+
+#[derive(Clone, Debug)]
+pub struct RunOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub ending: Ending,
+    pub elapsed: Duration,
+    // Some output went past MAX_OUTPUT and was dropped.
+    pub truncated: bool,
+}
+
+impl RunOutput {
+    pub fn success(&self) -> bool {
+        self.ending == Ending::Exited(0)
+    }
+
+    fn push(&mut self, stream: Stream, line: &str, on_line: &Fn(Stream, &str)) {
+        on_line(stream, line);
+        match stream {
+            Stream::Stdout => self.stdout.push_str(line),
+            Stream::Stderr => self.stderr.push_str(line),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SandboxError {
+    Io(io::Error),
+    // Linux refused us a network namespace, we don't run code with network access.
+    NoIsolation,
+}
+
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SandboxError::Io(ref e) => write!(f, "{}", e),
+            SandboxError::NoIsolation => write!(f, "runs can't be cut off from the network here, \
+                                                   unshare --net --map-root-user is not permitted"),
+        }
+    }
+}
+
+impl From<io::Error> for SandboxError {
+    fn from(e: io::Error) -> SandboxError {
+        SandboxError::Io(e)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_isolation() -> Result<(), SandboxError> {
+    let status = Command::new("unshare").arg("--net").arg("--map-root-user").arg("true")
+        .stdout(Stdio::null()).stderr(Stdio::null()).status();
+    match status {
+        Ok(ref s) if s.success() => Ok(()),
+        _ => Err(SandboxError::NoIsolation),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_isolation() -> Result<(), SandboxError> {
+    println!("Runs are only sandboxed on Linux, this one just gets a time limit");
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn kill_run(pid: u32) {
+    // setsid made the run a process group of its own, take all of it down.
+    let _ = Command::new("kill").arg("-KILL").arg("--").arg(format!("-{}", pid)).status();
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn kill_run(pid: u32) {
+    let _ = Command::new("kill").arg("-KILL").arg(format!("{}", pid)).status();
+}
+
+#[cfg(windows)]
+fn kill_run(pid: u32) {
+    let _ = Command::new("taskkill").arg("/F").arg("/T").arg("/PID").arg(format!("{}", pid)).status();
+}
+
+#[cfg(unix)]
+fn ending_of(status: &ExitStatus) -> Ending {
+    use std::os::unix::process::ExitStatusExt;
+    match status.code() {
+        Some(code) => Ending::Exited(code),
+        None => Ending::Killed(status.signal().unwrap_or(0)),
+    }
+}
+
+#[cfg(not(unix))]
+fn ending_of(status: &ExitStatus) -> Ending {
+    Ending::Exited(status.code().unwrap_or(-1))
+}
+
+// Owns the program's input, so a program that doesn't read it blocks this
+// thread and not the run. Ends, closing the input, on None or a failed write.
+fn write_input<W: Write + Send + 'static>(pipe: W, input: Receiver<Option<String>>) {
+    thread::spawn(move || {
+        let mut pipe = pipe;
+        while let Ok(Some(text)) = input.recv() {
+            if pipe.write_all(text.as_bytes()).and_then(|_| pipe.flush()).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+// Processes of our user running right now, which RLIMIT_NPROC counts too.
+#[cfg(target_os = "linux")]
+fn user_processes() -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    let uid = match fs::metadata("/proc/self") {
+        Ok(meta) => meta.uid(),
+        Err(_) => return 0,
+    };
+    match fs::read_dir("/proc") {
+        Ok(entries) => entries.filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().chars().all(|c| c.is_digit(10)))
+            .filter(|entry| entry.metadata().map(|meta| meta.uid() == uid).unwrap_or(false))
+            .count() as u64,
+        Err(_) => 0,
+    }
+}
+
+// Passes on every line, the last one may lack its newline. Returns whether
+// lines past MAX_OUTPUT were dropped.
+// Sends the lines of `pipe`, then whether some were dropped on `done`.
+fn read_lines<R: Read + Send + 'static>(pipe: R, stream: Stream, lines: Sender<(Stream, String)>, done: Sender<bool>) {
+    thread::spawn(move || {
+        let mut reader = io::BufReader::new(pipe);
+        let mut sent = 0;
+        let mut truncated = false;
+        loop {
+            let mut line = vec![];
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if sent + n > MAX_OUTPUT {
+                        truncated = true;
+                        continue;
+                    }
+                    sent += n;
+                    let _ = lines.send((stream, String::from_utf8_lossy(&line).into_owned()));
+                },
+            }
+        }
+        let _ = done.send(truncated);
+    });
+}
+
+/// A throwaway working directory for one run, removed when dropped. On Linux
+/// everything run in it has no network, and limited CPU time, memory and
+/// processes.
+pub struct Sandbox {
+    dir: PathBuf,
+    limits: Limits,
+    env: Vec<(String, String)>,
+    // Made by us, so removed and used as HOME.
+    owned: bool,
+    network: bool,
+}
+
+impl Sandbox {
+    pub fn new(limits: Limits) -> Result<Sandbox, SandboxError> {
+        try!(check_isolation());
+        let n: u64 = random();
+        let dir = env::temp_dir().join(format!("p2p3-run-{:016x}", n));
+        try!(fs::create_dir_all(&dir));
+        Ok(Sandbox { dir: dir, limits: limits, env: vec![], owned: true, network: false })
+    }
+
+    /// Runs in `dir`, which is left in place. HOME is kept, so tools find
+    /// their caches, as the network is still cut off.
+    pub fn in_dir(limits: Limits, dir: &Path) -> Result<Sandbox, SandboxError> {
+        try!(check_isolation());
+        Ok(Sandbox { dir: dir.to_path_buf(), limits: limits, env: vec![], owned: false, network: false })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Set for everything run in the sandbox after this.
+    pub fn set_env(&mut self, name: &str, value: &str) {
+        self.env.push((name.to_string(), value.to_string()));
+    }
+
+    /// Runs after this keep the network, the other limits still hold. Only
+    /// for steps that run none of the shared code, like fetching dependencies.
+    pub fn allow_network(&mut self) {
+        self.network = true;
+    }
+
+    #[cfg(target_os = "linux")]
+    fn command(&self, program: &str, args: &[String]) -> Command {
+        let mut command = Command::new("setsid");
+        if !self.network {
+            command.arg("unshare").arg("--net").arg("--map-root-user");
+        }
+        command.arg("prlimit")
+            .arg(format!("--cpu={}", self.limits.cpu_secs))
+            .arg(format!("--as={}", self.limits.memory_bytes))
+            .arg(format!("--nproc={}", user_processes() + self.limits.processes))
+            // Line buffered output, so it streams instead of arriving at exit.
+            .arg("--").arg("stdbuf").arg("-oL").arg("-eL")
+            .arg(program).args(args);
+        command
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn command(&self, program: &str, args: &[String]) -> Command {
+        let mut command = Command::new(program);
+        command.args(args);
+        command
+    }
+
+    /// Runs `program` in the sandbox directory until it exits, runs out of
+    /// wall time or `cancel` is set.
+    pub fn run(&self, program: &str, args: &[String], cancel: &CancelToken) -> Result<RunOutput, SandboxError> {
+        self.run_with(program, args, cancel, None, &|_, _| {})
+    }
+
+    /// Like `run`, handing every line of output to `on_line` as it appears.
+    /// Text from `stdin` is written to the program, None closes its input.
+    /// Without `stdin` the program reads nothing.
+    pub fn run_with(&self, program: &str, args: &[String], cancel: &CancelToken,
+                    stdin: Option<&Receiver<Option<String>>>, on_line: &Fn(Stream, &str)) -> Result<RunOutput, SandboxError> {
+        let mut command = self.command(program, args);
+        command.current_dir(&self.dir).env("PYTHONUNBUFFERED", "1");
+        if self.owned {
+            command.env("HOME", &self.dir).env("TMPDIR", &self.dir);
+        }
+        for &(ref name, ref value) in &self.env {
+            command.env(name, value);
+        }
+        command.stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let start = Instant::now();
+        let mut child = try!(command.spawn());
+        let pid = child.id();
+        let mut input = child.stdin.take().map(|pipe| {
+            let (input_tx, input_rx) = channel();
+            write_input(pipe, input_rx);
+            input_tx
+        });
+        let (line_tx, line_rx) = channel();
+        let (done_tx, done_rx) = channel();
+        read_lines(child.stdout.take().unwrap(), Stream::Stdout, line_tx.clone(), done_tx.clone());
+        read_lines(child.stderr.take().unwrap(), Stream::Stderr, line_tx, done_tx);
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let _ = tx.send(child.wait());
+        });
+
+        let mut output = RunOutput {
+            stdout: String::new(),
+            stderr: String::new(),
+            ending: Ending::Exited(0),
+            elapsed: Duration::from_secs(0),
+            truncated: false,
+        };
+        let mut stopped = None;
+        let status;
+        loop {
+            while let Ok((stream, line)) = line_rx.try_recv() {
+                output.push(stream, &line, on_line);
+            }
+            if let Some(stdin) = stdin {
+                while let Ok(text) = stdin.try_recv() {
+                    let closing = text.is_none();
+                    let sent = input.as_ref().map_or(false, |writer| writer.send(text).is_ok());
+                    if closing || !sent {
+                        input = None;
+                    }
+                }
+            }
+            if let Ok(result) = rx.try_recv() {
+                status = try!(result);
+                break;
+            }
+            if stopped.is_none() {
+                if cancel.is_cancelled() {
+                    stopped = Some(Ending::Cancelled);
+                } else if start.elapsed() >= self.limits.wall {
+                    stopped = Some(Ending::TimedOut);
+                }
+                if stopped.is_some() {
+                    kill_run(pid);
+                }
+            }
+            thread::sleep(Duration::from_millis(POLL_MS));
+        }
+        drop(input);
+        // The readers are not joined, one whose pipe stays open would block
+        // us. Output cut short by giving up on them counts as truncated.
+        let mut readers = 2;
+        let mut truncated = false;
+        let mut quiet_since = Instant::now();
+        while readers > 0 && quiet_since.elapsed() < Duration::from_millis(READER_GRACE_MS) {
+            while let Ok((stream, line)) = line_rx.try_recv() {
+                output.push(stream, &line, on_line);
+                quiet_since = Instant::now();
+            }
+            while let Ok(cut) = done_rx.try_recv() {
+                readers -= 1;
+                truncated = truncated || cut;
+            }
+            if readers > 0 {
+                thread::sleep(Duration::from_millis(POLL_MS));
+            }
+        }
+        // A reader sends its last line before saying it is done.
+        while let Ok((stream, line)) = line_rx.try_recv() {
+            output.push(stream, &line, on_line);
+        }
+        output.ending = stopped.unwrap_or(ending_of(&status));
+        output.elapsed = start.elapsed();
+        output.truncated = truncated || readers > 0;
+        Ok(output)
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        if self.owned {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::sync::Mutex;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["-c".to_string(), script.to_string()]
+    }
+
+    #[test]
+    fn runs_are_limited(){
+        let mut limits = Limits::default();
+        limits.wall = Duration::from_millis(500);
+        let sandbox = match Sandbox::new(limits) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Skipping, {}", e);
+                return;
+            }
+        };
+        let cancel = CancelToken::new();
+        let out = sandbox.run("sh", &sh("echo hi; echo oops >&2"), &cancel).unwrap();
+        assert!(out.success());
+        assert_eq!((&out.stdout[..], &out.stderr[..]), ("hi\n", "oops\n"));
+
+        let out = sandbox.run("sh", &sh("sleep 30"), &cancel).unwrap();
+        assert_eq!(out.ending, Ending::TimedOut);
+        assert!(out.elapsed < Duration::from_secs(5));
+
+        let dir = sandbox.dir().to_path_buf();
+        assert!(dir.exists());
+        drop(sandbox);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn output_streams_and_input_arrives(){
+        let sandbox = match Sandbox::new(Limits::default()) {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+        let (tx, rx) = channel();
+        tx.send(Some("ping\n".to_string())).unwrap();
+        tx.send(None).unwrap();
+        let lines = Mutex::new(vec![]);
+        let out = sandbox.run_with("sh", &sh("read x; echo got $x; echo warn >&2; cat"), &CancelToken::new(),
+                                   Some(&rx), &|stream, line| lines.lock().unwrap().push((stream, line.to_string()))).unwrap();
+        assert!(out.success());
+        let mut lines = lines.into_inner().unwrap();
+        lines.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(lines, vec![(Stream::Stdout, "got ping\n".to_string()), (Stream::Stderr, "warn\n".to_string())]);
+    }
+
+    #[test]
+    fn unread_input_doesnt_hold_up_the_run(){
+        let mut limits = Limits::default();
+        limits.wall = Duration::from_millis(500);
+        let sandbox = match Sandbox::new(limits) {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+        let (tx, rx) = channel();
+        // Far more than a pipe holds.
+        tx.send(Some(String::from_utf8(vec![b'x'; 1 << 20]).unwrap())).unwrap();
+        let out = sandbox.run_with("sh", &sh("sleep 30"), &CancelToken::new(), Some(&rx), &|_, _| {}).unwrap();
+        assert_eq!(out.ending, Ending::TimedOut);
+        assert!(out.elapsed < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn escaped_children_dont_hold_up_the_run(){
+        let mut limits = Limits::default();
+        limits.wall = Duration::from_millis(500);
+        let sandbox = match Sandbox::new(limits) {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+        // The killed group leaves the setsid child with our pipes.
+        let out = sandbox.run("sh", &sh("echo hi; setsid sleep 10 & sleep 30"), &CancelToken::new()).unwrap();
+        assert_eq!(out.ending, Ending::TimedOut);
+        assert_eq!(out.stdout, "hi\n");
+        assert!(out.elapsed < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn runs_can_be_cancelled(){
+        let sandbox = match Sandbox::new(Limits::default()) {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+        let cancel = CancelToken::new();
+        let stopper = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            stopper.cancel();
+        });
+        let out = sandbox.run("sh", &sh("sleep 30"), &cancel).unwrap();
+        assert_eq!(out.ending, Ending::Cancelled);
+    }
+}
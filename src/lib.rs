@@ -12,6 +12,7 @@ extern crate url;
 extern crate bincode;
 extern crate socket_addr;
 extern crate config_file_handler;
+extern crate sodiumoxide;
 
 pub mod compile;
 pub mod logger;
@@ -5,46 +5,632 @@ extern crate maidsafe_utilities;
 extern crate rand;
 extern crate getopts;
 extern crate crust;
+extern crate time;
 extern crate p2p3;
 
 use std::env;
 use getopts::Options;
 use std::thread;
-use p2p3::storage::storage_helper::GitAccess;
-use p2p3::woot::static_site::StaticSite;
-use p2p3::woot::site::UISend;
+use p2p3::storage::storage_helper::{GitAccess, Pull, PullKind, DEFAULT_BRANCH, pair_branch_name};
+use p2p3::storage::backend::{SharedStorage, GitRemote, LocalGit, PlainDirectory};
+use p2p3::storage::credentials::{CredentialProvider, redact_url};
+use p2p3::woot::site::{Site, UISend};
+use p2p3::woot::char_id::CharId;
+use p2p3::woot::documents::{Document, Documents, is_repo_path};
 use p2p3::permission::permissions_handler::get_permission_level;
 use p2p3::permission::permissions_handler::PermissionLevel;
-use p2p3::compile::{CompileMode, run_code};
-use p2p3::ui::{Command, FnCommand, static_ui_handler};
+use p2p3::permission::acl::{Acl, Action, Role, ACL_FILE, action_on};
+use p2p3::permission::signing::{Signer, SigningKeys, KEY_FILE, key_hex, verify, verify_notice};
+use p2p3::permission::grants::{Grant, Grants, Revocation};
+use p2p3::compile::{RunEvent, RunIo, RunReport, run_code};
+use p2p3::compile::project::{build, targets};
+use p2p3::compile::diagnostics::{offset_of, position_of};
+use p2p3::compile::languages::{Languages, LANGUAGES_FILE};
+use p2p3::compile::remote::{PendingRuns, RunRequest, Toolchains, MAX_RUN_SOURCE};
+use p2p3::compile::sandbox::CancelToken;
+use p2p3::ui::{Command, FnCommand, UiHandler, static_ui_handler};
+use p2p3::storage::coauthors::{Authors, with_trailers};
+use p2p3::storage::diff::{unified, revert_lines};
+use p2p3::storage::coordinator::CommitCoordinator;
+use p2p3::storage::workspace::{SyncStatus, Workspace, spawn_autosave};
+use p2p3::storage::file_tree::is_text_file;
 use p2p3::utils::p2p3_globals;
 use p2p3::network::{MessagePasser, MessagePasserT};
-use p2p3::network::bootstrap::BootstrapHandler;
+use p2p3::network::bootstrap::{BootstrapHandler, BootstrapSource, parse_contacts, spawn_contact_maintenance};
+use p2p3::network::invite::{Admissions, Invite, InviteBook};
 use p2p3::msg::Msg;
 use std::io::stdin;
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::Path;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::time::Duration;
 use crust::PeerId;
 use rand::random;
 
+type SessionDocs = Mutex<Documents<MessagePasser<Msg>>>;
+// Runner and id of the run in our run panel, the last one started.
+type ShownRun = Mutex<Option<(PeerId, u64)>>;
+// Commits shown by the history panel.
+const HISTORY_LENGTH: usize = 50;
+const GRANT_CHECK_SECS: u64 = 5;
+const JOIN_CHECK_SECS: u64 = 5;
+
 fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} FILE [options]", program);
+    let brief = format!("Usage: {0} [options]\n       {0} invite [options]\n       {0} join TOKEN [options]", program);
     print!("{}", opts.usage(&brief));
 }
 
+// From the first invite on, peers that connect have to join through one.
+fn print_invite(mp: &MessagePasser<Msg>, invites: &Mutex<InviteBook>, admissions: &Mutex<Admissions>,
+                expires_mins: Option<i64>, secret: Option<String>) {
+    let (session_id, branch) = {
+        let globals = p2p3_globals().inner.clone();
+        let values = globals.lock().unwrap();
+        (values.get_session_id(), values.get_git_access().branch)
+    };
+    let expires = expires_mins.map(|m| time::get_time().sec + m * 60);
+    let invite = Invite::new(mp, session_id, branch, expires, secret);
+    invites.lock().unwrap().issue(&invite);
+    admissions.lock().unwrap().gate(&mp.neighbours());
+    println!("###############################");
+    println!("Share this invite to let someone join:");
+    println!("{}", invite.to_token());
+    println!("###############################");
+}
+
+// The role `role_for` gives `peer`'s git email. The email is only what the
+// peer claims, unless the ACL lists the key it signs with for that email the
+// claim may lower its role but not raise it.
+fn trusted_role<F: Fn(Option<&str>) -> Role>(authors: &Mutex<Authors>, peer: &PeerId, me: &PeerId, role_for: F) -> Role {
+    let authors = authors.lock().unwrap();
+    let claimed = role_for(authors.get(peer).map(|a| &a.email[..]));
+    if *peer == *me || authors.is_verified(peer) {
+        claimed
+    } else {
+        ::std::cmp::min(claimed, role_for(None))
+    }
+}
+
+// What `peer` may do with `path` according to the ACL. Our own role is also
+// capped by whether we may push at all. A peer that has not proved its git
+// identity gets no more than the ACL's default. Granted edit rights lift
+// anyone who may open the file to editor, where the granter may edit it.
+fn role_of(authors: &Mutex<Authors>, peer: &PeerId, path: &str) -> Role {
+    let (acl, level, me, grants) = {
+        let globals = p2p3_globals().inner.clone();
+        let values = globals.lock().unwrap();
+        (values.get_acl(), values.get_permission_level(), values.get_site_id(), values.get_grants())
+    };
+    let acl_role = |who: &PeerId| {
+        let role = trusted_role(authors, who, &me, |email| acl.role(email, path));
+        if *who == me { ::std::cmp::min(role, level.ceiling()) } else { role }
+    };
+    let mut role = acl_role(peer);
+    if role >= Role::Viewer {
+        if let Some(granter) = grants.granter_of(peer, time::get_time().sec) {
+            if acl_role(&granter).allows(Action::Edit) {
+                role = ::std::cmp::max(role, Role::Editor);
+            }
+        }
+    }
+    role
+}
+
+// Only peers the ACL makes an editor somewhere may hand out edit rights,
+// granted rights can't be passed on.
+fn can_grant(authors: &Mutex<Authors>, peer: &PeerId) -> bool {
+    let (acl, level, me) = {
+        let globals = p2p3_globals().inner.clone();
+        let values = globals.lock().unwrap();
+        (values.get_acl(), values.get_permission_level(), values.get_site_id())
+    };
+    let mut highest = trusted_role(authors, peer, &me, |email| acl.highest_role(email));
+    if *peer == me {
+        highest = ::std::cmp::min(highest, level.ceiling());
+    }
+    highest >= Role::Editor
+}
+
+fn update_grants<F: FnOnce(&mut Grants) -> R, R>(f: F) -> R {
+    let globals = p2p3_globals().inner.clone();
+    let mut values = globals.lock().unwrap();
+    let mut grants = values.get_grants();
+    let result = f(&mut grants);
+    values.set_grants(grants);
+    result
+}
+
+fn send_edit_rights(ui: &Mutex<UiHandler>) {
+    let grants = {
+        let globals = p2p3_globals().inner.clone();
+        let values = globals.lock().unwrap();
+        values.get_grants()
+    };
+    ui.lock().unwrap().send_command(Command::EditRights(grants.all()));
+}
+
+// After our rights changed, lets the editor follow our role on the open file.
+fn refresh_editing(mp: &MessagePasser<Msg>, documents: &SessionDocs, ui: &Mutex<UiHandler>, authors: &Mutex<Authors>) {
+    let path = documents.lock().unwrap().active();
+    let role = role_of(authors, mp.get_id(), &path);
+    let ui = ui.lock().unwrap();
+    ui.send_command(Command::Role(role.to_string()));
+    if role.allows(action_on(&path, Action::Edit)) {
+        ui.send_command(Command::EnableEditing);
+    } else {
+        ui.send_command(Command::DisableEditing(format!("{} is read only for a {}", path, role)));
+    }
+}
+
+fn load_acl(root: &str) -> Acl {
+    match Acl::read(root) {
+        Ok(Some(acl)) => acl,
+        Ok(None) => Acl::open(),
+        Err(e) => {
+            println!("Could not read {}, everyone is a viewer: {}", ACL_FILE, e);
+            Acl::only(Role::Viewer)
+        },
+    }
+}
+
+// Roles change as soon as a commit or pull brings in a new ACL file.
+fn reload_acl(mp: &MessagePasser<Msg>, documents: &SessionDocs, ui: &Mutex<UiHandler>, authors: &Mutex<Authors>) {
+    let globals = p2p3_globals().inner.clone();
+    let storage = globals.lock().unwrap().get_storage();
+    let acl = load_acl(storage.root());
+    globals.lock().unwrap().set_acl(acl);
+    println!("Reloaded {}", ACL_FILE);
+    refresh_editing(mp, documents, ui, authors);
+}
+
+// The editor follows our role on the file it shows, the ACL file is read only
+// for anyone but its owners.
+fn send_role(ui: &UiHandler, role: Role, path: &str) {
+    ui.send_command(Command::Role(role.to_string()));
+    if role.allows(Action::Edit) && !role.allows(action_on(path, Action::Edit)) {
+        ui.send_command(Command::DisableEditing(format!("only owners may change {}", path)));
+    }
+}
+
+// A run on this machine, started from our editor or for the peer who asked.
+struct LocalRun {
+    id: u64,
+    // who started it, the only peer whose input and cancel it takes
+    owner: PeerId,
+    // peers who may open the file and so get to watch
+    watchers: Vec<PeerId>,
+    cancel: CancelToken,
+    stdin: Sender<Option<String>>,
+}
+
+// Our run on a peer's toolchain.
+struct RemoteRun {
+    runner: PeerId,
+    id: u64,
+    path: String,
+    snapshot: Snapshot,
+}
+
+enum CurrentRun {
+    Local(LocalRun),
+    Remote(RemoteRun),
+}
+
+fn stop_run(mp: &MessagePasser<Msg>, run: &CurrentRun) {
+    match *run {
+        CurrentRun::Local(ref local) => local.cancel.cancel(),
+        CurrentRun::Remote(ref remote) => mp.send(&remote.runner, Msg::RunCancel(remote.id)),
+    }
+}
+
+// Shows a step of our run in our run panel and in the watchers'.
+fn publish_run(mp: &MessagePasser<Msg>, ui: &Mutex<UiHandler>, shown: &ShownRun, watchers: &[PeerId], id: u64, event: RunEvent) {
+    for peer in watchers {
+        mp.send(peer, Msg::RunUpdate(mp.get_id().clone(), id, event.clone()));
+    }
+    show_run_update(ui, shown, mp.get_id().clone(), id, event);
+}
+
+// A finished run's diagnostics also go to the editor's gutter, if it is the
+// run the panel shows.
+fn show_run_update(ui: &Mutex<UiHandler>, shown: &ShownRun, runner: PeerId, id: u64, event: RunEvent) {
+    let watched = {
+        let mut shown = shown.lock().unwrap();
+        if let RunEvent::Started(_) = event {
+            *shown = Some((runner.clone(), id));
+        }
+        *shown == Some((runner.clone(), id))
+    };
+    let ui = ui.lock().unwrap();
+    if let RunEvent::Finished(ref report) = event {
+        if watched {
+            ui.send_command(Command::Diagnostics(report.diagnostics.clone()));
+        }
+    }
+    ui.send_command(Command::RunUpdate(runner, id, event));
+}
+
+// Starts `work` as our run, stopping the one before it. The peers who may
+// open `path` watch it. It runs off the UI thread and without any lock held,
+// so edits, input and cancelling keep working while it runs.
+fn start_run<F>(mp: &MessagePasser<Msg>, ui: &Arc<Mutex<UiHandler>>, shown: &Arc<ShownRun>, authors: &Mutex<Authors>,
+                current_run: &Mutex<Option<CurrentRun>>, path: &str, title: String, id: u64, owner: PeerId, work: F)
+    where F: FnOnce(&RunIo) -> Result<RunReport, String> + Send + 'static
+{
+    let watchers: Vec<PeerId> = mp.peers().into_iter()
+        .filter(|peer| role_of(authors, peer, path).allows(Action::Open)).collect();
+    let cancel = CancelToken::new();
+    let (stdin_tx, stdin_rx) = channel();
+    {
+        let mut run = current_run.lock().unwrap();
+        if let Some(ref previous) = *run {
+            stop_run(mp, previous);
+        }
+        *run = Some(CurrentRun::Local(LocalRun { id: id, owner: owner, watchers: watchers.clone(),
+                                                 cancel: cancel.clone(), stdin: stdin_tx }));
+    }
+    let ui = ui.clone();
+    let shown = shown.clone();
+    let mp = mp.clone();
+    thread::spawn(move || {
+        publish_run(&mp, &ui, &shown, &watchers, id, RunEvent::Started(title));
+        let io = RunIo {
+            cancel: &cancel,
+            stdin: &stdin_rx,
+            on_line: &|stream, line| publish_run(&mp, &ui, &shown, &watchers, id, RunEvent::Line(stream, line.to_string())),
+        };
+        let event = match work(&io) {
+            Ok(report) => RunEvent::Finished(report),
+            Err(e) => {
+                println!("error {}", e);
+                RunEvent::Failed(e)
+            },
+        };
+        publish_run(&mp, &ui, &shown, &watchers, id, event);
+    });
+}
+
+// A buffer as a run or build got it, to move the diagnostics along with the
+// edits made since.
+struct Snapshot {
+    content: String,
+    ids: Vec<CharId>,
+    site: Arc<Mutex<Site>>,
+}
+
+impl Snapshot {
+    fn take(site: &Arc<Mutex<Site>>) -> Snapshot {
+        let mut locked = site.lock().unwrap();
+        Snapshot { content: locked.content(), ids: locked.visible_ids(), site: site.clone() }
+    }
+}
+
+// Points the diagnostics about the snapshotted files at the same characters
+// in the live buffers.
+fn follow_edits(report: &mut RunReport, snapshots: &BTreeMap<String, Snapshot>) {
+    for d in &mut report.diagnostics {
+        if let Some(snapshot) = snapshots.get(&d.file) {
+            let mut site = snapshot.site.lock().unwrap();
+            let content = site.content();
+            let now = match snapshot.ids.get(offset_of(&snapshot.content, d.line, d.column)) {
+                Some(id) => site.current_index(id),
+                None => content.chars().count(),
+            };
+            let (line, column) = position_of(&content, now);
+            d.line = line;
+            d.column = d.column.map(|_| column);
+        }
+    }
+}
+
+// Writes every live buffer to its file, so builds and the disk agree, and
+// returns what was written. Files changed outside of the session are left
+// alone and reported.
+fn flush_documents(documents: &SessionDocs, ui: &Mutex<UiHandler>) -> BTreeMap<String, Snapshot> {
+    let mut snapshots = BTreeMap::new();
+    for (path, doc) in documents.lock().unwrap().all() {
+        let snapshot = Snapshot::take(&doc.site);
+        match doc.workspace.lock().unwrap().flush(&snapshot.content, false) {
+            Ok(SyncStatus::ExternalChange) => {
+                println!("{} was changed outside of the session, not overwriting it", path);
+                ui.lock().unwrap().send_command(Command::ExternalChange(path));
+            },
+            Ok(_) => {
+                snapshots.insert(path, snapshot);
+            },
+            Err(e) => println!("Could not save {}: {}", path, e),
+        }
+    }
+    snapshots
+}
+
+fn send_build_targets(ui: &Mutex<UiHandler>) {
+    let root = {
+        let globals = p2p3_globals().inner.clone();
+        let values = globals.lock().unwrap();
+        PathBuf::from(values.get_storage().root())
+    };
+    match targets(&root) {
+        Ok(targets) => ui.lock().unwrap().send_command(Command::BuildTargets(targets)),
+        Err(e) => println!("Could not read the builds: {}", e),
+    }
+}
+
+fn may(authors: &Mutex<Authors>, peer: &PeerId, path: &str, action: Action) -> bool {
+    let action = action_on(path, action);
+    let allowed = role_of(authors, peer, path).allows(action);
+    if !allowed {
+        println!("{:?} may not {:?} {}", peer, action, path);
+    }
+    allowed
+}
+
+// Path and live content of every document we have open.
+fn open_contents(documents: &SessionDocs) -> Vec<(String, String)> {
+    let docs = documents.lock().unwrap();
+    docs.all().into_iter().map(|(path, doc)| {
+        let content = doc.site.lock().unwrap().content();
+        (path, content)
+    }).collect()
+}
+
+// Commits the content every peer agreed on and lets the session know the sha.
+// Everyone who typed in the session since the last commit is credited with a
+// Co-authored-by trailer. Only the files `requester` may commit are included.
+// Documents only the requester has open are committed with the content it
+// sent along in `theirs`; the requester is told about those left out.
+fn commit_session(coordinator: &CommitCoordinator, mp: &MessagePasser<Msg>, documents: &SessionDocs, ui: &Mutex<UiHandler>,
+                  authors: &Mutex<Authors>, signer: &Signer, requester: &PeerId, message: &str,
+                  theirs: Vec<(String, String)>) {
+    let (ga, storage) = {
+        let globals = p2p3_globals().inner.clone();
+        let values = globals.lock().unwrap();
+        (values.get_git_access(), values.get_storage())
+    };
+    let (mut files, contributors) = {
+        let docs = documents.lock().unwrap();
+        let files: Vec<_> = docs.all().into_iter().map(|(path, doc)| {
+            let content = doc.site.lock().unwrap().content();
+            (path, content, doc.workspace.clone())
+        }).collect();
+        (files, docs.contributors())
+    };
+    let asked: Vec<String> = theirs.iter().map(|&(ref path, _)| path.clone()).collect();
+    for (path, content) in theirs {
+        if is_repo_path(&path) && !files.iter().any(|f| f.0 == path) {
+            let workspace = Workspace::new(Path::new(storage.root()).join(&path));
+            files.push((path, content, Arc::new(Mutex::new(workspace))));
+        }
+    }
+    let files: Vec<_> = files.into_iter().filter(|f| may(authors, requester, &f.0, Action::Commit)).collect();
+    let left_out: Vec<String> = asked.into_iter().filter(|path| !files.iter().any(|f| f.0 == *path)).collect();
+    if !left_out.is_empty() {
+        println!("Leaving {:?} out of the commit asked for by {:?}", left_out, requester);
+        if requester == mp.get_id() {
+            show_left_out(ui, &left_out);
+        } else {
+            mp.send(requester, Msg::CommitLeftOut(left_out));
+        }
+    }
+    if files.is_empty() {
+        println!("Nothing {:?} may commit", requester);
+        return;
+    }
+    let message = if message.trim().is_empty() { "Session commit" } else { message };
+    let message = match ga.identity() {
+        Ok(committer) => with_trailers(message, &committer, &authors.lock().unwrap().lookup(&contributors)),
+        Err(_) => message.to_string(),
+    };
+    match coordinator.commit(&*storage, &files, &message) {
+        Ok(outcome) => {
+            documents.lock().unwrap().clear_contributors();
+            for path in outcome.overwritten {
+                println!("{} was changed outside of the session, the session's version was committed", path);
+                ui.lock().unwrap().send_command(Command::ExternalChange(path));
+            }
+            if let Some(ref pull) = outcome.pulled {
+                integrate_pull(mp, documents, ui, authors, signer, pull);
+            }
+            if files.iter().any(|f| f.0 == ACL_FILE) {
+                reload_acl(mp, documents, ui, authors);
+            }
+            let conflicted = outcome.pulled.as_ref().map_or(false, |p| p.kind == PullKind::Conflicts);
+            // Without versions there is no sha, the UI just says saved.
+            let sha = outcome.sha.unwrap_or(String::new());
+            if conflicted {
+                println!("Committed {} locally, resolve the conflicts and commit again to push", sha);
+            } else {
+                println!("Committed at {}", sha);
+                mp.broadcast(Msg::Committed(signer.sign_notice((mp.get_id().clone(), sha.clone()))));
+                ui.lock().unwrap().send_command(Command::Committed(sha));
+            }
+        },
+        Err(e) => println!("Commit error: {}", e),
+    }
+}
+
+fn show_left_out(ui: &Mutex<UiHandler>, paths: &[String]) {
+    let text = format!("Not committed: {}", paths.join(", "));
+    ui.lock().unwrap().send_command(Command::Output(text));
+}
+
+// Merges what a pull brought in into the live documents and tells the whole
+// session which files have conflicts to resolve.
+fn integrate_pull(mp: &MessagePasser<Msg>, documents: &SessionDocs, ui: &Mutex<UiHandler>, authors: &Mutex<Authors>,
+                  signer: &Signer, pull: &Pull) {
+    let conflicts = documents.lock().unwrap().merge_pulled(pull);
+    if pull.files.iter().any(|f| f.path == ACL_FILE) {
+        reload_acl(mp, documents, ui, authors);
+    }
+    if !conflicts.is_empty() {
+        println!("Conflicts in {:?}", conflicts);
+    }
+    mp.broadcast(Msg::Pulled(signer.sign_notice((mp.get_id().clone(), conflicts.clone()))));
+    ui.lock().unwrap().send_command(Command::Pulled(conflicts));
+}
+
+// Only the committer pulls, so merged changes enter the documents once.
+fn pull_session(coordinator: &CommitCoordinator, mp: &MessagePasser<Msg>, documents: &SessionDocs, ui: &Mutex<UiHandler>,
+                authors: &Mutex<Authors>, signer: &Signer) {
+    let storage = {
+        let globals = p2p3_globals().inner.clone();
+        let values = globals.lock().unwrap();
+        values.get_storage()
+    };
+    match coordinator.pull(&*storage) {
+        Ok(Some(pull)) => integrate_pull(mp, documents, ui, authors, signer, &pull),
+        Ok(None) => println!("Nothing to pull from"),
+        Err(e) => println!("Pull error: {}", e),
+    }
+}
+
+// Nobody connected, us included, may both push and commit.
+fn no_committer(ui: &Mutex<UiHandler>) {
+    println!("Nobody in the session can push");
+    ui.lock().unwrap().send_command(Command::Output("Nobody in the session can push, ask an editor to join".to_string()));
+}
+
+// Shows `path` in our editor. A file another peer is editing is fetched from
+// that peer, so both sides share the same WOOT sequence.
+fn open_file(mp: &MessagePasser<Msg>, documents: &SessionDocs, ui: &Mutex<UiHandler>, authors: &Mutex<Authors>, path: &str) {
+    let role = role_of(authors, mp.get_id(), path);
+    if !role.allows(Action::Open) {
+        println!("Not opening {}, our role there is {}", path, role);
+        return;
+    }
+    let mut docs = documents.lock().unwrap();
+    let ga = {
+        let globals = p2p3_globals().inner.clone();
+        let values = globals.lock().unwrap();
+        values.get_git_access()
+    };
+    let full_path = ga.local_url.clone() + path;
+    if docs.get(path).is_none() {
+        if Path::new(&full_path).exists() && !is_text_file(Path::new(&full_path)) {
+            println!("Not opening {}, it is not a text file", path);
+            return;
+        }
+        // Only an editor's copy is taken, a peer that may not edit it could
+        // hand us anything.
+        let editor = docs.peers_with(path).into_iter()
+            .find(|peer| role_of(authors, peer, path).allows(action_on(path, Action::Edit)));
+        if let Some(peer) = editor {
+            println!("Asking {:?} for {}", peer, path);
+            docs.set_active(path);
+            docs.request_from_peer(path, peer.clone());
+            mp.send(&peer, Msg::DocumentRequest(path.to_string()));
+            mp.broadcast(Msg::OpenedFile(mp.get_id().clone(), path.to_string()));
+            let ui = ui.lock().unwrap();
+            send_role(&ui, role, path);
+            ui.send_command(Command::ActiveFile(path.to_string()));
+            return;
+        }
+    }
+    match docs.open_from_disk(path) {
+        Ok(doc) => {
+            let content = doc.site.lock().unwrap().content();
+            let ui = ui.lock().unwrap();
+            ui.send_command(Command::InsertString(0, content));
+            send_role(&ui, role, path);
+            ui.send_command(Command::ActiveFile(path.to_string()));
+            mp.broadcast(Msg::OpenedFile(mp.get_id().clone(), path.to_string()));
+        },
+        Err(e) => println!("could not open {}: {}", full_path, e),
+    }
+}
+
+// Files we have no access to are left out.
+fn send_file_tree(ui: &Mutex<UiHandler>, authors: &Mutex<Authors>) {
+    let (storage, me) = {
+        let globals = p2p3_globals().inner.clone();
+        let values = globals.lock().unwrap();
+        (values.get_storage(), values.get_site_id())
+    };
+    match storage.files() {
+        Ok(files) => {
+            let visible = files.into_iter().filter(|f| role_of(authors, &me, f).allows(Action::Open)).collect();
+            ui.lock().unwrap().send_command(Command::FileTree(visible));
+        },
+        Err(e) => println!("Could not list files: {}", e),
+    }
+}
+
+fn send_history(ui: &Mutex<UiHandler>, path: &str) {
+    let storage = {
+        let globals = p2p3_globals().inner.clone();
+        let values = globals.lock().unwrap();
+        values.get_storage()
+    };
+    match storage.history(path, HISTORY_LENGTH) {
+        Ok(entries) => ui.lock().unwrap().send_command(Command::HistoryEntries(entries)),
+        Err(e) => println!("Could not read the history of {}: {}", path, e),
+    }
+}
+
+fn send_diff(ui: &Mutex<UiHandler>, path: &str, doc: &Document, rev: &str) {
+    let storage = {
+        let globals = p2p3_globals().inner.clone();
+        let values = globals.lock().unwrap();
+        values.get_storage()
+    };
+    let live = doc.site.lock().unwrap().content();
+    let name: String = if rev.is_empty() { "HEAD".to_string() } else { rev.chars().take(7).collect() };
+    match storage.load_version(path, rev) {
+        Ok(old) => {
+            let diff = unified(&old, &live, &format!("{} ({})", path, name), &format!("{} (live)", path));
+            ui.lock().unwrap().send_command(Command::DiffOutput(diff));
+        },
+        Err(e) => println!("Could not diff {} against {}: {}", path, rev, e),
+    }
+}
+
+// Goes through the WOOT site like any edit, so every peer sees the revert.
+fn revert_file(path: &str, doc: &Document, rev: &str, lines: Option<(usize, usize)>) {
+    let storage = {
+        let globals = p2p3_globals().inner.clone();
+        let values = globals.lock().unwrap();
+        values.get_storage()
+    };
+    let old = match storage.load_version(path, rev) {
+        Ok(old) => old,
+        Err(e) => {
+            println!("Could not read {} at {}: {}", path, rev, e);
+            return;
+        }
+    };
+    let mut site = doc.site.lock().unwrap();
+    let target = match lines {
+        Some((start, end)) => revert_lines(&site.content(), &old, start, end),
+        None => old,
+    };
+    site.apply_text(&target);
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
 
     let mut opts = Options::new();
-    opts.optopt("u", "", "URL to the git repo to connect to", "URL");
-    opts.optopt("n", "", "Username", "Username");
-    opts.optopt("p", "", "Password", "Password");
+    opts.optopt("u", "", "URL to the git repo to connect to, without one the session is kept in a local repo", "URL");
+    opts.optflag("", "plain", "Without -u, keep the files in a plain directory instead of a local git repo");
+    opts.optopt("n", "", "Git username, when the remote URL does not name one", "Username");
+    opts.optopt("k", "ssh-key", "SSH private key to push with, instead of ~/.ssh/id_*", "KeyFile");
     opts.optopt("s", "", "Site id", "SiteId");
     opts.optopt("f", "", "File path to clone the git repo", "FilePath");
     opts.optopt("d", "port", "Port number", "PortNumber");
+    opts.optmulti("c", "contact", "Bootstrap contact, may be given more than once", "IP:PORT");
+    opts.optflag("l", "lan", "Only discover peers on the local network");
+    opts.optflag("", "new-session", "Start a new session, overwriting the committed config.p2p3");
+    opts.optopt("", "expires", "Minutes until an invite expires", "MINUTES");
+    opts.optopt("", "secret", "Join secret to put in an invite", "SECRET");
+    opts.optopt("b", "branch", "Branch the session works on (default master)", "BRANCH");
+    opts.optopt("", "base", "Branch to start a new session branch from (default master)", "BRANCH");
+    opts.optflag("", "pair", "Start a fresh pair/<date> branch for this session");
+    opts.optopt("", "autosave", "Seconds between writes of the live file to disk, 0 to disable (default 30)", "SECONDS");
+    opts.optopt("", "languages", "JSON file adding languages to run code in (default ~/.p2p3/languages.json)", "FILE");
     opts.optflag("h", "help", "print this help menu");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
@@ -54,14 +640,59 @@ fn main() {
         print_usage(&program, opts);
         return;
     };
-    if matches.free.len() > 0 {
-        print_usage(&program, opts);
-        return;
+    let mut join_invite = None;
+    let mut want_invite = false;
+    match matches.free.len() {
+        0 => {},
+        1 if matches.free[0] == "invite" => want_invite = true,
+        2 if matches.free[0] == "join" => {
+            match Invite::from_token(&matches.free[1]) {
+                Ok(invite) => join_invite = Some(invite),
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            }
+        },
+        _ => {
+            print_usage(&program, opts);
+            return;
+        }
+    };
+    let invite_expires = match matches.opt_str("expires").map(|m| m.parse::<i64>()) {
+        Some(Ok(minutes)) => Some(minutes),
+        Some(Err(_)) => {
+            println!("--expires takes a number of minutes");
+            print_usage(&program, opts);
+            return;
+        },
+        None => None,
+    };
+    let invite_secret = matches.opt_str("secret");
+    let autosave_secs = match matches.opt_str("autosave").map(|s| s.parse::<u64>()) {
+        Some(Ok(secs)) => secs,
+        Some(Err(_)) => {
+            println!("--autosave takes a number of seconds");
+            print_usage(&program, opts);
+            return;
+        },
+        None => 30,
+    };
+    let languages_file = matches.opt_str("languages").map(PathBuf::from)
+        .or(env::home_dir().map(|home| home.join(LANGUAGES_FILE)));
+    let languages = match languages_file {
+        Some(ref file) => match Languages::read(file) {
+            Ok(languages) => languages,
+            Err(e) => {
+                println!("Could not read {}, only the built in languages can run: {}", file.display(), e);
+                Languages::builtin()
+            },
+        },
+        None => Languages::builtin(),
     };
 
-    let git_url = matches.opt_str("u").unwrap();
-    let git_username = matches.opt_str("n").unwrap();
-    let git_password = matches.opt_str("p").unwrap();
+    let git_url = matches.opt_str("u");
+    let credentials = CredentialProvider::discover(matches.opt_str("n"), matches.opt_str("k").map(PathBuf::from));
     let port = matches.opt_str("d").unwrap();
     let port_number = port.parse::<u16>().unwrap();
     let local_path = matches.opt_str("f").unwrap();
@@ -71,41 +702,189 @@ fn main() {
     let port_js = format!("var portNumber = {};", port_number);
     write_to_file(&port_js_path, &port_js);
     let file_path = "c_code.c";
-    let git_access = GitAccess::new(git_url.clone(), local_path.clone(), file_path.to_string().clone(), git_username.clone(), git_password.clone());
+    let mut git_access = GitAccess::new(git_url.clone().unwrap_or(String::new()), local_path.clone(),
+                                        file_path.to_string().clone(), credentials);
+    // Without a remote the session lives in a local git repo or a plain directory.
+    let storage: SharedStorage = if git_url.is_some() {
+        if let Err(e) = GitRemote::new(git_access.clone()).prepare() {
+            println!("Could not get {}: {}", redact_url(&git_access.repo_url), e);
+            return;
+        }
+        let base = matches.opt_str("base").unwrap_or(DEFAULT_BRANCH.to_string());
+        let branch = if let Some(ref invite) = join_invite {
+            invite.branch.clone()
+        } else if matches.opt_present("pair") {
+            let date = time::strftime("%Y-%m-%d", &time::now()).unwrap();
+            pair_branch_name(&date, &git_access.remote_branches().unwrap_or(vec![]))
+        } else {
+            matches.opt_str("b").unwrap_or(DEFAULT_BRANCH.to_string())
+        };
+        git_access.set_branch(branch);
+        if let Err(e) = git_access.checkout_branch(&base) {
+            println!("Could not check out {}: {}", git_access.branch, e);
+            return;
+        }
+        Arc::new(GitRemote::new(git_access.clone()))
+    } else {
+        let storage: SharedStorage = if matches.opt_present("plain") {
+            Arc::new(PlainDirectory::new(local_path.clone()))
+        } else {
+            Arc::new(LocalGit::new(git_access.clone()))
+        };
+        if let Err(e) = storage.prepare() {
+            println!("Could not prepare {}: {}", local_path, e);
+            return;
+        }
+        storage
+    };
+    let acl = load_acl(storage.root());
     {
         let id: PeerId = random();
         let globals = p2p3_globals().inner.clone();
         let mut values = globals.lock().unwrap();
         values.init(id, port_number, p2p3_url.clone(), git_access.clone());
+        values.set_storage(storage.clone());
+        values.set_acl(acl);
+        values.set_languages(languages);
+        if let Some(ref invite) = join_invite {
+            values.set_session_id(invite.session_id);
+        }
     }
-    match git_access.clone_repo() {
-        Ok(()) => {},
-        Err(_) => {
-            println!("The folder already exits");
-        },
-    };
 
     println!("Starting bootstrap");
-    let boot = BootstrapHandler::bootstrap_load();
+    let source = if matches.opt_present("new-session") {
+        BootstrapSource::NewSession
+    } else if matches.opt_present("l") {
+        BootstrapSource::LanOnly
+    } else if matches.opt_present("c") {
+        match parse_contacts(&matches.opt_strs("c")) {
+            Ok(addrs) => BootstrapSource::Contacts(addrs),
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        }
+    } else {
+        BootstrapSource::RepoFile
+    };
+    // Joining through an invite connects directly, config.p2p3 is left alone.
+    let lan_only = match source {
+        BootstrapSource::LanOnly => true,
+        _ => join_invite.is_some(),
+    };
+    let mut boot = match BootstrapHandler::bootstrap_from(source) {
+        Ok(boot) => boot,
+        Err(e) => {
+            println!("Bootstrap failed: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = boot.write_crust_config() {
+        println!("Could not write crust config: {}", e);
+    }
     let (mp,_) = MessagePasser::<Msg>::new();
-    boot.update_config(mp.clone());
+    if !lan_only {
+        if let Err(e) = boot.update_config(mp.clone()) {
+            println!("Could not update {}: {}", boot.config_file, e);
+        }
+    }
+    let my_contact = boot.own_contact;
+    let boot = Arc::new(Mutex::new(boot));
+    let coordinator = CommitCoordinator::new();
+    if !lan_only {
+        spawn_contact_maintenance(boot.clone(), mp.clone(), coordinator.clone());
+    }
+    let leave_mp = mp.clone();
+    let invites = Arc::new(Mutex::new(InviteBook::new()));
+    // Until it joined, a peer may only send its join request.
+    let admissions = Arc::new(Mutex::new(Admissions::new()));
+    {
+        let admissions = admissions.clone();
+        mp.set_admission(Box::new(move |peer, msg| {
+            match msg {
+                Some(&Msg::JoinRequest(_, _)) => true,
+                _ => admissions.lock().unwrap().allows(peer),
+            }
+        }));
+    }
+    if let Some(invite) = join_invite {
+        let host = invite.host.clone();
+        let secret = invite.secret.clone();
+        let session_id = invite.session_id;
+        match invite.join(&mp, time::get_time().sec, Duration::from_secs(30)) {
+            Ok(()) => mp.send(&host, Msg::JoinRequest(session_id, secret)),
+            Err(e) => {
+                println!("Could not join: {}", e);
+                return;
+            }
+        }
+    }
+    if want_invite {
+        print_invite(&mp, &invites, &admissions, invite_expires, invite_secret.clone());
+    }
     println!("###############################");
     println!("My id is {:?}", mp.get_id());
     println!("###############################");
 
 
-    let permission_level = get_permission_level(&git_access);
+    let authors = Arc::new(Mutex::new(Authors::new()));
+    let identity = match git_access.identity() {
+        Ok(me) => {
+            authors.lock().unwrap().insert(mp.get_id().clone(), me.clone());
+            Some(me)
+        },
+        Err(e) => {
+            println!("No git identity to announce: {}", e);
+            None
+        },
+    };
+    // Every operation we broadcast is signed, the peers learn the key with our identity.
+    let key_file = env::home_dir().unwrap_or(env::temp_dir()).join(KEY_FILE);
+    let signer = match Signer::load_or_generate(&key_file) {
+        Ok(signer) => signer,
+        Err(e) => {
+            println!("Could not keep a signing key at {}, using one for this session only: {}", key_file.display(), e);
+            Signer::generate()
+        },
+    };
+    println!("Signing key: {}, list it in {} to prove who we are", key_hex(&signer.public_key()), ACL_FILE);
+    let signer = Arc::new(signer);
+    let signing_keys = Arc::new(Mutex::new(SigningKeys::new()));
+    signing_keys.lock().unwrap().insert(mp.get_id().clone(), signer.public_key());
+    mp.broadcast(Msg::Identity(identity.clone(), signer.public_key()));
+    // Peers without a toolchain can ask us to run code in the modes we have.
+    let my_toolchains = {
+        let globals = p2p3_globals().inner.clone();
+        let values = globals.lock().unwrap();
+        values.get_languages().installed()
+    };
+    println!("Can run {}", my_toolchains.join(", "));
+    mp.broadcast(Msg::Toolchains(my_toolchains.clone()));
+    let toolchains = Arc::new(Mutex::new(Toolchains::new()));
+    let pending_runs = Arc::new(Mutex::new(PendingRuns::new()));
+
+    // Only a remote can refuse our pushes.
+    let permission_level = if git_url.is_some() {
+        get_permission_level(&git_access)
+    } else {
+        PermissionLevel::Editor
+    };
     match permission_level {
         PermissionLevel::Editor => println!("The user is an editor"),
         PermissionLevel::Viewer => println!("The user is a viewer"),
     };
 
-    let file_name = &(local_path+file_path);
     {
         let globals = p2p3_globals().inner.clone();
         let mut values = globals.lock().unwrap();
         values.set_site_id(mp.get_id().clone());
+        values.set_permission_level(permission_level);
     }
+    // Only peers that can push are elected committer, the others learn it
+    // from a signed claim the ACL has to back.
+    let can_push = can_grant(&authors, mp.get_id());
+    coordinator.set_can_push(mp.get_id().clone(), can_push);
+    mp.broadcast(Msg::CanPush(signer.sign_notice((mp.get_id().clone(), can_push))));
 
     let static_ui_handler = static_ui_handler(port_number, p2p3_url.clone());
     println!("Called Static UI Handler");
@@ -117,63 +896,238 @@ fn main() {
         ui.send_command(comm);
     });
 
-    let static_site = StaticSite::new(mp.clone(), Arc::new(ui_send));
-    let site_inner = static_site.inner.clone();
-    {
-        let initial_file_content = read_file(file_name);
-        let site_clone = static_site.inner.clone();
-        let mut site = site_clone.lock().unwrap();
-        site.parse_given_string(&initial_file_content);
+    let documents = Arc::new(Mutex::new(Documents::new(mp.clone(), local_path.clone(), Arc::new(ui_send))));
+    documents.lock().unwrap().set_signer(signer.clone());
+    if autosave_secs > 0 {
+        let autosave_ui = static_ui_handler.inner.clone();
+        let autosave_docs = documents.clone();
+        let open_docs = move || {
+            autosave_docs.lock().unwrap().all().into_iter()
+                .map(|(_, doc)| (doc.site, doc.workspace)).collect()
+        };
+        spawn_autosave(open_docs, Duration::from_secs(autosave_secs), move |path| {
+            println!("{} was changed outside of the session, not autosaving", path.display());
+            let ui = autosave_ui.lock().unwrap();
+            ui.send_command(Command::ExternalChange(format!("{}", path.display())));
+        });
     }
+    let ui_docs = documents.clone();
     let mp = mp.clone();
     let another_mp = mp.clone();
+    let mp_for_joins = mp.clone();
     let static_ui = static_ui_handler.inner.clone();
     let another_static_ui = static_ui_handler.inner.clone();
+    let another_coordinator = coordinator.clone();
+    let leave_coordinator = coordinator.clone();
+    let ui_authors = authors.clone();
+    let ui_signer = signer.clone();
+    let current_run: Arc<Mutex<Option<CurrentRun>>> = Arc::new(Mutex::new(None));
+    let net_current_run = current_run.clone();
+    let shown_run: Arc<ShownRun> = Arc::new(Mutex::new(None));
+    let net_shown_run = shown_run.clone();
+    let ui_toolchains = toolchains.clone();
+    let ui_pending_runs = pending_runs.clone();
     let ui_cmd: FnCommand = Box::new(move|comm| {
+        let active = ui_docs.lock().unwrap().active_document();
         match comm.clone() {
             Command::Compile => {
-                let globals = p2p3_globals().inner.clone();
-                let values = globals.lock().unwrap();
-                let mut site = site_inner.lock().unwrap();
-                let ui = static_ui.lock().unwrap();
-                match run_code(values.get_compile_mode(), &site.content()) {
-                    Ok(o) => ui.send_command(Command::Output(o)),
-                    Err(e) => println!("error {}", e),
+                let path = ui_docs.lock().unwrap().active();
+                if !may(&ui_authors, mp.get_id(), &path, Action::Run) {
+                    return Ok("".to_string());
+                }
+                if let Some(doc) = active {
+                    let snapshot = Snapshot::take(&doc.site);
+                    let (mode, languages) = {
+                        let globals = p2p3_globals().inner.clone();
+                        let values = globals.lock().unwrap();
+                        (values.get_compile_mode(), values.get_languages())
+                    };
+                    let language = languages.get(&mode).cloned();
+                    // Without the toolchain a peer who has it is asked to run the code.
+                    if !language.as_ref().map_or(false, |l| l.installed()) {
+                        let capable = ui_toolchains.lock().unwrap().capable(&mode, &mp.peers());
+                        if let Some(runner) = capable.into_iter().next() {
+                            let id: u64 = random();
+                            let mut run = current_run.lock().unwrap();
+                            if let Some(ref previous) = *run {
+                                stop_run(&mp, previous);
+                            }
+                            mp.send(&runner, Msg::RunRequest(id, mode.clone(), path.clone(), snapshot.content.clone()));
+                            *run = Some(CurrentRun::Remote(RemoteRun { runner: runner, id: id, path: path, snapshot: snapshot }));
+                            static_ui.lock().unwrap().send_command(
+                                Command::Output(format!("No {} toolchain here, asked {:?} to run it", mode, runner)));
+                            return Ok("".to_string());
+                        }
+                    }
+                    let run_path = path.clone();
+                    start_run(&mp, &static_ui, &shown_run, &ui_authors, &current_run, &path, path.clone(), random(), mp.get_id().clone(), move |io| {
+                        let language = match language {
+                            Some(language) => language,
+                            None => return Err(format!("No language is set up to run {} code", mode)),
+                        };
+                        let mut report = try!(run_code(&language, &run_path, &snapshot.content, io));
+                        let mut snapshots = BTreeMap::new();
+                        snapshots.insert(run_path, snapshot);
+                        follow_edits(&mut report, &snapshots);
+                        Ok(report)
+                    });
+                }
+            },
+            Command::Build(name) => {
+                // A build runs whatever the repo holds, so it needs Run on all of it.
+                if !may(&ui_authors, mp.get_id(), "", Action::Run) {
+                    return Ok("".to_string());
+                }
+                let root = {
+                    let globals = p2p3_globals().inner.clone();
+                    let values = globals.lock().unwrap();
+                    PathBuf::from(values.get_storage().root())
+                };
+                let target = match targets(&root) {
+                    Ok(targets) => targets.into_iter().find(|t| t.name == name),
+                    Err(e) => {
+                        println!("Could not read the builds: {}", e);
+                        None
+                    },
                 };
+                if let Some(target) = target {
+                    let snapshots = flush_documents(&ui_docs, &static_ui);
+                    start_run(&mp, &static_ui, &shown_run, &ui_authors, &current_run, "", target.to_string(), random(), mp.get_id().clone(), move |io| {
+                        let mut report = try!(build(&root, &target, io));
+                        follow_edits(&mut report, &snapshots);
+                        Ok(report)
+                    });
+                }
+            },
+            Command::CancelRun => {
+                if let Some(run) = current_run.lock().unwrap().take() {
+                    stop_run(&mp, &run);
+                }
+            },
+            Command::Stdin(text) => {
+                match *current_run.lock().unwrap() {
+                    Some(CurrentRun::Local(ref run)) => {
+                        if run.stdin.send(Some(text.clone())).is_ok() {
+                            publish_run(&mp, &static_ui, &shown_run, &run.watchers, run.id, RunEvent::Input(text));
+                        }
+                    },
+                    // The runner shows the input once the program has it.
+                    Some(CurrentRun::Remote(ref run)) => mp.send(&run.runner, Msg::RunInput(run.id, Some(text))),
+                    None => {},
+                }
+            },
+            Command::CloseStdin => {
+                match *current_run.lock().unwrap() {
+                    Some(CurrentRun::Local(ref run)) => {
+                        let _ = run.stdin.send(None);
+                    },
+                    Some(CurrentRun::Remote(ref run)) => mp.send(&run.runner, Msg::RunInput(run.id, None)),
+                    None => {},
+                }
+            },
+            Command::AcceptRun(requester, id) => {
+                let request = ui_pending_runs.lock().unwrap().take(&requester, id);
+                if let Some(request) = request {
+                    let language = {
+                        let globals = p2p3_globals().inner.clone();
+                        let values = globals.lock().unwrap();
+                        values.get_languages().get(&request.mode).cloned()
+                    };
+                    match language {
+                        Some(language) => {
+                            let path = request.path.clone();
+                            start_run(&mp, &static_ui, &shown_run, &ui_authors, &current_run, &path, path.clone(), id, request.requester.clone(),
+                                      move |io| run_code(&language, &request.path, &request.content, io));
+                        },
+                        None => mp.send(&request.requester, Msg::RunDeclined(id)),
+                    }
+                }
+            },
+            Command::DeclineRun(requester, id) => {
+                if let Some(request) = ui_pending_runs.lock().unwrap().take(&requester, id) {
+                    mp.send(&request.requester, Msg::RunDeclined(id));
+                }
             },
             Command::InsertChar(position, character) => {
                 println!("Received {} {}", position, character);
-                let mut site = site_inner.lock().unwrap();
-                site.generate_insert(position, character, true);
-                // println!("Site content {}", site.content());
+                let path = ui_docs.lock().unwrap().active();
+                if !may(&ui_authors, mp.get_id(), &path, Action::Edit) {
+                    return Ok("".to_string());
+                }
+                if let Some(doc) = active {
+                    let mut site = doc.site.lock().unwrap();
+                    site.generate_insert(position, character, true);
+                }
             },
             Command::DeleteChar(position) => {
                 println!("Received {}", position);
-                let mut site = site_inner.lock().unwrap();
-                site.generate_del(position);
-                println!("Site content {}", site.content());
+                let path = ui_docs.lock().unwrap().active();
+                if !may(&ui_authors, mp.get_id(), &path, Action::Edit) {
+                    return Ok("".to_string());
+                }
+                if let Some(doc) = active {
+                    let mut site = doc.site.lock().unwrap();
+                    site.generate_del(position);
+                    println!("Site content {}", site.content());
+                }
             },
-            Command::Commit => {
-                let globals = p2p3_globals().inner.clone();
-                let values = globals.lock().unwrap();
-                let ga = values.get_git_access();
-                ga.commit_path("Commit message").unwrap();
-                ga.push().unwrap();
+            Command::Commit(message) => {
+                match coordinator.committer(mp.get_id(), &mp.peers()) {
+                    Some(ref committer) if committer == mp.get_id() =>
+                        commit_session(&coordinator, &mp, &ui_docs, &static_ui, &ui_authors, &ui_signer, mp.get_id(), &message,
+                                       open_contents(&ui_docs)),
+                    Some(committer) => {
+                        println!("Asking {:?} to commit", committer);
+                        mp.send(&committer, Msg::CommitRequest(message, open_contents(&ui_docs)));
+                    },
+                    None => no_committer(&static_ui),
+                }
+            },
+            Command::Pull => {
+                match coordinator.committer(mp.get_id(), &mp.peers()) {
+                    Some(ref committer) if committer == mp.get_id() =>
+                        pull_session(&coordinator, &mp, &ui_docs, &static_ui, &ui_authors, &ui_signer),
+                    Some(committer) => {
+                        println!("Asking {:?} to pull", committer);
+                        mp.send(&committer, Msg::PullRequest);
+                    },
+                    None => no_committer(&static_ui),
+                }
+            },
+            Command::ListFiles => {
+                send_file_tree(&static_ui, &ui_authors);
+                send_build_targets(&static_ui);
+                let languages = {
+                    let globals = p2p3_globals().inner.clone();
+                    let values = globals.lock().unwrap();
+                    values.get_languages()
+                };
+                static_ui.lock().unwrap().send_command(Command::Languages(languages.all()));
+            },
+            Command::OpenFile(path) => {
+                open_file(&mp, &ui_docs, &static_ui, &ui_authors, &path);
             },
             Command::InsertString(_,_ /*position, content*/) => {
 
             },
             Command::Output(_ /*results*/ ) => {
 
+            },
+            Command::RunUpdate(_, _, _) => {
+
             },
             Command::DisableEditing(_) => {
 
+            },
+            Command::Languages(_) | Command::BuildTargets(_) | Command::Diagnostics(_) | Command::ActiveFile(_)
+            | Command::RunRequested(_, _, _, _) => {
+
             },
             Command::Mode(mode) => {
                 println!("Mode selected: {}", mode);
                 let globals = p2p3_globals().inner.clone();
                 let mut values = globals.lock().unwrap();
-                values.set_compile_mode(mode.parse::<CompileMode>().unwrap());
+                values.set_compile_mode(mode);
             },
             Command::UpdateCursor(row, col) => {
                 // broadcast to people with your own peerId
@@ -181,32 +1135,108 @@ fn main() {
             },
             Command::UpdatePeerCursor(_, _, _) => {
 
+            },
+            Command::Committed(_) => {
+
+            },
+            Command::Pulled(_) => {
+
+            },
+            Command::ExternalChange(_) => {
+
+            },
+            Command::FileTree(_) => {
+
+            },
+            Command::PeerFile(_, _) => {
+
+            },
+            Command::History => {
+                let path = ui_docs.lock().unwrap().active();
+                send_history(&static_ui, &path);
+            },
+            Command::Diff(rev) => {
+                if let Some(doc) = active {
+                    let path = ui_docs.lock().unwrap().active();
+                    send_diff(&static_ui, &path, &doc, &rev);
+                }
+            },
+            Command::Revert(rev, lines) => {
+                if let Some(doc) = active {
+                    let path = ui_docs.lock().unwrap().active();
+                    if !may(&ui_authors, mp.get_id(), &path, Action::Edit) {
+                        return Ok("".to_string());
+                    }
+                    revert_file(&path, &doc, &rev, lines);
+                }
+            },
+            Command::HistoryEntries(_) => {
+
+            },
+            Command::DiffOutput(_) => {
+
+            },
+            Command::Role(_) => {
+
+            },
+            Command::RequestEdit => {
+                println!("Asking the editors for edit rights");
+                mp.broadcast(Msg::EditRequest);
+            },
+            Command::GrantEdit(peer, minutes) => {
+                if can_grant(&ui_authors, mp.get_id()) {
+                    let grant = Grant {
+                        holder: peer,
+                        granted_by: mp.get_id().clone(),
+                        expires: minutes.map(|m| time::get_time().sec + m * 60),
+                    };
+                    update_grants(|g| g.grant(grant.clone()));
+                    mp.broadcast(Msg::EditGranted(ui_signer.sign_notice(grant)));
+                    send_edit_rights(&static_ui);
+                }
+            },
+            Command::RevokeEdit(peer) => {
+                if can_grant(&ui_authors, mp.get_id()) {
+                    update_grants(|g| g.revoke(&peer));
+                    let revocation = Revocation { holder: peer, revoked_by: mp.get_id().clone() };
+                    mp.broadcast(Msg::EditRevoked(ui_signer.sign_notice(revocation)));
+                    send_edit_rights(&static_ui);
+                }
+            },
+            Command::EnableEditing => {
+
+            },
+            Command::EditRequested(_) => {
+
+            },
+            Command::EditRights(_) => {
+
             },
         }
         Ok("".to_string())
     });
     {
         let ui_inner = static_ui_handler.inner.clone();
-        let ui = ui_inner.lock().unwrap();
-
-        ui.add_listener(ui_cmd);
-        let mut content = String::new();
         {
-            let site_clone = static_site.inner.clone();
-            let mut site = site_clone.lock().unwrap();
-            let mut borrowed_content = &mut content;
-            *borrowed_content = site.content();
+            let ui = ui_inner.lock().unwrap();
+            ui.add_listener(ui_cmd);
         }
-        ui.send_command(Command::InsertString(0, content));
-        match permission_level {
-            PermissionLevel::Editor => {},
-            PermissionLevel::Viewer => {
-                ui.send_command(Command::DisableEditing(String::new()))
-            },
-        };
+        open_file(&mp, &documents, &ui_inner, &authors, file_path);
+        send_file_tree(&ui_inner, &authors);
     }
     println!("Connection with front-end initialized.");
-    let site_inner = static_site.inner.clone();
+    let net_docs = documents.clone();
+    let join_invites = invites.clone();
+    let join_admissions = admissions.clone();
+    let join_mp = mp_for_joins;
+    let commit_ui = static_ui_handler.inner.clone();
+    let net_authors = authors.clone();
+    let net_keys = signing_keys.clone();
+    let my_key = signer.public_key();
+    let net_signer = signer.clone();
+    let net_toolchains = toolchains.clone();
+    let net_pending_runs = pending_runs.clone();
+    let net_boot = boot.clone();
     thread::spawn(move || {
         loop {
             let message = another_mp.recv();
@@ -215,19 +1245,379 @@ fn main() {
                     let ui = another_static_ui.lock().unwrap();
                     ui.send_command(Command::UpdatePeerCursor(peer_id, row, col));
                 },
-                Msg::WootOperation(operation) => {
+                Msg::WootOperation(path, signed) => {
                     println!("Received WootOperation");
-                    let mut site = site_inner.lock().unwrap();
-                    // site.pool.push_back(operation);
-                    // site.implement_pool();
-                    site.implement_operation(operation);
+                    let peer = message.source();
+                    let role = role_of(&net_authors, &peer, &path);
+                    if let Err(e) = verify(&net_keys.lock().unwrap(), &peer, &path, &signed, role) {
+                        println!("Rejected an edit of {} from {:?}: {}", path, peer, e);
+                        continue;
+                    }
+                    let doc = {
+                        let mut docs = net_docs.lock().unwrap();
+                        let doc = docs.get(&path);
+                        if doc.is_none() {
+                            // Applied once the document we asked for arrives.
+                            docs.hold(&path, signed.operation.clone());
+                        }
+                        doc
+                    };
+                    if let Some(doc) = doc {
+                        let mut site = doc.site.lock().unwrap();
+                        // site.pool.push_back(operation);
+                        // site.implement_pool();
+                        site.implement_operation(signed.operation);
+                    }
+                },
+                Msg::RunUpdate(runner, id, mut event) => {
+                    if runner != message.source() {
+                        continue;
+                    }
+                    let done = match event {
+                        RunEvent::Finished(_) | RunEvent::Failed(_) => true,
+                        _ => false,
+                    };
+                    // The end of a run we asked for, its diagnostics follow our edits.
+                    {
+                        let mut current = net_current_run.lock().unwrap();
+                        let ours = match *current {
+                            Some(CurrentRun::Remote(ref run)) => done && run.runner == runner && run.id == id,
+                            _ => false,
+                        };
+                        if ours {
+                            if let (Some(CurrentRun::Remote(run)), &mut RunEvent::Finished(ref mut report)) = (current.take(), &mut event) {
+                                let mut snapshots = BTreeMap::new();
+                                snapshots.insert(run.path, run.snapshot);
+                                follow_edits(report, &snapshots);
+                            }
+                        }
+                    }
+                    show_run_update(&another_static_ui, &net_shown_run, runner, id, event);
+                },
+                Msg::Toolchains(modes) => {
+                    net_toolchains.lock().unwrap().set(message.source(), modes);
+                },
+                Msg::RunRequest(id, mode, path, content) => {
+                    let peer = message.source();
+                    let installed = {
+                        let globals = p2p3_globals().inner.clone();
+                        let values = globals.lock().unwrap();
+                        values.get_languages().get(&mode).map_or(false, |l| l.installed())
+                    };
+                    let acceptable = is_repo_path(&path) && content.len() <= MAX_RUN_SOURCE && installed &&
+                        may(&net_authors, &peer, &path, Action::Run);
+                    // Nothing runs on this machine until our user agrees, a
+                    // peer gets no second prompt while one is open.
+                    let added = acceptable && net_pending_runs.lock().unwrap().add(id, RunRequest {
+                        requester: peer.clone(), mode: mode.clone(), path: path.clone(), content: content,
+                    });
+                    if !added {
+                        join_mp.send(&peer, Msg::RunDeclined(id));
+                        continue;
+                    }
+                    let ui = another_static_ui.lock().unwrap();
+                    ui.send_command(Command::RunRequested(peer, id, mode, path));
+                },
+                Msg::RunDeclined(id) => {
+                    let mut run = net_current_run.lock().unwrap();
+                    let ours = match *run {
+                        Some(CurrentRun::Remote(ref remote)) => remote.runner == message.source() && remote.id == id,
+                        _ => false,
+                    };
+                    if ours {
+                        *run = None;
+                        let ui = another_static_ui.lock().unwrap();
+                        ui.send_command(Command::Output(format!("{:?} declined to run the code", message.source())));
+                    }
+                },
+                Msg::RunInput(id, text) => {
+                    if let Some(CurrentRun::Local(ref run)) = *net_current_run.lock().unwrap() {
+                        if run.id != id || run.owner != message.source() {
+                            continue;
+                        }
+                        if run.stdin.send(text.clone()).is_ok() {
+                            if let Some(text) = text {
+                                publish_run(&join_mp, &another_static_ui, &net_shown_run, &run.watchers, id, RunEvent::Input(text));
+                            }
+                        }
+                    }
+                },
+                Msg::RunCancel(id) => {
+                    let mut run = net_current_run.lock().unwrap();
+                    let owned = match *run {
+                        Some(CurrentRun::Local(ref local)) => local.id == id && local.owner == message.source(),
+                        _ => false,
+                    };
+                    if owned {
+                        if let Some(local) = run.take() {
+                            stop_run(&join_mp, &local);
+                        }
+                    }
+                },
+                Msg::OpenedFile(peer_id, path) => {
+                    net_docs.lock().unwrap().set_peer_file(peer_id.clone(), path.clone());
+                    let ui = another_static_ui.lock().unwrap();
+                    ui.send_command(Command::PeerFile(peer_id, path));
+                },
+                Msg::DocumentRequest(path) => {
+                    if !may(&net_authors, &message.source(), &path, Action::Open) {
+                        continue;
+                    }
+                    let doc = net_docs.lock().unwrap().get(&path);
+                    if let Some(doc) = doc {
+                        let chars = doc.site.lock().unwrap().chars();
+                        join_mp.send(&message.source(), Msg::DocumentState(path, chars));
+                    }
+                },
+                Msg::DocumentState(path, chars) => {
+                    let peer = message.source();
+                    if !may(&net_authors, &peer, &path, Action::Edit) {
+                        continue;
+                    }
+                    let mut docs = net_docs.lock().unwrap();
+                    match docs.open_from_peer(&path, &peer, chars) {
+                        Ok(doc) => {
+                            if docs.active() == path {
+                                let content = doc.site.lock().unwrap().content();
+                                let ui = another_static_ui.lock().unwrap();
+                                ui.send_command(Command::InsertString(0, content));
+                            }
+                        },
+                        Err(e) => println!("Not opening {} as sent by {:?}: {}", path, peer, e),
+                    }
+                },
+                Msg::CommitRequest(commit_message, theirs) => {
+                    if !another_coordinator.is_committer(join_mp.get_id(), &join_mp.peers()) {
+                        println!("Not committing for {:?}, we are not the committer", message.source());
+                        continue;
+                    }
+                    println!("{:?} asked us to commit", message.source());
+                    commit_session(&another_coordinator, &join_mp, &net_docs, &commit_ui, &net_authors, &net_signer,
+                                   &message.source(), &commit_message, theirs);
+                },
+                Msg::CommitLeftOut(paths) => {
+                    println!("{:?} left {:?} out of our commit", message.source(), paths);
+                    show_left_out(&another_static_ui, &paths);
+                },
+                Msg::Committed(signed) => {
+                    let committer = signed.notice.0.clone();
+                    if let Err(e) = verify_notice(&net_keys.lock().unwrap(), &committer, &signed) {
+                        println!("Ignoring a commit said to come from {:?}: {}", committer, e);
+                        continue;
+                    }
+                    if another_coordinator.committer(join_mp.get_id(), &join_mp.peers()) != Some(committer.clone()) {
+                        println!("Ignoring a commit announced by {:?}, who is not the committer", committer);
+                        continue;
+                    }
+                    let sha = signed.notice.1;
+                    println!("{:?} committed at {}", committer, sha);
+                    net_docs.lock().unwrap().clear_contributors();
+                    let ui = another_static_ui.lock().unwrap();
+                    ui.send_command(Command::Committed(sha));
+                },
+                Msg::Identity(author, key) => {
+                    let peer = message.source();
+                    let (is_new, kept) = {
+                        let mut keys = net_keys.lock().unwrap();
+                        let is_new = keys.insert(peer.clone(), key);
+                        (is_new, keys.get(&peer).cloned())
+                    };
+                    if let Some(author) = author {
+                        // The ACL vouches for the email with the key the peer signs with.
+                        let acl = {
+                            let globals = p2p3_globals().inner.clone();
+                            let values = globals.lock().unwrap();
+                            values.get_acl()
+                        };
+                        let verified = kept.map_or(false, |kept| acl.lists_key(&author.email, &key_hex(&kept)));
+                        println!("{:?} is {} <{}>{}", peer, author.name, author.email,
+                                 if verified { "" } else { ", not proved by the ACL" });
+                        let mut authors = net_authors.lock().unwrap();
+                        authors.insert(peer.clone(), author);
+                        authors.set_verified(peer.clone(), verified);
+                    }
+                    if is_new {
+                        join_mp.send(&peer, Msg::Identity(identity.clone(), my_key));
+                        join_mp.send(&peer, Msg::Toolchains(my_toolchains.clone()));
+                        join_mp.send(&peer, Msg::CanPush(net_signer.sign_notice((join_mp.get_id().clone(), can_push))));
+                        if my_contact.is_some() {
+                            join_mp.send(&peer, Msg::Contact(my_contact));
+                        }
+                        // Newcomers ask us for the documents we have open instead of
+                        // reading them from disk, the shown one is told last.
+                        let (mut open, active) = {
+                            let docs = net_docs.lock().unwrap();
+                            (docs.all().into_iter().map(|(path, _)| path).collect::<Vec<_>>(), docs.active())
+                        };
+                        open.sort_by_key(|path| *path == active);
+                        for path in open {
+                            join_mp.send(&peer, Msg::OpenedFile(join_mp.get_id().clone(), path));
+                        }
+                        // Newcomers learn about the rights we handed out.
+                        let ours = {
+                            let globals = p2p3_globals().inner.clone();
+                            let values = globals.lock().unwrap();
+                            values.get_grants().granted_by(join_mp.get_id())
+                        };
+                        for grant in ours {
+                            join_mp.send(&peer, Msg::EditGranted(net_signer.sign_notice(grant)));
+                        }
+                    }
+                },
+                Msg::CanPush(signed) => {
+                    let peer = signed.notice.0.clone();
+                    if let Err(e) = verify_notice(&net_keys.lock().unwrap(), &peer, &signed) {
+                        println!("Ignoring push rights said to come from {:?}: {}", peer, e);
+                        continue;
+                    }
+                    let can_push = signed.notice.1 && can_grant(&net_authors, &peer);
+                    another_coordinator.set_can_push(peer, can_push);
+                },
+                Msg::Contact(addr) => {
+                    net_boot.lock().unwrap().contact_seen(message.source(), addr);
+                },
+                Msg::PullRequest => {
+                    if !another_coordinator.is_committer(join_mp.get_id(), &join_mp.peers()) {
+                        println!("Not pulling for {:?}, we are not the committer", message.source());
+                        continue;
+                    }
+                    println!("{:?} asked us to pull", message.source());
+                    pull_session(&another_coordinator, &join_mp, &net_docs, &commit_ui, &net_authors, &net_signer);
+                },
+                Msg::Pulled(signed) => {
+                    let committer = signed.notice.0.clone();
+                    if let Err(e) = verify_notice(&net_keys.lock().unwrap(), &committer, &signed) {
+                        println!("Ignoring a pull said to come from {:?}: {}", committer, e);
+                        continue;
+                    }
+                    if another_coordinator.committer(join_mp.get_id(), &join_mp.peers()) != Some(committer.clone()) {
+                        println!("Ignoring a pull announced by {:?}, who is not the committer", committer);
+                        continue;
+                    }
+                    let conflicts = signed.notice.1;
+                    println!("{:?} pulled", committer);
+                    let ui = another_static_ui.lock().unwrap();
+                    ui.send_command(Command::Pulled(conflicts));
+                },
+                Msg::JoinRequest(session_id, secret) => {
+                    let our_session = {
+                        let globals = p2p3_globals().inner.clone();
+                        let values = globals.lock().unwrap();
+                        values.get_session_id()
+                    };
+                    let admitted = session_id == our_session &&
+                        join_invites.lock().unwrap().admits(session_id, &secret, time::get_time().sec);
+                    if admitted {
+                        println!("{:?} joined through an invite", message.source());
+                        join_admissions.lock().unwrap().admit(&message.source());
+                    } else {
+                        println!("Rejecting join request from {:?}", message.source());
+                        join_mp.disconnect(&message.source());
+                    }
+                },
+                Msg::EditRequest => {
+                    if can_grant(&net_authors, join_mp.get_id()) {
+                        let ui = another_static_ui.lock().unwrap();
+                        ui.send_command(Command::EditRequested(message.source()));
+                    }
+                },
+                Msg::EditGranted(signed) => {
+                    // Anyone may pass the grant on, the granter's signature
+                    // is what counts.
+                    let peer = signed.notice.granted_by.clone();
+                    if let Err(e) = verify_notice(&net_keys.lock().unwrap(), &peer, &signed) {
+                        println!("Ignoring edit rights said to come from {:?}: {}", peer, e);
+                        continue;
+                    }
+                    if !can_grant(&net_authors, &peer) {
+                        println!("Ignoring edit rights handed out by {:?}", peer);
+                        continue;
+                    }
+                    let grant = signed.notice;
+                    println!("{:?} granted edit rights to {:?}", peer, grant.holder);
+                    let mine = grant.holder == *join_mp.get_id();
+                    update_grants(|g| g.grant(grant));
+                    send_edit_rights(&another_static_ui);
+                    if mine {
+                        refresh_editing(&join_mp, &net_docs, &another_static_ui, &net_authors);
+                    }
+                },
+                Msg::EditRevoked(signed) => {
+                    let peer = signed.notice.revoked_by.clone();
+                    if let Err(e) = verify_notice(&net_keys.lock().unwrap(), &peer, &signed) {
+                        println!("Ignoring a revoke said to come from {:?}: {}", peer, e);
+                        continue;
+                    }
+                    if !can_grant(&net_authors, &peer) {
+                        println!("Ignoring a revoke from {:?}", peer);
+                        continue;
+                    }
+                    let holder = signed.notice.holder;
+                    println!("{:?} revoked the edit rights of {:?}", peer, holder);
+                    update_grants(|g| g.revoke(&holder));
+                    send_edit_rights(&another_static_ui);
+                    if holder == *join_mp.get_id() {
+                        refresh_editing(&join_mp, &net_docs, &another_static_ui, &net_authors);
+                    }
                 },
                 _ => {}
             }
         }
     });
-    let mut x = String::new();
-    stdin().read_line(&mut x).unwrap();
+    // Granted rights run out on every peer at the time the editor picked.
+    {
+        let mp = leave_mp.clone();
+        let docs = documents.clone();
+        let ui = static_ui_handler.inner.clone();
+        let authors = authors.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(GRANT_CHECK_SECS));
+                let expired = update_grants(|g| g.expire(time::get_time().sec));
+                if expired.is_empty() {
+                    continue;
+                }
+                send_edit_rights(&ui);
+                if expired.contains(mp.get_id()) {
+                    println!("Our edit rights expired");
+                    refresh_editing(&mp, &docs, &ui, &authors);
+                }
+            }
+        });
+    }
+    // Peers that connected to an inviting node and never joined are cut off.
+    {
+        let mp = leave_mp.clone();
+        let admissions = admissions.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(JOIN_CHECK_SECS));
+                let overdue = admissions.lock().unwrap().overdue(&mp.neighbours(), time::get_time().sec);
+                for peer in overdue {
+                    println!("{:?} did not join in time, disconnecting", peer);
+                    mp.disconnect(&peer);
+                }
+            }
+        });
+    }
+    // Type "invite" to print another invite, anything else quits.
+    loop {
+        let mut x = String::new();
+        stdin().read_line(&mut x).unwrap();
+        if x.trim() == "invite" {
+            print_invite(&leave_mp, &invites, &admissions, invite_expires, invite_secret.clone());
+        } else {
+            break;
+        }
+    }
+    flush_documents(&documents, &static_ui_handler.inner);
+    if !lan_only {
+        let mut boot = boot.lock().unwrap();
+        leave_mp.broadcast(Msg::Contact(None));
+        if let Err(e) = boot.leave(&leave_mp, &leave_coordinator) {
+            println!("Could not remove our contact: {}", e);
+        }
+    }
 }
 
 fn read_file(url: &str) -> String {
@@ -1,14 +1,65 @@
 extern crate crust;
-use woot::operation::Operation;
+use woot::woot_char::WootChar;
 use network::Message;
 use crust::PeerId;
+use storage::coauthors::Author;
+use permission::signing::{PublicKey, Signed, SignedOperation};
+use permission::grants::{Grant, Revocation};
+use compile::RunEvent;
+use socket_addr::SocketAddr;
 
 #[derive(RustcEncodable,RustcDecodable, Clone, Debug)]
 pub enum Msg{
     String(String),
     // row, col
     Cursor(PeerId, u32, u32),
-    WootOperation(Operation)
+    // document path, operation on it signed by the sender
+    WootOperation(String, SignedOperation),
+    // session id, join secret from the invite token
+    JoinRequest(u64, Option<String>),
+    // commit message, path and content of every document the sender has
+    // open, sent to the elected committer
+    CommitRequest(String, Vec<(String, String)>),
+    // files of the commit the receiver asked for that were left out
+    CommitLeftOut(Vec<String>),
+    // committer, sha of the pushed commit, signed by the committer
+    Committed(Signed<(PeerId, String)>),
+    // sent to the elected committer
+    PullRequest,
+    // committer, files left with conflicts, signed by the committer
+    Pulled(Signed<(PeerId, Vec<String>)>),
+    // the signer, whether it can push to the remote and commit by the ACL,
+    // only peers that can are elected committer
+    CanPush(Signed<(PeerId, bool)>),
+    // git identity of the sender if it has one and the key it signs
+    // operations with, answered by our own the first time
+    Identity(Option<Author>, PublicKey),
+    // peer, document it now has open, empty for none
+    OpenedFile(PeerId, String),
+    DocumentRequest(String),
+    // document path, full sequence including hidden characters
+    DocumentState(String, Vec<WootChar>),
+    // the sender asks the editors for edit rights
+    EditRequest,
+    // broadcast by the editor who granted them, signed by it
+    EditGranted(Signed<Grant>),
+    // broadcast by the editor who takes the rights back, signed by it
+    EditRevoked(Signed<Revocation>),
+    // runner, run id, sent to the peers who may open the file being run
+    RunUpdate(PeerId, u64, RunEvent),
+    // editor modes the sender can run code in
+    Toolchains(Vec<String>),
+    // run id, mode, path, buffer content: asks the receiver to run it for us
+    RunRequest(u64, String, String, String),
+    // run id the receiver asked for and will not get
+    RunDeclined(u64),
+    // run id, text typed for a run we asked for, None ends its input
+    RunInput(u64, Option<String>),
+    // run id of a run we asked for
+    RunCancel(u64),
+    // acceptor the sender can be reached at, None when it leaves, for the
+    // committer to keep config.p2p3 up to date
+    Contact(Option<SocketAddr>),
 }
 
 impl Message for Msg{}
@@ -1,15 +1,25 @@
 use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::io::prelude::*;
+use std::io;
+use std::fmt;
+use std::net;
 use crust::{TheirConnectionInfo,StaticContactInfo};
 use socket_addr::SocketAddr;
 use rustc_serialize::json;
 use rustc_serialize::json::Json;
 use rustc_serialize::json::as_pretty_json;
-use super::{MessagePasser,Message};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use super::{MessagePasser, MessagePasserT, Message};
+use super::contacts::{ContactBook, ContactEntry, CONTACT_MAX_AGE_SECS};
+use storage::coordinator::CommitCoordinator;
 use utils::p2p3_globals;
+use crust::PeerId;
 use crust;
 use config_file_handler;
+use time;
 
 #[derive(PartialEq, Eq, Debug, RustcDecodable, RustcEncodable, Clone)]
 pub struct Config {
@@ -22,6 +32,8 @@ pub struct Config {
     pub tcp_mapper_servers: Vec<SocketAddr>,
     pub service_discovery_port: Option<u16>,
     pub bootstrap_cache_name: Option<String>,
+    // p2p3 only, crust ignores it. Owner and last seen time of every acceptor.
+    pub contacts: Option<Vec<ContactEntry>>,
 }
 
 impl Default for Config {
@@ -36,19 +48,100 @@ impl Default for Config {
             tcp_mapper_servers: vec![],
             service_discovery_port: None,
             bootstrap_cache_name: None,
+            contacts: None,
         }
     }
 }
 
+pub const P2P3_CONFIG_FILE: &'static str = "config.p2p3";
+// How often the committer looks for contact changes to commit.
+const CONTACT_CHECK_SECS: u64 = 30;
+
+/// Where the initial set of contacts for this session comes from.
+#[derive(Clone, Debug)]
+pub enum BootstrapSource {
+    /// Read `config.p2p3` from the cloned repo. A missing file starts a new session.
+    RepoFile,
+    /// Contacts given on the command line, the repo file is still updated with our address.
+    Contacts(Vec<SocketAddr>),
+    /// No hard coded contacts at all, rely on crust's service discovery on the LAN.
+    LanOnly,
+    /// Ignore whatever is in the repo and write a fresh `config.p2p3`.
+    NewSession,
+}
+
+#[derive(Debug)]
+pub enum BootstrapError {
+    Io(io::Error),
+    Malformed(String),
+    BadContact(String),
+    NoAcceptor,
+}
+
+impl fmt::Display for BootstrapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BootstrapError::Io(ref e) => write!(f, "could not access {}: {}", P2P3_CONFIG_FILE, e),
+            BootstrapError::Malformed(ref e) => write!(f, "{} is malformed: {}", P2P3_CONFIG_FILE, e),
+            BootstrapError::BadContact(ref c) => write!(f, "invalid contact address \"{}\", expected ip:port", c),
+            BootstrapError::NoAcceptor => write!(f, "this node has no TCP acceptor to advertise"),
+        }
+    }
+}
+
+impl From<io::Error> for BootstrapError {
+    fn from(e: io::Error) -> BootstrapError {
+        BootstrapError::Io(e)
+    }
+}
+
+/// Parses `ip:port` strings as given on the command line.
+pub fn parse_contacts(contacts: &[String]) -> Result<Vec<SocketAddr>, BootstrapError> {
+    let mut addrs = Vec::new();
+    for c in contacts {
+        match c.parse::<net::SocketAddr>() {
+            Ok(addr) => addrs.push(SocketAddr(addr)),
+            Err(_) => return Err(BootstrapError::BadContact(c.clone())),
+        }
+    }
+    Ok(addrs)
+}
+
+pub fn parse_config(file_str: &str) -> Result<Config, BootstrapError> {
+    match json::decode(file_str) {
+        Ok(conf) => Ok(conf),
+        Err(e) => Err(BootstrapError::Malformed(format!("{}", e))),
+    }
+}
+
+/// Reads and parses the config file, `None` if there is no such file.
+fn read_config_file(path: &str) -> Result<Option<Config>, BootstrapError> {
+    let mut p2p3_file = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(BootstrapError::Io(e)),
+    };
+    let mut file_str = String::new();
+    try!(p2p3_file.read_to_string(&mut file_str));
+    Ok(Some(try!(parse_config(&file_str))))
+}
+
 #[derive(Clone)]
 pub struct BootstrapHandler {
     pub config: Config,
     pub config_file: String,
-    pub full_path: String
+    pub full_path: String,
+    pub contacts: ContactBook,
+    // Our acceptor, once `update_config` found it.
+    pub own_contact: Option<SocketAddr>,
 }
 
 impl BootstrapHandler {
-    pub fn bootstrap_load() -> BootstrapHandler{
+    pub fn bootstrap_load() -> Result<BootstrapHandler, BootstrapError> {
+        BootstrapHandler::bootstrap_from(BootstrapSource::RepoFile)
+    }
+
+    pub fn bootstrap_from(source: BootstrapSource) -> Result<BootstrapHandler, BootstrapError> {
         // Load the p2p3 config file in the same directory of the working file.
         let git_local_url = {
             let globals = p2p3_globals().inner.clone();
@@ -56,20 +149,61 @@ impl BootstrapHandler {
             values.get_git_access().local_url.clone()
         };
 
-        let p2p3_file_name = String::from("config.p2p3");
+        let p2p3_file_name = String::from(P2P3_CONFIG_FILE);
         let p2p3_file_url = git_local_url.clone() + &p2p3_file_name;
         println!("git_local_url: {} p2p3_file_url: {}", git_local_url, p2p3_file_url.clone());
-        let mut p2p3_file = File::open(p2p3_file_url.clone()).unwrap();
-        let mut file_str = String::new();
-        p2p3_file.read_to_string(&mut file_str).unwrap();
 
-        // Construct the Config object.
-        let conf: Config = json::decode(&file_str).unwrap();
+        let conf = match source {
+            BootstrapSource::RepoFile => {
+                match try!(read_config_file(&p2p3_file_url)) {
+                    Some(conf) => conf,
+                    None => {
+                        println!("No {} in the repo, starting a new session", p2p3_file_name);
+                        Config::default()
+                    }
+                }
+            },
+            BootstrapSource::Contacts(addrs) => {
+                let mut conf = try!(read_config_file(&p2p3_file_url)).unwrap_or(Config::default());
+                if conf.hard_coded_contacts.is_empty() {
+                    conf.hard_coded_contacts.push(StaticContactInfo {
+                        tcp_acceptors: vec![],
+                        tcp_mapper_servers: vec![],
+                    });
+                }
+                // Contacts given by the user take precedence over the committed ones.
+                for (i, addr) in addrs.into_iter().enumerate() {
+                    conf.hard_coded_contacts[0].tcp_acceptors.insert(i, addr);
+                }
+                conf
+            },
+            BootstrapSource::LanOnly | BootstrapSource::NewSession => Config::default(),
+        };
 
-        BootstrapHandler {
+        let contacts = ContactBook::from_config(&conf, time::get_time().sec);
+        Ok(BootstrapHandler {
             config: conf,
             config_file: p2p3_file_name,
             full_path: p2p3_file_url,
+            contacts: contacts,
+            own_contact: None,
+        })
+    }
+
+    /// Writes the contacts to the crust config next to the executable, so that
+    /// `Service::new` bootstraps off them.
+    pub fn write_crust_config(&self) -> Result<(), BootstrapError> {
+        let name = match get_crust_config() {
+            Ok(name) => name,
+            Err(e) => return Err(BootstrapError::Malformed(format!("{}", e))),
+        };
+        let handler = match config_file_handler::FileHandler::new(&name) {
+            Ok(h) => h,
+            Err(e) => return Err(BootstrapError::Malformed(format!("{}", e))),
+        };
+        match handler.write_file(&self.config) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(BootstrapError::Malformed(format!("{}", e))),
         }
     }
 
@@ -85,49 +219,102 @@ impl BootstrapHandler {
         info
     }
 
-    pub fn update_config<T:Message>(&self, mp: MessagePasser<T>) {
+    /// Adds our own acceptor to the contact list. Only the session's committer
+    /// commits the file, the other peers send it their acceptor instead.
+    pub fn update_config<T:Message>(&mut self, mp: MessagePasser<T>) -> Result<(), BootstrapError> {
         let tok = mp.prepare_connection_info();
         let their_info = mp.wait_conn_info(tok);
         let mut info = BootstrapHandler::static_info_from_their(their_info);
+        // The first acceptor is the loopback one, which is useless to other peers.
+        if info.tcp_acceptors.len() < 2 {
+            return Err(BootstrapError::NoAcceptor);
+        }
         info.tcp_acceptors.remove(0);
 
-        /*
-         *  Because the crust can only connect to the first TCP acceptor in the config file,
-         *  we need to insert the new node's info in the first position.
-         */
-        let mut boot_clone = self.clone();
-        boot_clone.config.hard_coded_contacts[0].tcp_acceptors.insert(0, info.tcp_acceptors[0]);
-        let update_str = as_pretty_json(&boot_clone.config);
+        let now = time::get_time().sec;
+        self.contacts.touch(mp.get_id().clone(), info.tcp_acceptors[0], now);
+        self.contacts.prune(now, CONTACT_MAX_AGE_SECS);
+        self.own_contact = Some(info.tcp_acceptors[0]);
+        Ok(())
+    }
+
+    /// Records the acceptor `peer` sent us, None when it left the session.
+    pub fn contact_seen(&mut self, peer: PeerId, addr: Option<SocketAddr>) {
+        match addr {
+            Some(addr) => {
+                self.contacts.touch(peer, addr, time::get_time().sec);
+            },
+            None => self.contacts.remove_peer(&peer),
+        }
+    }
+
+    /// Removes our own entry, to be called when the node shuts down cleanly.
+    /// With other peers still around, their committer commits the removal.
+    pub fn leave<T:Message>(&mut self, mp: &MessagePasser<T>, coordinator: &CommitCoordinator) -> Result<(), BootstrapError> {
+        self.contacts.remove_peer(mp.get_id());
+        if mp.peers().is_empty() && coordinator.is_committer(mp.get_id(), &[]) && self.contacts.is_dirty() {
+            try!(self.save_config(coordinator, "Remove departed contact from config file."));
+        }
+        Ok(())
+    }
+
+    /// One batch of contact maintenance. Only the session's elected committer
+    /// commits, so that the others do not race it with the same change.
+    pub fn maintain<T:Message>(&mut self, mp: &MessagePasser<T>, coordinator: &CommitCoordinator) -> Result<(), BootstrapError> {
+        let now = time::get_time().sec;
+        let peers = mp.peers();
+        let mut connected = peers.clone();
+        connected.push(mp.get_id().clone());
+        self.contacts.refresh_peers(&connected, now);
+        self.contacts.prune(now, CONTACT_MAX_AGE_SECS);
+
+        let is_writer = coordinator.is_committer(mp.get_id(), &peers);
+        if is_writer && self.contacts.should_flush(now) {
+            try!(self.save_config(coordinator, "Refresh contacts in config file."));
+        }
+        Ok(())
+    }
+
+    // Writes the contact book to config.p2p3, then commits and pushes it.
+    fn save_config(&mut self, coordinator: &CommitCoordinator, commit_message: &str) -> Result<(), BootstrapError> {
+        self.contacts.apply(&mut self.config);
+        let update_str = as_pretty_json(&self.config);
         let pretty_json_str = update_str.to_string();
 
-        let git_access = {
+        let storage = {
             let globals = p2p3_globals().inner.clone();
             let values = globals.lock().unwrap();
-            values.get_git_access()
+            values.get_storage()
         };
 
         // Get the p2p3 config file path and store the new config infomation it in that path.
         let path_str = &self.full_path;
         let path = Path::new(&path_str);
-        let mut file = File::create(path.clone()).unwrap();
+        let mut file = try!(File::create(path.clone()));
         let file_byte = pretty_json_str.into_bytes();
-        file.write_all(&file_byte).unwrap();
+        try!(file.write_all(&file_byte));
+        self.contacts.mark_flushed(time::get_time().sec);
 
-        println!("commiting {} ", &self.config_file);
-        match git_access.commit_config("Update config file.", &self.config_file) {
-            Ok(()) => (),
-            Err(e) => {
-                println!("Commit error: {}", e);
-            }
+        println!("sharing {} ", &self.config_file);
+        if let Err(e) = coordinator.share_config(&*storage, &self.config_file, commit_message) {
+            println!("Could not share {}: {}", self.config_file, e);
         }
+        Ok(())
+    }
+}
 
-        match git_access.push() {
-            Ok(()) => (),
-            Err(e) => {
-                println!("Push error: {}", e);
+/// Runs `BootstrapHandler::maintain` in the background for the lifetime of the node.
+pub fn spawn_contact_maintenance<T:Message>(boot: Arc<Mutex<BootstrapHandler>>, mp: MessagePasser<T>,
+                                             coordinator: CommitCoordinator) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(CONTACT_CHECK_SECS));
+            let mut boot = boot.lock().unwrap();
+            if let Err(e) = boot.maintain(&mp, &coordinator) {
+                println!("Contact maintenance failed: {}", e);
             }
         }
-    }
+    });
 }
 
 /*
@@ -152,3 +339,33 @@ pub fn get_p2p3_config(file_name: &String) -> String {
     };
     result
 }
+
+#[cfg(test)]
+mod test{
+    use super::*;
+
+    #[test]
+    fn malformed_config_is_an_error(){
+        match parse_config("{ not json") {
+            Err(BootstrapError::Malformed(_)) => {},
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn default_config_round_trips(){
+        let json = ::rustc_serialize::json::encode(&Config::default()).unwrap();
+        assert_eq!(parse_config(&json).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn parse_cli_contacts(){
+        let good = vec!["127.0.0.1:5483".to_string()];
+        assert_eq!(parse_contacts(&good).unwrap().len(), 1);
+        let bad = vec!["localhost".to_string()];
+        match parse_contacts(&bad) {
+            Err(BootstrapError::BadContact(c)) => assert_eq!(c, "localhost"),
+            _ => assert!(false),
+        }
+    }
+}
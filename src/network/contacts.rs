@@ -0,0 +1,239 @@
+use crust::PeerId;
+use socket_addr::SocketAddr;
+use super::bootstrap::Config;
+use crust::StaticContactInfo;
+
+// Entries not seen by anyone for this long are dropped from config.p2p3.
+pub const CONTACT_MAX_AGE_SECS: i64 = 7 * 24 * 60 * 60;
+// A live entry's last_seen is only rewritten once it is this old.
+pub const CONTACT_REFRESH_SECS: i64 = 24 * 60 * 60;
+// Minimum time between two batched commits of config.p2p3.
+pub const CONTACT_FLUSH_SECS: i64 = 10 * 60;
+// Seen last, kept whatever their age so a session idle for a while can
+// still find its way back.
+pub const CONTACT_MIN_KEPT: usize = 3;
+
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug, PartialEq, Eq)]
+pub struct ContactEntry {
+    pub addr: SocketAddr,
+    // None for addresses inherited from an old config without owners.
+    pub peer: Option<PeerId>,
+    pub last_seen: i64,
+}
+
+/// The contacts committed in config.p2p3, with enough book keeping to keep the
+/// list short and to avoid a commit on every join.
+#[derive(Clone, Debug)]
+pub struct ContactBook {
+    entries: Vec<ContactEntry>,
+    dirty: bool,
+    // An address nobody can find us at until it is written, flushed right away.
+    unpublished: bool,
+    last_flush: i64,
+}
+
+impl ContactBook {
+    pub fn new() -> ContactBook {
+        ContactBook {
+            entries: vec![],
+            dirty: false,
+            unpublished: false,
+            last_flush: 0,
+        }
+    }
+
+    pub fn from_config(config: &Config, now: i64) -> ContactBook {
+        let mut book = ContactBook::new();
+        if let Some(ref entries) = config.contacts {
+            for e in entries {
+                book.insert(e.clone());
+            }
+        }
+        // Plain acceptors from older configs get one max age of grace.
+        for info in config.hard_coded_contacts.iter() {
+            for addr in info.tcp_acceptors.iter() {
+                if book.position_of_addr(addr).is_none() {
+                    book.entries.push(ContactEntry { addr: *addr, peer: None, last_seen: now });
+                }
+            }
+        }
+        book.last_flush = now;
+        book
+    }
+
+    pub fn entries(&self) -> &[ContactEntry] {
+        &self.entries
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    fn position_of_addr(&self, addr: &SocketAddr) -> Option<usize> {
+        self.entries.iter().position(|e| e.addr == *addr)
+    }
+
+    // Deduplicates on both the address and the owner.
+    fn insert(&mut self, entry: ContactEntry) {
+        let peer = entry.peer.clone();
+        let addr = entry.addr;
+        self.entries.retain(|e| e.addr != addr && (peer.is_none() || e.peer != peer));
+        self.entries.insert(0, entry);
+    }
+
+    /// Records that `peer` is reachable at `addr`. Returns true if the address
+    /// was not known yet, i.e. other peers cannot find us until it is written.
+    pub fn touch(&mut self, peer: PeerId, addr: SocketAddr, now: i64) -> bool {
+        let is_new = self.position_of_addr(&addr).is_none();
+        self.insert(ContactEntry { addr: addr, peer: Some(peer), last_seen: now });
+        self.dirty = true;
+        self.unpublished = self.unpublished || is_new;
+        is_new
+    }
+
+    /// Bumps the entries owned by currently connected peers.
+    pub fn refresh_peers(&mut self, connected: &[PeerId], now: i64) {
+        for e in self.entries.iter_mut() {
+            let is_connected = match e.peer {
+                Some(ref p) => connected.contains(p),
+                None => false,
+            };
+            if is_connected && now - e.last_seen >= CONTACT_REFRESH_SECS {
+                e.last_seen = now;
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Drops every entry nobody has seen for `max_age` seconds, but the
+    /// CONTACT_MIN_KEPT seen last.
+    pub fn prune(&mut self, now: i64, max_age: i64) {
+        let before = self.entries.len();
+        let mut seen: Vec<i64> = self.entries.iter().map(|e| e.last_seen).collect();
+        seen.sort_by(|a, b| b.cmp(a));
+        let kept_since = seen.get(CONTACT_MIN_KEPT - 1).cloned().unwrap_or(i64::min_value());
+        self.entries.retain(|e| now - e.last_seen < max_age || e.last_seen >= kept_since);
+        if self.entries.len() != before {
+            self.dirty = true;
+        }
+    }
+
+    /// Called on clean shutdown so we do not leave a dead address behind.
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        let before = self.entries.len();
+        self.entries.retain(|e| e.peer.as_ref() != Some(peer));
+        if self.entries.len() != before {
+            self.dirty = true;
+        }
+    }
+
+    pub fn should_flush(&self, now: i64) -> bool {
+        self.dirty && (self.unpublished || now - self.last_flush >= CONTACT_FLUSH_SECS)
+    }
+
+    pub fn mark_flushed(&mut self, now: i64) {
+        self.dirty = false;
+        self.unpublished = false;
+        self.last_flush = now;
+    }
+
+    /// Writes the entries back into `config`, freshest acceptor first since
+    /// crust tries the first one.
+    pub fn apply(&self, config: &mut Config) {
+        let mut sorted = self.entries.clone();
+        sorted.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+        let acceptors = sorted.iter().map(|e| e.addr).collect();
+        config.hard_coded_contacts = vec![StaticContactInfo {
+            tcp_acceptors: acceptors,
+            tcp_mapper_servers: vec![],
+        }];
+        config.contacts = Some(sorted);
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use crust::PeerId;
+    use rand::random;
+    use socket_addr::SocketAddr;
+    use network::bootstrap::Config;
+
+    fn addr(s: &str) -> SocketAddr {
+        SocketAddr(s.parse().unwrap())
+    }
+
+    #[test]
+    fn touch_deduplicates(){
+        let mut book = ContactBook::new();
+        let p1: PeerId = random();
+        let p2: PeerId = random();
+        assert!(book.touch(p1, addr("10.0.0.1:5483"), 10));
+        assert!(!book.touch(p2, addr("10.0.0.1:5483"), 20));
+        assert_eq!(book.entries().len(), 1);
+        assert_eq!(book.entries()[0].peer, Some(p2));
+        // The same peer moving to another address replaces its old entry.
+        assert!(book.touch(p2, addr("10.0.0.2:5483"), 30));
+        assert_eq!(book.entries().len(), 1);
+    }
+
+    #[test]
+    fn prune_and_remove(){
+        let mut book = ContactBook::new();
+        let p1: PeerId = random();
+        let p2: PeerId = random();
+        book.touch(p1, addr("10.0.0.1:5483"), 0);
+        book.touch(p2, addr("10.0.0.2:5483"), 100);
+        for i in 0..CONTACT_MIN_KEPT {
+            book.touch(random(), addr(&format!("10.0.1.{}:5483", i)), 110 + i as i64);
+        }
+        book.mark_flushed(200);
+        book.prune(250, 100);
+        assert!(book.is_dirty());
+        assert_eq!(book.entries().len(), CONTACT_MIN_KEPT);
+        assert!(book.entries().iter().all(|e| e.last_seen >= 100));
+        book.remove_peer(&p2);
+        assert_eq!(book.entries().len(), CONTACT_MIN_KEPT);
+    }
+
+    #[test]
+    fn long_idle_sessions_keep_their_last_contacts(){
+        let mut book = ContactBook::new();
+        let p1: PeerId = random();
+        let p2: PeerId = random();
+        book.touch(p1, addr("10.0.0.1:5483"), 0);
+        book.touch(p2, addr("10.0.0.2:5483"), 100);
+        book.mark_flushed(100);
+        book.prune(100 + 10 * CONTACT_MAX_AGE_SECS, CONTACT_MAX_AGE_SECS);
+        assert!(!book.is_dirty());
+        assert_eq!(book.entries().len(), 2);
+        book.remove_peer(&p2);
+        assert_eq!(book.entries().len(), 1);
+    }
+
+    #[test]
+    fn flushes_are_batched(){
+        let mut book = ContactBook::new();
+        let p1: PeerId = random();
+        book.touch(p1, addr("10.0.0.1:5483"), 999);
+        // A new address goes out with the next flush.
+        assert!(book.should_flush(999));
+        book.mark_flushed(1000);
+        book.touch(p1, addr("10.0.0.1:5483"), 1001);
+        assert!(!book.should_flush(1001));
+        assert!(book.should_flush(1000 + CONTACT_FLUSH_SECS));
+    }
+
+    #[test]
+    fn apply_orders_by_last_seen(){
+        let mut book = ContactBook::new();
+        let p1: PeerId = random();
+        let p2: PeerId = random();
+        book.touch(p1, addr("10.0.0.1:5483"), 200);
+        book.touch(p2, addr("10.0.0.2:5483"), 100);
+        let mut config = Config::default();
+        book.apply(&mut config);
+        assert_eq!(config.hard_coded_contacts[0].tcp_acceptors[0], addr("10.0.0.1:5483"));
+        assert_eq!(config.contacts.unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,435 @@
+use std::sync::{Arc, Mutex};
+use crust::PeerId;
+use rand::{Rng, SeedableRng, XorShiftRng};
+use super::{MessagePasserT, Message, Packet};
+
+/// Probabilities of each fault, rolled in this order for every outgoing message.
+/// Delays are counted in messages sent, not wall time, so a run is fully
+/// determined by the seed and the sequence of calls.
+#[derive(Clone, Debug)]
+pub struct FaultPlan {
+    pub seed: u32,
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+    pub delay_rate: f64,
+    // 0 turns delays off whatever delay_rate says.
+    pub max_delay: u64,
+    pub reorder_rate: f64,
+}
+
+impl FaultPlan {
+    /// A plan that injects nothing, fields can then be set as needed.
+    pub fn reliable(seed: u32) -> FaultPlan {
+        FaultPlan {
+            seed: seed,
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            delay_rate: 0.0,
+            max_delay: 1,
+            reorder_rate: 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FaultEvent {
+    // tick of the send the fault applied to
+    Dropped(u64),
+    Duplicated(u64),
+    Delayed(u64, u64),
+    Reordered(u64),
+    Partitioned(u64, PeerId),
+}
+
+#[derive(Clone)]
+enum Dest {
+    One(PeerId),
+    All,
+}
+
+struct Held<T> {
+    release_at: u64,
+    dest: Dest,
+    msg: T,
+}
+
+struct FaultState<T> {
+    rng: XorShiftRng,
+    plan: FaultPlan,
+    tick: u64,
+    held: Vec<Held<T>>,
+    partitions: Vec<(PeerId, PeerId)>,
+    peers: Vec<PeerId>,
+    events: Vec<FaultEvent>,
+}
+
+impl<T> FaultState<T> {
+    fn is_partitioned(&self, a: &PeerId, b: &PeerId) -> bool {
+        self.partitions.iter().any(|&(ref x, ref y)| (x == a && y == b) || (x == b && y == a))
+    }
+
+    fn roll(&mut self, rate: f64) -> bool {
+        // Always draw, so that changing one rate does not shift the others.
+        let r = self.rng.next_f64();
+        r < rate
+    }
+}
+
+/// Wraps any `MessagePasserT` and drops, delays, duplicates, reorders or
+/// partitions its traffic according to a seeded `FaultPlan`.
+#[derive(Clone)]
+pub struct FaultyPasser<T:Message, M: MessagePasserT<T> + Clone> {
+    inner: M,
+    state: Arc<Mutex<FaultState<T>>>,
+}
+
+impl<T:Message, M: MessagePasserT<T> + Clone> FaultyPasser<T, M> {
+    pub fn new(inner: M, plan: FaultPlan) -> FaultyPasser<T, M> {
+        let rng = XorShiftRng::from_seed([plan.seed, 0x193a6754, 0xa8a7d469, 0x97830e05]);
+        FaultyPasser {
+            inner: inner,
+            state: Arc::new(Mutex::new(FaultState {
+                rng: rng,
+                plan: plan,
+                tick: 0,
+                held: vec![],
+                partitions: vec![],
+                peers: vec![],
+                events: vec![],
+            })),
+        }
+    }
+
+    /// Cuts traffic between `a` and `b` in both directions until healed.
+    pub fn partition(&self, a: PeerId, b: PeerId) {
+        unwrap_result!(self.state.lock()).partitions.push((a, b));
+    }
+
+    /// The peers a broadcast reaches. While this node is partitioned from
+    /// any of them its broadcasts go out as one send per peer, skipping the
+    /// cut ones.
+    pub fn set_peers(&self, peers: Vec<PeerId>) {
+        unwrap_result!(self.state.lock()).peers = peers;
+    }
+
+    pub fn heal(&self, a: &PeerId, b: &PeerId) {
+        let mut state = unwrap_result!(self.state.lock());
+        state.partitions.retain(|&(ref x, ref y)| !((x == a && y == b) || (x == b && y == a)));
+    }
+
+    pub fn heal_all(&self) {
+        unwrap_result!(self.state.lock()).partitions.clear();
+    }
+
+    /// Advances the logical clock without sending, releasing due messages.
+    pub fn tick(&self) {
+        let mut state = unwrap_result!(self.state.lock());
+        state.tick += 1;
+        self.release_due(&mut state);
+    }
+
+    /// Delivers every held message right away, in the order they are due.
+    pub fn flush(&self) {
+        let mut state = unwrap_result!(self.state.lock());
+        state.held.sort_by(|a, b| a.release_at.cmp(&b.release_at));
+        let held: Vec<Held<T>> = state.held.drain(..).collect();
+        for h in held {
+            self.deliver(h.dest, h.msg);
+        }
+    }
+
+    /// Every fault injected so far, to print when a test fails.
+    pub fn events(&self) -> Vec<FaultEvent> {
+        unwrap_result!(self.state.lock()).events.clone()
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    fn deliver(&self, dest: Dest, msg: T) {
+        match dest {
+            Dest::One(ref dst) => self.inner.send(dst, msg),
+            Dest::All => self.inner.broadcast(msg),
+        }
+    }
+
+    fn release_due(&self, state: &mut FaultState<T>) {
+        let tick = state.tick;
+        let mut due = vec![];
+        let mut i = 0;
+        while i < state.held.len() {
+            if state.held[i].release_at <= tick {
+                due.push(state.held.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        due.sort_by(|a, b| a.release_at.cmp(&b.release_at));
+        for h in due {
+            self.deliver(h.dest, h.msg);
+        }
+    }
+
+    // Where a message to `dest` may go, recording the partitioned peers.
+    fn route(&self, state: &mut FaultState<T>, dest: Dest, tick: u64) -> Vec<Dest> {
+        let me = self.inner.get_id().clone();
+        let targets = match dest {
+            Dest::All => {
+                if !state.peers.iter().any(|peer| state.is_partitioned(&me, peer)) {
+                    return vec![Dest::All];
+                }
+                state.peers.clone()
+            },
+            Dest::One(dst) => vec![dst],
+        };
+        let mut dests = vec![];
+        for dst in targets {
+            if state.is_partitioned(&me, &dst) {
+                state.events.push(FaultEvent::Partitioned(tick, dst));
+            } else {
+                dests.push(Dest::One(dst));
+            }
+        }
+        dests
+    }
+
+    fn submit(&self, dest: Dest, msg: T) {
+        let mut state = unwrap_result!(self.state.lock());
+        state.tick += 1;
+        let tick = state.tick;
+
+        let dests = self.route(&mut state, dest, tick);
+        if dests.is_empty() {
+            self.release_due(&mut state);
+            return;
+        }
+
+        let (drop_rate, duplicate_rate, delay_rate, reorder_rate, max_delay) = {
+            let p = &state.plan;
+            (p.drop_rate, p.duplicate_rate, p.delay_rate, p.reorder_rate, p.max_delay)
+        };
+        let dropped = state.roll(drop_rate);
+        let duplicated = state.roll(duplicate_rate);
+        let delayed = state.roll(delay_rate) && max_delay > 0;
+        let reordered = state.roll(reorder_rate);
+        let delay = 1 + state.rng.gen_range(0, ::std::cmp::max(max_delay, 1));
+
+        if dropped {
+            state.events.push(FaultEvent::Dropped(tick));
+        } else if delayed {
+            state.events.push(FaultEvent::Delayed(tick, delay));
+            for dest in &dests {
+                state.held.push(Held { release_at: tick + delay, dest: dest.clone(), msg: msg.clone() });
+            }
+        } else if reordered {
+            // Held back until after the next message goes out.
+            state.events.push(FaultEvent::Reordered(tick));
+            for dest in &dests {
+                state.held.push(Held { release_at: tick + 1, dest: dest.clone(), msg: msg.clone() });
+            }
+        } else {
+            for dest in &dests {
+                self.deliver(dest.clone(), msg.clone());
+            }
+        }
+        if duplicated && !dropped {
+            state.events.push(FaultEvent::Duplicated(tick));
+            for dest in dests {
+                self.deliver(dest, msg.clone());
+            }
+        }
+        self.release_due(&mut state);
+    }
+
+    fn accept(&self, pkt: &Packet<T>) -> bool {
+        let state = unwrap_result!(self.state.lock());
+        !state.is_partitioned(&pkt.source(), self.inner.get_id())
+    }
+}
+
+impl<T:Message, M: MessagePasserT<T> + Clone> MessagePasserT<T> for FaultyPasser<T, M> {
+    fn recv(&self) -> Packet<T> {
+        loop {
+            let pkt = self.inner.recv();
+            if self.accept(&pkt) {
+                return pkt;
+            }
+        }
+    }
+
+    fn try_recv(&self) -> Option<Packet<T>> {
+        while let Some(pkt) = self.inner.try_recv() {
+            if self.accept(&pkt) {
+                return Some(pkt);
+            }
+        }
+        None
+    }
+
+    fn get_id(&self) -> &PeerId {
+        self.inner.get_id()
+    }
+
+    fn broadcast(&self, msg: T) {
+        self.submit(Dest::All, msg);
+    }
+
+    fn send(&self, dst: &PeerId, msg: T) {
+        self.submit(Dest::One(dst.clone()), msg);
+    }
+}
+
+/// Records what is sent instead of sending it, for tests. The destination
+/// is None for broadcasts.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct MpRecorder<T:Message>{
+    pub id: PeerId,
+    pub sent: Arc<Mutex<Vec<(Option<PeerId>, T)>>>,
+}
+
+#[cfg(test)]
+impl<T:Message> MpRecorder<T>{
+    pub fn new(id: PeerId) -> MpRecorder<T> {
+        MpRecorder { id: id, sent: Arc::new(Mutex::new(vec![])) }
+    }
+
+    pub fn messages(&self) -> Vec<T> {
+        self.sent.lock().unwrap().iter().map(|&(_, ref msg)| msg.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+impl<T:Message> MessagePasserT<T> for MpRecorder<T>{
+    fn recv(&self) -> Packet<T>{
+        panic!("unimplemented");
+    }
+    fn try_recv(&self) -> Option<Packet<T>>{
+        None
+    }
+    fn get_id(&self) -> &PeerId{
+        &self.id
+    }
+    fn broadcast(&self, msg: T){
+        self.sent.lock().unwrap().push((None, msg));
+    }
+    fn send(&self, dst: &PeerId, msg: T){
+        self.sent.lock().unwrap().push((Some(dst.clone()), msg));
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use crust::PeerId;
+    use rand::random;
+    use network::{Message, MessagePasserT};
+
+    #[derive(RustcEncodable, RustcDecodable, Clone, Debug, PartialEq)]
+    struct TestMsg(u32);
+
+    impl Message for TestMsg{}
+
+    fn recorder() -> MpRecorder<TestMsg> {
+        MpRecorder::new(random())
+    }
+
+    fn sent_values(mp: &MpRecorder<TestMsg>) -> Vec<u32> {
+        mp.messages().iter().map(|&TestMsg(v)| v).collect()
+    }
+
+    fn lossy_plan(seed: u32) -> FaultPlan {
+        let mut plan = FaultPlan::reliable(seed);
+        plan.drop_rate = 0.2;
+        plan.duplicate_rate = 0.2;
+        plan.delay_rate = 0.2;
+        plan.max_delay = 5;
+        plan.reorder_rate = 0.2;
+        plan
+    }
+
+    #[test]
+    fn same_seed_same_faults(){
+        let run = |seed| {
+            let rec = recorder();
+            let mp = FaultyPasser::new(rec.clone(), lossy_plan(seed));
+            for i in 0..200 {
+                mp.broadcast(TestMsg(i));
+            }
+            mp.flush();
+            (mp.events(), sent_values(&rec))
+        };
+        assert_eq!(run(42), run(42));
+        assert!(run(42) != run(43));
+    }
+
+    #[test]
+    fn reliable_plan_passes_through(){
+        let rec = recorder();
+        let mp = FaultyPasser::new(rec.clone(), FaultPlan::reliable(1));
+        for i in 0..10 {
+            mp.broadcast(TestMsg(i));
+        }
+        assert_eq!(sent_values(&rec), (0..10).collect::<Vec<u32>>());
+        assert!(mp.events().is_empty());
+    }
+
+    #[test]
+    fn delayed_messages_arrive_after_flush(){
+        let rec = recorder();
+        let mut plan = FaultPlan::reliable(7);
+        plan.delay_rate = 1.0;
+        plan.max_delay = 100;
+        let mp = FaultyPasser::new(rec.clone(), plan);
+        for i in 0..5 {
+            mp.broadcast(TestMsg(i));
+        }
+        assert!(sent_values(&rec).len() < 5);
+        mp.flush();
+        assert_eq!(sent_values(&rec).len(), 5);
+    }
+
+    #[test]
+    fn zero_max_delay_never_delays(){
+        let rec = recorder();
+        let mut plan = FaultPlan::reliable(9);
+        plan.delay_rate = 1.0;
+        plan.max_delay = 0;
+        let mp = FaultyPasser::new(rec.clone(), plan);
+        for i in 0..5 {
+            mp.broadcast(TestMsg(i));
+        }
+        assert_eq!(sent_values(&rec), (0..5).collect::<Vec<u32>>());
+        assert!(mp.events().is_empty());
+    }
+
+    #[test]
+    fn partition_blocks_until_healed(){
+        let rec = recorder();
+        let other: PeerId = random();
+        let mp = FaultyPasser::new(rec.clone(), FaultPlan::reliable(3));
+        mp.partition(rec.id.clone(), other.clone());
+        mp.send(&other, TestMsg(1));
+        assert!(sent_values(&rec).is_empty());
+        mp.heal(&other, &rec.id);
+        mp.send(&other, TestMsg(2));
+        assert_eq!(sent_values(&rec), vec![2]);
+    }
+
+    #[test]
+    fn partition_cuts_broadcasts(){
+        let rec = recorder();
+        let other: PeerId = random();
+        let third: PeerId = random();
+        let mp = FaultyPasser::new(rec.clone(), FaultPlan::reliable(5));
+        mp.set_peers(vec![other.clone(), third.clone()]);
+        mp.partition(rec.id.clone(), other.clone());
+        mp.broadcast(TestMsg(1));
+        assert_eq!(*rec.sent.lock().unwrap(), vec![(Some(third.clone()), TestMsg(1))]);
+        assert_eq!(mp.events(), vec![FaultEvent::Partitioned(1, other.clone())]);
+        mp.heal_all();
+        mp.broadcast(TestMsg(2));
+        assert_eq!(rec.sent.lock().unwrap()[1], (None, TestMsg(2)));
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::time::{Duration, Instant};
+use std::thread;
+use crust::{PeerId, TheirConnectionInfo};
+use bincode;
+use bincode::rustc_serialize::{encode, decode};
+use rustc_serialize::base64::{ToBase64, FromBase64, URL_SAFE};
+use super::{MessagePasser, MessagePasserT, Message};
+
+const TOKEN_PREFIX: &'static str = "p2p3:";
+/// How long a peer that connects to an inviting node has to send its join request.
+pub const JOIN_TIMEOUT_SECS: i64 = 30;
+
+/// Everything a peer needs to join a running session, without going through git.
+#[derive(RustcEncodable, RustcDecodable, Debug)]
+pub struct Invite {
+    pub session_id: u64,
+    pub host: PeerId,
+    pub info: TheirConnectionInfo,
+    // Seconds since the epoch after which the host refuses the invite.
+    pub expires: Option<i64>,
+    pub secret: Option<String>,
+    // The git branch the session works on.
+    pub branch: String,
+}
+
+#[derive(Debug)]
+pub enum InviteError {
+    BadToken(String),
+    Expired,
+    HostUnreachable,
+}
+
+impl fmt::Display for InviteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InviteError::BadToken(ref e) => write!(f, "not a valid invite token: {}", e),
+            InviteError::Expired => write!(f, "the invite has expired"),
+            InviteError::HostUnreachable => write!(f, "could not reach the host of the invite"),
+        }
+    }
+}
+
+impl Invite {
+    /// Prepares a fresh connection info on `mp` and wraps it into an invite.
+    pub fn new<T:Message>(mp: &MessagePasser<T>, session_id: u64, branch: String, expires: Option<i64>, secret: Option<String>) -> Invite {
+        let tok = mp.prepare_connection_info();
+        let info = mp.wait_conn_info(tok);
+        Invite {
+            session_id: session_id,
+            host: mp.get_id().clone(),
+            info: info,
+            expires: expires,
+            secret: secret,
+            branch: branch,
+        }
+    }
+
+    pub fn to_token(&self) -> String {
+        let bytes = unwrap_result!(encode(self, bincode::SizeLimit::Infinite));
+        format!("{}{}", TOKEN_PREFIX, bytes.to_base64(URL_SAFE))
+    }
+
+    pub fn from_token(token: &str) -> Result<Invite, InviteError> {
+        let token = token.trim();
+        if !token.starts_with(TOKEN_PREFIX) {
+            return Err(InviteError::BadToken(format!("missing \"{}\" prefix", TOKEN_PREFIX)));
+        }
+        let bytes = match token[TOKEN_PREFIX.len()..].from_base64() {
+            Ok(b) => b,
+            Err(e) => return Err(InviteError::BadToken(format!("{}", e))),
+        };
+        match decode(&bytes[..]) {
+            Ok(invite) => Ok(invite),
+            Err(e) => Err(InviteError::BadToken(format!("{}", e))),
+        }
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        match self.expires {
+            Some(t) => now >= t,
+            None => false,
+        }
+    }
+
+    /// Connects straight to the host of the invite and waits until the
+    /// connection is up or `timeout` has passed.
+    pub fn join<T:Message>(self, mp: &MessagePasser<T>, now: i64, timeout: Duration) -> Result<(), InviteError> {
+        if self.is_expired(now) {
+            return Err(InviteError::Expired);
+        }
+        let host = self.host;
+        let tok = mp.prepare_connection_info();
+        mp.wait_conn_info(tok);
+        mp.connect(tok, self.info);
+        let start = Instant::now();
+        while !mp.peers().contains(&host) {
+            if start.elapsed() > timeout {
+                return Err(InviteError::HostUnreachable);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+}
+
+/// The invites handed out by this node, used to check incoming join requests.
+#[derive(Clone, Debug)]
+pub struct InviteBook {
+    issued: Vec<(u64, Option<i64>, Option<String>)>,
+}
+
+impl InviteBook {
+    pub fn new() -> InviteBook {
+        InviteBook { issued: vec![] }
+    }
+
+    pub fn issue(&mut self, invite: &Invite) {
+        self.issued.push((invite.session_id, invite.expires, invite.secret.clone()));
+    }
+
+    /// True if some unexpired invite for `session_id` carries `secret`.
+    pub fn admits(&self, session_id: u64, secret: &Option<String>, now: i64) -> bool {
+        self.issued.iter().any(|&(id, expires, ref s)| {
+            id == session_id && s == secret && expires.map_or(true, |t| now < t)
+        })
+    }
+}
+
+/// Which neighbours this node takes messages from. Once it hands out an
+/// invite, a peer that connects has to send a join request the invite book
+/// admits, and is cut off if it doesn't within JOIN_TIMEOUT_SECS. Peers that
+/// were connected before the first invite stay admitted.
+#[derive(Clone, Debug)]
+pub struct Admissions {
+    gated: bool,
+    admitted: BTreeSet<PeerId>,
+    // neighbours that have not joined yet, with when they were first seen
+    waiting: BTreeMap<PeerId, i64>,
+}
+
+impl Admissions {
+    pub fn new() -> Admissions {
+        Admissions { gated: false, admitted: BTreeSet::new(), waiting: BTreeMap::new() }
+    }
+
+    /// Starts asking for join requests, `connected` stay admitted.
+    pub fn gate(&mut self, connected: &[PeerId]) {
+        if !self.gated {
+            self.gated = true;
+            self.admitted.extend(connected.iter().cloned());
+        }
+    }
+
+    pub fn admit(&mut self, peer: &PeerId) {
+        self.waiting.remove(peer);
+        self.admitted.insert(peer.clone());
+    }
+
+    pub fn allows(&self, peer: &PeerId) -> bool {
+        !self.gated || self.admitted.contains(peer)
+    }
+
+    /// Notes the neighbours connected now and returns the ones that waited
+    /// too long to join.
+    pub fn overdue(&mut self, connected: &[PeerId], now: i64) -> Vec<PeerId> {
+        if !self.gated {
+            return vec![];
+        }
+        self.waiting.retain(|peer, _| connected.contains(peer));
+        for peer in connected {
+            if !self.admitted.contains(peer) {
+                self.waiting.entry(peer.clone()).or_insert(now);
+            }
+        }
+        let late: Vec<PeerId> = self.waiting.iter()
+            .filter(|&(_, since)| now - since >= JOIN_TIMEOUT_SECS)
+            .map(|(peer, _)| peer.clone()).collect();
+        for peer in &late {
+            self.waiting.remove(peer);
+        }
+        late
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use crust::PeerId;
+    use rand::random;
+
+    #[test]
+    fn rejects_garbage_tokens(){
+        match Invite::from_token("hello") {
+            Err(InviteError::BadToken(_)) => {},
+            _ => assert!(false),
+        }
+        match Invite::from_token("p2p3:%%%") {
+            Err(InviteError::BadToken(_)) => {},
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn book_checks_secret_and_expiry(){
+        let mut book = InviteBook::new();
+        book.issued.push((7, Some(100), Some("s3cret".to_string())));
+        book.issued.push((7, None, None));
+        assert!(book.admits(7, &Some("s3cret".to_string()), 50));
+        assert!(!book.admits(7, &Some("s3cret".to_string()), 100));
+        assert!(!book.admits(7, &Some("guess".to_string()), 50));
+        assert!(book.admits(7, &None, 1000));
+        assert!(!book.admits(8, &None, 1000));
+    }
+
+    #[test]
+    fn newcomers_must_join_in_time(){
+        let (old, joiner, silent): (PeerId, PeerId, PeerId) = (random(), random(), random());
+        let mut admissions = Admissions::new();
+        assert!(admissions.allows(&silent));
+        admissions.gate(&[old.clone()]);
+        assert!(admissions.allows(&old));
+        assert!(!admissions.allows(&joiner));
+
+        let connected = [old.clone(), joiner.clone(), silent.clone()];
+        assert!(admissions.overdue(&connected, 100).is_empty());
+        admissions.admit(&joiner);
+        assert!(admissions.allows(&joiner));
+        assert_eq!(admissions.overdue(&connected, 100 + JOIN_TIMEOUT_SECS), vec![silent]);
+    }
+}
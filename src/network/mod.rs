@@ -1,5 +1,8 @@
 #![allow(dead_code)]
 pub mod bootstrap;
+pub mod contacts;
+pub mod faulty;
+pub mod invite;
 
 use std::collections::{BTreeMap, HashMap};
 use std::collections::hash_map::Entry;
@@ -78,7 +81,9 @@ pub struct MessagePasser<T:Message>{
     conn_cvar: Arc<Condvar>,
     // temp_conn_infos intended to be used for full socket connection to store our connection infos sent for other peers
     temp_conn_infos: Am<HashMap<PeerId,u32>>,
-    on_disconnect: Am<Box<FnMut(&PeerId) + Send>>
+    on_disconnect: Am<Box<FnMut(&PeerId) + Send>>,
+    // whether to take a packet from a neighbour, None for our own protocol messages
+    admit: Am<Box<Fn(&PeerId, Option<&T>) -> bool + Send>>,
 }
 
 impl<T:Message> MessagePasser<T> {
@@ -109,7 +114,8 @@ impl<T:Message> MessagePasser<T> {
             conn_cvar: Arc::new(Condvar::new()),
             conn_infos: Arc::new(Mutex::new(HashMap::new())),
             temp_conn_infos: Arc::new(Mutex::new(HashMap::new())),
-            on_disconnect: Arc::new(Mutex::new(Box::new(|_:&PeerId|{})))
+            on_disconnect: Arc::new(Mutex::new(Box::new(|_:&PeerId|{}))),
+            admit: Arc::new(Mutex::new(Box::new(|_: &PeerId, _: Option<&T>| true))),
         };
 
         let handler = {
@@ -256,7 +262,18 @@ impl<T:Message> MessagePasser<T> {
     }
 
     // fired whenever a message is received
-    fn on_recv_pkt(&self, _: PeerId, pkt: Packet<T>){
+    fn on_recv_pkt(&self, peer_id: PeerId, pkt: Packet<T>){
+        let admitted = {
+            let admit = unwrap_result!(self.admit.lock());
+            match pkt.message {
+                InnerMessage::Outside(ref msg) => admit(&peer_id, Some(msg)),
+                _ => admit(&peer_id, None),
+            }
+        };
+        if !admitted {
+            println!("Dropping a packet from {}, it was not admitted", peer_id);
+            return;
+        }
         match pkt.protocol {
             Protocol::Normal =>{
                 println!("Received packet");
@@ -386,6 +403,23 @@ impl<T:Message> MessagePasser<T> {
         unwrap_result!(unwrap_result!(self.service.lock()).send(&dst, bytes));
     }
 
+    pub fn disconnect(&self, peer: &PeerId){
+        unwrap_result!(self.service.lock()).disconnect(peer);
+        unwrap_result!(self.peer_seqs.lock()).remove(peer);
+    }
+
+    /// The peers we hold a connection to, not just hear from through others.
+    pub fn neighbours(&self) -> Vec<PeerId>{
+        let service = unwrap_result!(self.service.lock());
+        self.peers().into_iter().filter(|id| service.connection_info(id).is_some()).collect()
+    }
+
+    /// Packets a neighbour sends are only taken, and relayed, if `admit`
+    /// allows them. Nothing is dropped until this is set.
+    pub fn set_admission(&self, admit: Box<Fn(&PeerId, Option<&T>) -> bool + Send>) {
+        *unwrap_result!(self.admit.lock()) = admit;
+    }
+
     pub fn peers(&self) -> Vec<PeerId>{
         let peer_seqs = unwrap_result!(self.peer_seqs.lock());
         peer_seqs.keys().map(|k| *k).collect()
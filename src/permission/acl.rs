@@ -0,0 +1,329 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::str::FromStr;
+use rustc_serialize::json::Json;
+
+/// Committed at the root of the repo, maps git identities to roles per path.
+pub const ACL_FILE: &'static str = ".p2p3-permissions";
+// Version 1 was a plain list of editors for the whole repo.
+pub const ACL_VERSION: u64 = 2;
+
+/// Ordered from least to most trusted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, RustcEncodable, RustcDecodable)]
+pub enum Role {
+    NoAccess,
+    Viewer,
+    Editor,
+    Owner,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Open,
+    Edit,
+    Run,
+    Commit,
+    // Changing who holds which role.
+    Manage,
+}
+
+impl Role {
+    pub fn allows(&self, action: Action) -> bool {
+        let needed = match action {
+            Action::Open => Role::Viewer,
+            Action::Edit | Action::Run | Action::Commit => Role::Editor,
+            Action::Manage => Role::Owner,
+        };
+        *self >= needed
+    }
+}
+
+/// What doing `action` on `path` takes. Editing or committing the ACL file
+/// changes who holds which role, so it takes `Manage`.
+pub fn action_on(path: &str, action: Action) -> Action {
+    match action {
+        Action::Edit | Action::Commit if path == ACL_FILE => Action::Manage,
+        _ => action,
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Role::NoAccess => "none",
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Owner => "owner",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Role {
+    type Err = AclError;
+
+    fn from_str(s: &str) -> Result<Role, AclError> {
+        match s {
+            "none" => Ok(Role::NoAccess),
+            "viewer" => Ok(Role::Viewer),
+            "editor" => Ok(Role::Editor),
+            "owner" => Ok(Role::Owner),
+            _ => Err(AclError::UnknownRole(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AclError {
+    Io(io::Error),
+    Malformed(String),
+    UnsupportedVersion(u64),
+    UnknownRole(String),
+}
+
+impl fmt::Display for AclError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AclError::Io(ref e) => write!(f, "{}", e),
+            AclError::Malformed(ref e) => write!(f, "malformed {}: {}", ACL_FILE, e),
+            AclError::UnsupportedVersion(v) => write!(f, "{} version {} is newer than this p2p3", ACL_FILE, v),
+            AclError::UnknownRole(ref r) => write!(f, "unknown role {}", r),
+        }
+    }
+}
+
+impl From<io::Error> for AclError {
+    fn from(e: io::Error) -> AclError {
+        AclError::Io(e)
+    }
+}
+
+/// Gives `role` to `identity`, a git email or "*" for everyone, on the
+/// paths matching `paths`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub identity: String,
+    pub paths: String,
+    pub role: Role,
+}
+
+impl Rule {
+    fn applies_to(&self, email: Option<&str>) -> bool {
+        self.identity == "*" || Some(&self.identity[..]) == email
+    }
+}
+
+/// `*` and `?` stay within a directory, `**` crosses them.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    matches_from(pattern.as_bytes(), path.as_bytes())
+}
+
+fn matches_from(p: &[u8], s: &[u8]) -> bool {
+    if p.is_empty() {
+        return s.is_empty();
+    }
+    if p.starts_with(b"**") {
+        let rest = &p[2..];
+        // "a/**/b" also matches "a/b".
+        if rest.starts_with(b"/") && matches_from(&rest[1..], s) {
+            return true;
+        }
+        return (0..s.len() + 1).any(|i| matches_from(rest, &s[i..]));
+    }
+    match p[0] {
+        b'*' => (0..s.len() + 1)
+            .take_while(|&i| i == 0 || s[i - 1] != b'/')
+            .any(|i| matches_from(&p[1..], &s[i..])),
+        b'?' => !s.is_empty() && s[0] != b'/' && matches_from(&p[1..], &s[1..]),
+        c => !s.is_empty() && s[0] == c && matches_from(&p[1..], &s[1..]),
+    }
+}
+
+/// The session's role table. Of the rules matching an identity and a path
+/// the last one wins, `default` applies when none does.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Acl {
+    pub default: Role,
+    pub rules: Vec<Rule>,
+    // The signing keys, in hex, that prove a peer holds a git email.
+    pub keys: BTreeMap<String, Vec<String>>,
+}
+
+fn field<'a>(obj: &'a Json, name: &str) -> Result<&'a Json, AclError> {
+    obj.find(name).ok_or(AclError::Malformed(format!("missing {}", name)))
+}
+
+fn string_field<'a>(obj: &'a Json, name: &str) -> Result<&'a str, AclError> {
+    try!(field(obj, name)).as_string().ok_or(AclError::Malformed(format!("{} is not a string", name)))
+}
+
+impl Acl {
+    /// Without an ACL file everybody in the session may edit everything.
+    pub fn open() -> Acl {
+        Acl { default: Role::Editor, rules: vec![], keys: BTreeMap::new() }
+    }
+
+    /// Every role is `default`, e.g. when the ACL can't be read.
+    pub fn only(default: Role) -> Acl {
+        Acl { default: default, rules: vec![], keys: BTreeMap::new() }
+    }
+
+    pub fn parse(s: &str) -> Result<Acl, AclError> {
+        let json = try!(Json::from_str(s).map_err(|e| AclError::Malformed(format!("{}", e))));
+        let version = json.find("version").and_then(|v| v.as_u64()).unwrap_or(1);
+        match version {
+            1 => {
+                let editors = try!(try!(field(&json, "editors")).as_array()
+                                   .ok_or(AclError::Malformed("editors is not a list".to_string())));
+                let mut rules = vec![];
+                for editor in editors {
+                    let email = try!(editor.as_string().ok_or(AclError::Malformed("editors holds a non string".to_string())));
+                    rules.push(Rule { identity: email.to_string(), paths: "**".to_string(), role: Role::Editor });
+                }
+                Ok(Acl { default: Role::Viewer, rules: rules, keys: BTreeMap::new() })
+            },
+            ACL_VERSION => {
+                let default = try!(try!(string_field(&json, "default")).parse());
+                let entries = try!(try!(field(&json, "rules")).as_array()
+                                   .ok_or(AclError::Malformed("rules is not a list".to_string())));
+                let mut rules = vec![];
+                for entry in entries {
+                    rules.push(Rule {
+                        identity: try!(string_field(entry, "identity")).to_string(),
+                        paths: try!(string_field(entry, "paths")).to_string(),
+                        role: try!(try!(string_field(entry, "role")).parse()),
+                    });
+                }
+                let mut keys = BTreeMap::new();
+                if let Some(listed) = json.find("keys") {
+                    let listed = try!(listed.as_object().ok_or(AclError::Malformed("keys is not an object".to_string())));
+                    for (email, hexes) in listed {
+                        let hexes = try!(hexes.as_array().ok_or(AclError::Malformed(format!("keys of {} is not a list", email))));
+                        let mut held = vec![];
+                        for hex in hexes {
+                            let hex = try!(hex.as_string().ok_or(AclError::Malformed(format!("keys of {} holds a non string", email))));
+                            held.push(hex.to_lowercase());
+                        }
+                        keys.insert(email.clone(), held);
+                    }
+                }
+                Ok(Acl { default: default, rules: rules, keys: keys })
+            },
+            v => Err(AclError::UnsupportedVersion(v)),
+        }
+    }
+
+    /// The ACL committed under `root`, None if there is none.
+    pub fn read(root: &str) -> Result<Option<Acl>, AclError> {
+        let mut file = match File::open(Path::new(root).join(ACL_FILE)) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(AclError::Io(e)),
+        };
+        let mut s = String::new();
+        try!(file.read_to_string(&mut s));
+        Acl::parse(&s).map(Some)
+    }
+
+    /// Whether `key`, in hex, is one the ACL lists for `email`.
+    pub fn lists_key(&self, email: &str, key: &str) -> bool {
+        self.keys.get(email).map_or(false, |keys| keys.iter().any(|k| *k == key.to_lowercase()))
+    }
+
+    /// `email` is None for a peer that has not told us who it is.
+    pub fn role(&self, email: Option<&str>, path: &str) -> Role {
+        self.rules.iter()
+            .filter(|r| r.applies_to(email) && glob_match(&r.paths, path))
+            .last()
+            .map_or(self.default, |r| r.role)
+    }
+
+    /// The most `email` may do anywhere in the repo.
+    pub fn highest_role(&self, email: Option<&str>) -> Role {
+        self.rules.iter()
+            .filter(|r| r.applies_to(email))
+            .map(|r| r.role)
+            .fold(self.default, ::std::cmp::max)
+    }
+
+    pub fn allows(&self, email: Option<&str>, path: &str, action: Action) -> bool {
+        self.role(email, path).allows(action)
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+
+    #[test]
+    fn globs_respect_directories(){
+        assert!(glob_match("src/*.c", "src/main.c"));
+        assert!(!glob_match("src/*.c", "src/net/peer.c"));
+        assert!(glob_match("src/**/*.c", "src/net/peer.c"));
+        assert!(glob_match("src/**/*.c", "src/main.c"));
+        assert!(glob_match("**", "anything/at/all"));
+        assert!(glob_match("a?c", "abc"));
+        assert!(!glob_match("a?c", "a/c"));
+    }
+
+    #[test]
+    fn last_matching_rule_wins(){
+        let acl = Acl::parse(r#"{
+            "version": 2,
+            "default": "viewer",
+            "rules": [
+                {"identity": "*", "paths": "docs/**", "role": "none"},
+                {"identity": "alice@example.com", "paths": "**", "role": "owner"},
+                {"identity": "bob@example.com", "paths": "src/**", "role": "editor"},
+                {"identity": "bob@example.com", "paths": "src/secret/**", "role": "none"}
+            ]
+        }"#).unwrap();
+        assert_eq!(acl.role(Some("alice@example.com"), "docs/a.md"), Role::Owner);
+        assert_eq!(acl.role(Some("bob@example.com"), "src/main.c"), Role::Editor);
+        assert_eq!(acl.role(Some("bob@example.com"), "src/secret/key.c"), Role::NoAccess);
+        assert_eq!(acl.role(Some("bob@example.com"), "docs/a.md"), Role::NoAccess);
+        assert_eq!(acl.role(None, "README.md"), Role::Viewer);
+        assert_eq!(acl.highest_role(Some("bob@example.com")), Role::Editor);
+        assert!(!acl.allows(None, "docs/a.md", Action::Open));
+        assert!(acl.allows(None, "README.md", Action::Open));
+    }
+
+    #[test]
+    fn keys_prove_identities(){
+        let acl = Acl::parse(r#"{
+            "version": 2,
+            "default": "viewer",
+            "rules": [],
+            "keys": {"alice@example.com": ["00FF"]}
+        }"#).unwrap();
+        assert!(acl.lists_key("alice@example.com", "00ff"));
+        assert!(!acl.lists_key("alice@example.com", "0100"));
+        assert!(!acl.lists_key("bob@example.com", "00ff"));
+        assert!(Acl::parse(r#"{"version": 2, "default": "viewer", "rules": [], "keys": []}"#).is_err());
+    }
+
+    #[test]
+    fn only_owners_change_the_acl(){
+        assert_eq!(action_on(ACL_FILE, Action::Edit), Action::Manage);
+        assert_eq!(action_on(ACL_FILE, Action::Commit), Action::Manage);
+        assert_eq!(action_on(ACL_FILE, Action::Open), Action::Open);
+        assert_eq!(action_on("src/main.c", Action::Edit), Action::Edit);
+        assert!(!Role::Editor.allows(action_on(ACL_FILE, Action::Edit)));
+        assert!(Role::Owner.allows(action_on(ACL_FILE, Action::Edit)));
+    }
+
+    #[test]
+    fn version_one_lists_editors(){
+        let acl = Acl::parse(r#"{"editors": ["alice@example.com"]}"#).unwrap();
+        assert_eq!(acl.role(Some("alice@example.com"), "a.c"), Role::Editor);
+        assert_eq!(acl.role(Some("bob@example.com"), "a.c"), Role::Viewer);
+        match Acl::parse(r#"{"version": 99}"#) {
+            Err(AclError::UnsupportedVersion(99)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+use crust::PeerId;
+
+/// Edit rights an editor handed to a peer the ACL keeps read only.
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Grant {
+    pub holder: PeerId,
+    pub granted_by: PeerId,
+    // Seconds since the epoch, None until revoked.
+    pub expires: Option<i64>,
+}
+
+/// Takes back the rights `holder` was granted.
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Revocation {
+    pub holder: PeerId,
+    pub revoked_by: PeerId,
+}
+
+impl Grant {
+    pub fn active(&self, now: i64) -> bool {
+        self.expires.map_or(true, |t| now < t)
+    }
+}
+
+/// The temporary edit rights held in the session.
+#[derive(Clone, Debug)]
+pub struct Grants {
+    held: BTreeMap<PeerId, Grant>,
+}
+
+impl Grants {
+    pub fn new() -> Grants {
+        Grants { held: BTreeMap::new() }
+    }
+
+    /// A new grant to the same peer replaces the old one.
+    pub fn grant(&mut self, grant: Grant) {
+        self.held.insert(grant.holder.clone(), grant);
+    }
+
+    /// Returns false if `peer` held no rights.
+    pub fn revoke(&mut self, peer: &PeerId) -> bool {
+        self.held.remove(peer).is_some()
+    }
+
+    /// Who granted the rights `peer` holds, None if it holds none.
+    pub fn granter_of(&self, peer: &PeerId, now: i64) -> Option<PeerId> {
+        self.held.get(peer).and_then(|g| if g.active(now) { Some(g.granted_by.clone()) } else { None })
+    }
+
+    /// Drops the grants that ran out and returns their holders.
+    pub fn expire(&mut self, now: i64) -> Vec<PeerId> {
+        let expired: Vec<PeerId> = self.held.values().filter(|g| !g.active(now)).map(|g| g.holder.clone()).collect();
+        for peer in &expired {
+            self.held.remove(peer);
+        }
+        expired
+    }
+
+    pub fn all(&self) -> Vec<Grant> {
+        self.held.values().cloned().collect()
+    }
+
+    pub fn granted_by(&self, peer: &PeerId) -> Vec<Grant> {
+        self.held.values().filter(|g| g.granted_by == *peer).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use crust::PeerId;
+    use rand::random;
+
+    #[test]
+    fn grants_expire_and_revoke(){
+        let host: PeerId = random();
+        let guest: PeerId = random();
+        let other: PeerId = random();
+        let mut grants = Grants::new();
+        grants.grant(Grant { holder: guest.clone(), granted_by: host.clone(), expires: Some(100) });
+        grants.grant(Grant { holder: other.clone(), granted_by: host.clone(), expires: None });
+        assert_eq!(grants.granter_of(&guest, 99), Some(host.clone()));
+        assert_eq!(grants.granter_of(&guest, 100), None);
+        assert_eq!(grants.expire(100), vec![guest.clone()]);
+        assert_eq!(grants.granted_by(&host).len(), 1);
+        assert!(grants.revoke(&other));
+        assert!(!grants.revoke(&other));
+        assert!(grants.all().is_empty());
+    }
+}
@@ -1 +1,4 @@
 pub mod permissions_handler;
+pub mod acl;
+pub mod signing;
+pub mod grants;
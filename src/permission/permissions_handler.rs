@@ -1,31 +1,257 @@
 #![allow(dead_code)]
 
+use std::fmt;
+use std::path::Path;
 use storage::storage_helper::GitAccess;
-use git2::ErrorCode;
+use permission::acl::{Acl, Role, ACL_FILE};
+use git2;
+use git2::{Direction, ErrorClass, ErrorCode, Repository};
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum PermissionLevel {
     Editor,
     Viewer
 }
 
-pub fn get_permission_level(ga: &GitAccess) -> PermissionLevel {
-    return match ga.push() {
-        Ok(()) => PermissionLevel::Editor,
-        Err(e) => {
-            if e.code() == ErrorCode::GenericError {
-                if e.to_string().contains("403") {
-                    println!("User does not have write access")
-                } else if e.to_string().contains("401") {
-                    println!("Invalid password")
-                }
-            } else if e.code() == ErrorCode::NotFastForward {
-                match ga.pull_repo() {
-                    Ok(()) => println!("pull repo successful"),
-                    Err(e) => println!("pull repo error {}", e),
-                };
-                return get_permission_level(ga);
+impl PermissionLevel {
+    /// The most we let ourselves do, whatever the ACL says.
+    pub fn ceiling(&self) -> Role {
+        match *self {
+            PermissionLevel::Editor => Role::Owner,
+            PermissionLevel::Viewer => Role::Viewer,
+        }
+    }
+}
+
+/// What a single probe could tell about our access to the repo.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Access {
+    Write,
+    Read,
+    // This probe can't tell, ask the next one.
+    Unknown,
+}
+
+#[derive(Debug)]
+pub enum ProbeError {
+    AuthFailed,
+    Unreachable(String),
+    Git(git2::Error),
+}
+
+impl From<git2::Error> for ProbeError {
+    fn from(e: git2::Error) -> ProbeError {
+        ProbeError::Git(e)
+    }
+}
+
+/// One way of finding out whether we may push, without pushing anything.
+/// A hosting service API can be queried by implementing this.
+pub trait PermissionProbe {
+    fn name(&self) -> &'static str;
+    fn probe(&self, ga: &GitAccess) -> Result<Access, ProbeError>;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PermissionOutcome {
+    Editor { decided_by: &'static str },
+    Viewer { decided_by: &'static str },
+    AuthFailed,
+    Unreachable(String),
+    Failed(String),
+    // None of the probes could tell.
+    Undetermined,
+}
+
+impl PermissionOutcome {
+    /// Anything short of a positive answer means read only.
+    pub fn level(&self) -> PermissionLevel {
+        match *self {
+            PermissionOutcome::Editor { .. } => PermissionLevel::Editor,
+            _ => PermissionLevel::Viewer,
+        }
+    }
+}
+
+impl fmt::Display for PermissionOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PermissionOutcome::Editor { decided_by } => write!(f, "editor (according to {})", decided_by),
+            PermissionOutcome::Viewer { decided_by } => write!(f, "viewer (according to {})", decided_by),
+            PermissionOutcome::AuthFailed => write!(f, "viewer, the remote rejected our credentials"),
+            PermissionOutcome::Unreachable(ref e) => write!(f, "viewer, the remote could not be reached: {}", e),
+            PermissionOutcome::Failed(ref e) => write!(f, "viewer, the permission check failed: {}", e),
+            PermissionOutcome::Undetermined => write!(f, "viewer, nothing could tell whether we may push"),
+        }
+    }
+}
+
+/// The ACL committed in the repo. Anyone it makes an editor nowhere is a
+/// viewer, even if the server would take their pushes.
+pub struct PermissionFile;
+
+impl PermissionProbe for PermissionFile {
+    fn name(&self) -> &'static str {
+        ACL_FILE
+    }
+
+    fn probe(&self, ga: &GitAccess) -> Result<Access, ProbeError> {
+        let acl = match Acl::read(&ga.local_url) {
+            Ok(Some(acl)) => acl,
+            Ok(None) => return Ok(Access::Unknown),
+            Err(e) => {
+                println!("Ignoring {}: {}", ACL_FILE, e);
+                return Ok(Access::Unknown);
             }
-            PermissionLevel::Viewer
-        },
-    };
+        };
+        let me = try!(ga.identity());
+        if acl.highest_role(Some(&me.email)) >= Role::Editor {
+            Ok(Access::Write)
+        } else {
+            Ok(Access::Read)
+        }
+    }
+}
+
+/// Opens a push connection to origin and hangs up before sending anything.
+/// The server checks our right to push when the connection is made.
+pub struct PushNegotiation;
+
+impl PermissionProbe for PushNegotiation {
+    fn name(&self) -> &'static str {
+        "push negotiation"
+    }
+
+    fn probe(&self, ga: &GitAccess) -> Result<Access, ProbeError> {
+        let repo = try!(Repository::open(Path::new(&ga.local_url)));
+        let mut remote = try!(repo.find_remote("origin"));
+        let connected = remote.connect_auth(Direction::Push, Some(ga.callbacks()), None).map(|_| ());
+        match connected {
+            Ok(()) => Ok(Access::Write),
+            Err(ref e) if e.code() == ErrorCode::Auth => Err(ProbeError::AuthFailed),
+            // The server refused the push service, e.g. 403 over https.
+            Err(ref e) if e.class() == ErrorClass::Http => Ok(Access::Read),
+            Err(ref e) if e.class() == ErrorClass::Net || e.class() == ErrorClass::Os => {
+                Err(ProbeError::Unreachable(e.message().to_string()))
+            },
+            Err(e) => Err(ProbeError::Git(e)),
+        }
+    }
+}
+
+pub fn default_probes() -> Vec<Box<PermissionProbe>> {
+    vec![Box::new(PushNegotiation), Box::new(PermissionFile)]
+}
+
+/// Asks the probes in turn. The first one that can tell decides, the ones
+/// after it can only take write access away.
+pub fn check_permission(ga: &GitAccess, probes: &[Box<PermissionProbe>]) -> PermissionOutcome {
+    let mut outcome = PermissionOutcome::Undetermined;
+    for probe in probes {
+        match probe.probe(ga) {
+            Ok(Access::Write) => {
+                if outcome == PermissionOutcome::Undetermined {
+                    outcome = PermissionOutcome::Editor { decided_by: probe.name() };
+                }
+            },
+            Ok(Access::Read) => return PermissionOutcome::Viewer { decided_by: probe.name() },
+            Ok(Access::Unknown) => {},
+            Err(ProbeError::AuthFailed) => return PermissionOutcome::AuthFailed,
+            Err(ProbeError::Unreachable(e)) => return PermissionOutcome::Unreachable(e),
+            Err(ProbeError::Git(e)) => return PermissionOutcome::Failed(format!("{}: {}", probe.name(), e)),
+        }
+    }
+    outcome
+}
+
+pub fn get_permission_level(ga: &GitAccess) -> PermissionLevel {
+    let outcome = check_permission(ga, &default_probes());
+    println!("Permission: {}", outcome);
+    outcome.level()
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::path::Path;
+    use git2::Repository;
+    use rand::random;
+    use storage::storage_helper::GitAccess;
+    use storage::credentials::CredentialProvider;
+    use permission::acl::ACL_FILE;
+
+    struct Fixed(Access);
+
+    impl PermissionProbe for Fixed {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+        fn probe(&self, _: &GitAccess) -> Result<Access, ProbeError> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn temp_repo(name: &str) -> (String, Repository) {
+        let n: u32 = random();
+        let dir = format!("{}/", env::temp_dir().join(format!("p2p3_{}_{}", name, n)).display());
+        fs::create_dir_all(&dir).unwrap();
+        let repo = Repository::init(Path::new(&dir)).unwrap();
+        {
+            let mut config = repo.config().unwrap();
+            config.set_str("user.name", "Tester").unwrap();
+            config.set_str("user.email", "tester@example.com").unwrap();
+        }
+        (dir, repo)
+    }
+
+    #[test]
+    fn first_probe_that_knows_decides(){
+        let ga = GitAccess::default();
+        let probes: Vec<Box<PermissionProbe>> = vec![Box::new(Fixed(Access::Unknown)), Box::new(Fixed(Access::Read))];
+        assert_eq!(check_permission(&ga, &probes), PermissionOutcome::Viewer { decided_by: "fixed" });
+        assert_eq!(check_permission(&ga, &[]), PermissionOutcome::Undetermined);
+        assert_eq!(check_permission(&ga, &[]).level(), PermissionLevel::Viewer);
+    }
+
+    #[test]
+    fn later_probes_only_take_access_away(){
+        let ga = GitAccess::default();
+        let capped: Vec<Box<PermissionProbe>> = vec![Box::new(Fixed(Access::Write)), Box::new(Fixed(Access::Read))];
+        assert_eq!(check_permission(&ga, &capped).level(), PermissionLevel::Viewer);
+        let refused: Vec<Box<PermissionProbe>> = vec![Box::new(Fixed(Access::Read)), Box::new(Fixed(Access::Write))];
+        assert_eq!(check_permission(&ga, &refused).level(), PermissionLevel::Viewer);
+        let allowed: Vec<Box<PermissionProbe>> = vec![Box::new(Fixed(Access::Write)), Box::new(Fixed(Access::Unknown))];
+        assert_eq!(check_permission(&ga, &allowed), PermissionOutcome::Editor { decided_by: "fixed" });
+    }
+
+    #[test]
+    fn permission_file_lists_editors(){
+        let (dir, _repo) = temp_repo("perm_file");
+        let ga = GitAccess::new(String::new(), dir.clone(), String::new(), CredentialProvider::none());
+        assert_eq!(PermissionFile.probe(&ga).unwrap(), Access::Unknown);
+        let path = Path::new(&dir).join(ACL_FILE);
+        File::create(&path).unwrap().write_all(b"{\"editors\": [\"someone@example.com\"]}").unwrap();
+        assert_eq!(PermissionFile.probe(&ga).unwrap(), Access::Read);
+        File::create(&path).unwrap().write_all(b"{\"editors\": [\"tester@example.com\"]}").unwrap();
+        assert_eq!(PermissionFile.probe(&ga).unwrap(), Access::Write);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn negotiating_with_a_local_remote_pushes_nothing(){
+        let n: u32 = random();
+        let remote = format!("{}", env::temp_dir().join(format!("p2p3_perm_remote_{}", n)).display());
+        let bare = Repository::init_bare(Path::new(&remote)).unwrap();
+        let (dir, repo) = temp_repo("perm_clone");
+        repo.remote("origin", &remote).unwrap();
+        let ga = GitAccess::new(remote.clone(), dir.clone(), String::new(), CredentialProvider::none());
+        assert_eq!(PushNegotiation.probe(&ga).unwrap(), Access::Write);
+        assert!(bare.references().unwrap().next().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&remote).unwrap();
+    }
 }
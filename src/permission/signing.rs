@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use bincode;
+use bincode::rustc_serialize::encode;
+use crust::PeerId;
+use sodiumoxide;
+use sodiumoxide::crypto::sign;
+use sodiumoxide::crypto::sign::SecretKey;
+use rustc_serialize::Encodable;
+use rustc_serialize::hex::{FromHex, ToHex};
+pub use sodiumoxide::crypto::sign::{PublicKey, Signature};
+use permission::acl::{Action, Role, action_on};
+use woot::operation::Operation;
+
+/// A WOOT operation with its author's signature over the document path and
+/// the operation.
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct SignedOperation {
+    pub operation: Operation,
+    pub signature: Signature,
+}
+
+fn signed_bytes(path: &str, operation: &Operation) -> Vec<u8> {
+    encode(&(path, operation), bincode::SizeLimit::Infinite).unwrap()
+}
+
+/// A notice to the session, such as a grant, signed by the peer it names as
+/// its sender. Packets say who sent them, but nothing stops a peer from
+/// lying there.
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Signed<T> {
+    pub notice: T,
+    pub signature: Signature,
+}
+
+// No file is called this, so no signed operation reads as a notice.
+const NOTICE_TAG: &'static str = "\0notice";
+
+fn notice_bytes<T: Encodable>(notice: &T) -> Vec<u8> {
+    encode(&(NOTICE_TAG, notice), bincode::SizeLimit::Infinite).unwrap()
+}
+
+/// Under the home directory, the key is kept from one session to the next
+/// so that the ACL can list it.
+pub const KEY_FILE: &'static str = ".p2p3/signing-key";
+
+/// How a key is written in the ACL.
+pub fn key_hex(key: &PublicKey) -> String {
+    key.0.to_hex()
+}
+
+fn bad_key(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{} does not hold a signing key", path.display()))
+}
+
+/// The key this peer signs its operations with.
+pub struct Signer {
+    public: PublicKey,
+    secret: SecretKey,
+}
+
+impl Signer {
+    pub fn generate() -> Signer {
+        sodiumoxide::init();
+        let (public, secret) = sign::gen_keypair();
+        Signer { public: public, secret: secret }
+    }
+
+    /// The key kept at `path`, made and written there the first time.
+    pub fn load_or_generate(path: &Path) -> io::Result<Signer> {
+        let mut s = String::new();
+        match File::open(path) {
+            Ok(mut file) => {
+                try!(file.read_to_string(&mut s));
+                let bytes = try!(s.trim().from_hex().map_err(|_| bad_key(path)));
+                let secret = try!(SecretKey::from_slice(&bytes).ok_or(bad_key(path)));
+                // The secret key ends with the public one.
+                let public = try!(PublicKey::from_slice(&bytes[sign::SECRETKEYBYTES - sign::PUBLICKEYBYTES..]).ok_or(bad_key(path)));
+                sodiumoxide::init();
+                Ok(Signer { public: public, secret: secret })
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                let signer = Signer::generate();
+                if let Some(dir) = path.parent() {
+                    try!(fs::create_dir_all(dir));
+                }
+                let mut file = try!(private_file(path));
+                try!(file.write_all(signer.secret.0.to_hex().as_bytes()));
+                Ok(signer)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public
+    }
+
+    pub fn sign(&self, path: &str, operation: Operation) -> SignedOperation {
+        let signature = sign::sign_detached(&signed_bytes(path, &operation), &self.secret);
+        SignedOperation { operation: operation, signature: signature }
+    }
+
+    pub fn sign_notice<T: Encodable>(&self, notice: T) -> Signed<T> {
+        let signature = sign::sign_detached(&notice_bytes(&notice), &self.secret);
+        Signed { notice: notice, signature: signature }
+    }
+}
+
+#[cfg(unix)]
+fn private_file(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn private_file(path: &Path) -> io::Result<File> {
+    File::create(path)
+}
+
+/// The signing keys the peers announced with their identity. The first key
+/// heard from a peer is kept for the whole session.
+pub struct SigningKeys {
+    known: BTreeMap<PeerId, PublicKey>,
+}
+
+impl SigningKeys {
+    pub fn new() -> SigningKeys {
+        SigningKeys { known: BTreeMap::new() }
+    }
+
+    /// Returns true the first time we hear from `peer`.
+    pub fn insert(&mut self, peer: PeerId, key: PublicKey) -> bool {
+        if self.known.contains_key(&peer) {
+            return false;
+        }
+        self.known.insert(peer, key);
+        true
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<&PublicKey> {
+        self.known.get(peer)
+    }
+
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.known.remove(peer);
+    }
+}
+
+/// Why an operation from another peer was not applied.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Rejection {
+    // The peer never announced a signing key.
+    UnknownKey,
+    BadSignature,
+    // The operation claims to come from another site.
+    ForgedSite(PeerId),
+    NotAllowed(Role),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Rejection::UnknownKey => write!(f, "no signing key is known for the sender"),
+            Rejection::BadSignature => write!(f, "the signature does not match"),
+            Rejection::ForgedSite(ref site) => write!(f, "it claims to come from {:?}", site),
+            Rejection::NotAllowed(role) => write!(f, "the sender is only a {}", role),
+        }
+    }
+}
+
+/// Checks an operation `peer` sent for `path`, where the role table gives it
+/// `role`, before it may reach `implement_operation`.
+pub fn verify(keys: &SigningKeys, peer: &PeerId, path: &str, signed: &SignedOperation, role: Role) -> Result<(), Rejection> {
+    let key = match keys.get(peer) {
+        Some(key) => key,
+        None => return Err(Rejection::UnknownKey),
+    };
+    if !sign::verify_detached(&signed.signature, &signed_bytes(path, &signed.operation), key) {
+        return Err(Rejection::BadSignature);
+    }
+    let site = match signed.operation {
+        Operation::Insert { ref from_site, .. } => from_site,
+        Operation::Delete { ref from_site, .. } => from_site,
+    };
+    if site != peer {
+        return Err(Rejection::ForgedSite(site.clone()));
+    }
+    if !role.allows(action_on(path, Action::Edit)) {
+        return Err(Rejection::NotAllowed(role));
+    }
+    Ok(())
+}
+
+/// Checks that `signer`, who the notice says sent it, did sign it.
+pub fn verify_notice<T: Encodable>(keys: &SigningKeys, signer: &PeerId, signed: &Signed<T>) -> Result<(), Rejection> {
+    let key = match keys.get(signer) {
+        Some(key) => key,
+        None => return Err(Rejection::UnknownKey),
+    };
+    if !sign::verify_detached(&signed.signature, &notice_bytes(&signed.notice), key) {
+        return Err(Rejection::BadSignature);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::env;
+    use std::fs;
+    use crust::PeerId;
+    use rand::random;
+    use permission::acl::{Role, ACL_FILE};
+    use permission::grants::Grant;
+    use woot::operation::Operation;
+    use woot::woot_char::WootChar;
+    use woot::char_id::{CharId, create_char_id};
+
+    fn insert_from(site: PeerId) -> Operation {
+        let w_char = WootChar::new(create_char_id(site.clone(), 1), 'x', CharId::Beginning, CharId::Ending);
+        Operation::Insert { w_char: w_char, from_site: site }
+    }
+
+    #[test]
+    fn only_signed_edits_from_editors_pass(){
+        let peer: PeerId = random();
+        let signer = Signer::generate();
+        let mut keys = SigningKeys::new();
+        let signed = signer.sign("a.c", insert_from(peer.clone()));
+        assert_eq!(verify(&keys, &peer, "a.c", &signed, Role::Editor), Err(Rejection::UnknownKey));
+
+        assert!(keys.insert(peer.clone(), signer.public_key()));
+        assert!(!keys.insert(peer.clone(), Signer::generate().public_key()));
+        assert_eq!(verify(&keys, &peer, "a.c", &signed, Role::Editor), Ok(()));
+        assert_eq!(verify(&keys, &peer, "b.c", &signed, Role::Editor), Err(Rejection::BadSignature));
+        assert_eq!(verify(&keys, &peer, "a.c", &signed, Role::Viewer), Err(Rejection::NotAllowed(Role::Viewer)));
+
+        let other: PeerId = random();
+        let forged = signer.sign("a.c", insert_from(other.clone()));
+        assert_eq!(verify(&keys, &peer, "a.c", &forged, Role::Editor), Err(Rejection::ForgedSite(other)));
+    }
+
+    #[test]
+    fn editors_may_not_edit_the_acl(){
+        let peer: PeerId = random();
+        let signer = Signer::generate();
+        let mut keys = SigningKeys::new();
+        keys.insert(peer.clone(), signer.public_key());
+        let signed = signer.sign(ACL_FILE, insert_from(peer.clone()));
+        assert_eq!(verify(&keys, &peer, ACL_FILE, &signed, Role::Editor), Err(Rejection::NotAllowed(Role::Editor)));
+        assert_eq!(verify(&keys, &peer, ACL_FILE, &signed, Role::Owner), Ok(()));
+    }
+
+    #[test]
+    fn forged_grants_are_rejected(){
+        let editor: PeerId = random();
+        let viewer: PeerId = random();
+        let editor_signer = Signer::generate();
+        let viewer_signer = Signer::generate();
+        let mut keys = SigningKeys::new();
+        keys.insert(editor.clone(), editor_signer.public_key());
+        keys.insert(viewer.clone(), viewer_signer.public_key());
+        let grant = Grant { holder: viewer.clone(), granted_by: editor.clone(), expires: None };
+
+        let real = editor_signer.sign_notice(grant.clone());
+        assert_eq!(verify_notice(&keys, &editor, &real), Ok(()));
+        // The viewer claims the editor granted it rights.
+        let forged = viewer_signer.sign_notice(grant.clone());
+        assert_eq!(verify_notice(&keys, &editor, &forged), Err(Rejection::BadSignature));
+        let mut altered = real.clone();
+        altered.notice.expires = Some(0);
+        assert_eq!(verify_notice(&keys, &editor, &altered), Err(Rejection::BadSignature));
+        assert_eq!(verify_notice(&keys, &random(), &real), Err(Rejection::UnknownKey));
+    }
+
+    #[test]
+    fn kept_key_is_loaded_again(){
+        let n: u32 = random();
+        let dir = env::temp_dir().join(format!("p2p3_key_{}", n));
+        let path = dir.join("signing-key");
+        let first = Signer::load_or_generate(&path).unwrap();
+        let again = Signer::load_or_generate(&path).unwrap();
+        assert_eq!(again.public_key(), first.public_key());
+        let peer: PeerId = random();
+        let mut keys = SigningKeys::new();
+        keys.insert(peer.clone(), first.public_key());
+        let signed = again.sign("a.c", insert_from(peer.clone()));
+        assert_eq!(verify(&keys, &peer, "a.c", &signed, Role::Editor), Ok(()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
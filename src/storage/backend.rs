@@ -0,0 +1,401 @@
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::Arc;
+use git2;
+use git2::{ErrorCode, Repository};
+use storage::storage_helper::{GitAccess, Pull, PullKind};
+use storage::history::{LogEntry, file_log, file_at};
+use storage::file_tree::{list_files, is_text_file};
+
+#[derive(Debug)]
+pub enum StorageError {
+    Git(git2::Error),
+    Io(io::Error),
+    // The backend has no notion of this, e.g. history of a plain directory.
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StorageError::Git(ref e) => write!(f, "git error: {}", e),
+            StorageError::Io(ref e) => write!(f, "{}", e),
+            StorageError::Unsupported(what) => write!(f, "{} is not supported by this storage", what),
+        }
+    }
+}
+
+impl From<git2::Error> for StorageError {
+    fn from(e: git2::Error) -> StorageError {
+        StorageError::Git(e)
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> StorageError {
+        StorageError::Io(e)
+    }
+}
+
+/// What saving the session's files amounted to.
+pub struct Saved {
+    // The new commit, None when the backend keeps no versions.
+    pub sha: Option<String>,
+    // What had to be pulled before the push went through.
+    pub pulled: Option<Pull>,
+    // False when the pull stopped on conflicts, the commit is only local then.
+    pub pushed: bool,
+}
+
+/// Where the session's files come from and go to. Paths are relative to
+/// `root`, which is where the live documents are flushed before a save.
+pub trait StorageBackend {
+    fn root(&self) -> &str;
+    /// Gets the files in place, cloning or creating what is missing.
+    fn prepare(&self) -> Result<(), StorageError>;
+    /// The text files a session can open.
+    fn files(&self) -> Result<Vec<String>, StorageError>;
+    fn load(&self, path: &str) -> Result<String, StorageError> {
+        let mut s = String::new();
+        try!(try!(File::open(Path::new(self.root()).join(path))).read_to_string(&mut s));
+        Ok(s)
+    }
+    /// Records `paths`, already written under `root`, as a new version.
+    fn save(&self, paths: &[String], message: &str) -> Result<Saved, StorageError>;
+    fn history(&self, path: &str, limit: usize) -> Result<Vec<LogEntry>, StorageError>;
+    fn load_version(&self, path: &str, rev: &str) -> Result<String, StorageError>;
+    /// Brings in changes made elsewhere, None when there is nowhere to pull from.
+    fn pull(&self) -> Result<Option<Pull>, StorageError>;
+    /// Publishes the session config written at `path` so other peers find it.
+    fn share_config(&self, path: &str, message: &str) -> Result<(), StorageError>;
+}
+
+pub type SharedStorage = Arc<StorageBackend + Send + Sync>;
+
+/// A clone of a hosted repository, saves are pushed.
+pub struct GitRemote {
+    ga: GitAccess,
+}
+
+impl GitRemote {
+    pub fn new(ga: GitAccess) -> GitRemote {
+        GitRemote { ga: ga }
+    }
+}
+
+impl StorageBackend for GitRemote {
+    fn root(&self) -> &str {
+        &self.ga.local_url
+    }
+
+    fn prepare(&self) -> Result<(), StorageError> {
+        if Repository::open(Path::new(&self.ga.local_url)).is_ok() {
+            println!("The folder already exists, fetching");
+            try!(self.ga.fetch());
+        } else {
+            try!(self.ga.clone_repo());
+        }
+        Ok(())
+    }
+
+    fn files(&self) -> Result<Vec<String>, StorageError> {
+        Ok(try!(list_files(&self.ga.local_url)))
+    }
+
+    // A push rejected as not fast forward is retried once after pulling.
+    fn save(&self, paths: &[String], message: &str) -> Result<Saved, StorageError> {
+        let oid = try!(self.ga.commit_paths(paths, message));
+        let mut saved = Saved { sha: Some(format!("{}", oid)), pulled: None, pushed: true };
+        match self.ga.push() {
+            Ok(()) => {},
+            Err(ref e) if e.code() == ErrorCode::NotFastForward => {
+                println!("Push rejected, pulling before retrying");
+                let pull = try!(self.ga.pull_repo());
+                if pull.kind == PullKind::Conflicts {
+                    saved.pushed = false;
+                } else {
+                    try!(self.ga.push());
+                    // A merge puts its own commit on top of ours.
+                    saved.sha = Some(try!(self.ga.head_sha()));
+                }
+                saved.pulled = Some(pull);
+            },
+            Err(e) => return Err(StorageError::Git(e)),
+        }
+        Ok(saved)
+    }
+
+    fn history(&self, path: &str, limit: usize) -> Result<Vec<LogEntry>, StorageError> {
+        // The other peers' commits only reach us through origin.
+        if let Err(e) = self.ga.fetch() {
+            println!("Could not fetch: {}", e);
+        }
+        Ok(try!(file_log(&self.ga, path, limit)))
+    }
+
+    fn load_version(&self, path: &str, rev: &str) -> Result<String, StorageError> {
+        Ok(try!(file_at(&self.ga, rev, path)))
+    }
+
+    fn pull(&self) -> Result<Option<Pull>, StorageError> {
+        Ok(Some(try!(self.ga.pull_repo())))
+    }
+
+    fn share_config(&self, path: &str, message: &str) -> Result<(), StorageError> {
+        try!(self.ga.commit_config(message, path));
+        try!(self.ga.push());
+        Ok(())
+    }
+}
+
+/// A git repository on this machine only, created if missing. Saves are
+/// commits that never leave it.
+pub struct LocalGit {
+    ga: GitAccess,
+}
+
+impl LocalGit {
+    pub fn new(ga: GitAccess) -> LocalGit {
+        LocalGit { ga: ga }
+    }
+}
+
+impl StorageBackend for LocalGit {
+    fn root(&self) -> &str {
+        &self.ga.local_url
+    }
+
+    fn prepare(&self) -> Result<(), StorageError> {
+        if Repository::open(Path::new(&self.ga.local_url)).is_err() {
+            try!(fs::create_dir_all(&self.ga.local_url));
+            try!(Repository::init(Path::new(&self.ga.local_url)));
+        }
+        Ok(())
+    }
+
+    fn files(&self) -> Result<Vec<String>, StorageError> {
+        Ok(try!(list_files(&self.ga.local_url)))
+    }
+
+    fn save(&self, paths: &[String], message: &str) -> Result<Saved, StorageError> {
+        let oid = try!(self.ga.commit_paths(paths, message));
+        Ok(Saved { sha: Some(format!("{}", oid)), pulled: None, pushed: false })
+    }
+
+    fn history(&self, path: &str, limit: usize) -> Result<Vec<LogEntry>, StorageError> {
+        match file_log(&self.ga, path, limit) {
+            Ok(entries) => Ok(entries),
+            // Nothing committed yet.
+            Err(ref e) if e.code() == ErrorCode::UnbornBranch => Ok(vec![]),
+            Err(e) => Err(StorageError::Git(e)),
+        }
+    }
+
+    fn load_version(&self, path: &str, rev: &str) -> Result<String, StorageError> {
+        Ok(try!(file_at(&self.ga, rev, path)))
+    }
+
+    fn pull(&self) -> Result<Option<Pull>, StorageError> {
+        Ok(None)
+    }
+
+    fn share_config(&self, path: &str, message: &str) -> Result<(), StorageError> {
+        Ok(try!(self.ga.commit_config(message, path)))
+    }
+}
+
+/// Just a directory, for quick sessions. Saving only writes the files.
+pub struct PlainDirectory {
+    root: String,
+}
+
+impl PlainDirectory {
+    pub fn new(root: String) -> PlainDirectory {
+        PlainDirectory { root: root }
+    }
+}
+
+fn walk(root: &Path, dir: &Path, files: &mut Vec<String>) -> io::Result<()> {
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        let hidden = path.file_name().map_or(false, |n| n.to_string_lossy().starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if path.is_dir() {
+            try!(walk(root, &path, files));
+        } else if is_text_file(&path) {
+            if let Ok(relative) = path.strip_prefix(root) {
+                files.push(relative.to_string_lossy().into_owned());
+            }
+        }
+    }
+    Ok(())
+}
+
+impl StorageBackend for PlainDirectory {
+    fn root(&self) -> &str {
+        &self.root
+    }
+
+    fn prepare(&self) -> Result<(), StorageError> {
+        Ok(try!(fs::create_dir_all(&self.root)))
+    }
+
+    fn files(&self) -> Result<Vec<String>, StorageError> {
+        let mut files = vec![];
+        let root = Path::new(&self.root);
+        try!(walk(root, root, &mut files));
+        files.sort();
+        Ok(files)
+    }
+
+    fn save(&self, _: &[String], _: &str) -> Result<Saved, StorageError> {
+        Ok(Saved { sha: None, pulled: None, pushed: false })
+    }
+
+    fn history(&self, _: &str, _: usize) -> Result<Vec<LogEntry>, StorageError> {
+        Ok(vec![])
+    }
+
+    fn load_version(&self, _: &str, _: &str) -> Result<String, StorageError> {
+        Err(StorageError::Unsupported("history"))
+    }
+
+    fn pull(&self) -> Result<Option<Pull>, StorageError> {
+        Ok(None)
+    }
+
+    fn share_config(&self, _: &str, _: &str) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::path::Path;
+    use git2::Repository;
+    use rand::random;
+    use storage::storage_helper::GitAccess;
+    use storage::credentials::CredentialProvider;
+
+    fn temp_dir(name: &str) -> String {
+        let n: u32 = random();
+        format!("{}/", env::temp_dir().join(format!("p2p3_{}_{}", name, n)).display())
+    }
+
+    #[test]
+    fn saves_reach_a_local_bare_remote(){
+        let remote = temp_dir("remote");
+        let bare = Repository::init_bare(Path::new(&remote)).unwrap();
+        let local = temp_dir("clone");
+        let ga = GitAccess::new(remote.clone(), local.clone(), "a.txt".to_string(), CredentialProvider::none());
+        let storage = GitRemote::new(ga);
+        storage.prepare().unwrap();
+        {
+            let repo = Repository::open(Path::new(&local)).unwrap();
+            let mut config = repo.config().unwrap();
+            config.set_str("user.name", "Tester").unwrap();
+            config.set_str("user.email", "tester@example.com").unwrap();
+        }
+        File::create(Path::new(&local).join("a.txt")).unwrap().write_all(b"hello\n").unwrap();
+
+        let saved = storage.save(&["a.txt".to_string()], "first").unwrap();
+        assert!(saved.pushed);
+        let pushed = format!("{}", bare.refname_to_id("refs/heads/master").unwrap());
+        assert_eq!(saved.sha, Some(pushed));
+        assert_eq!(storage.load("a.txt").unwrap(), "hello\n");
+        assert_eq!(storage.history("a.txt", 10).unwrap().len(), 1);
+        fs::remove_dir_all(&remote).unwrap();
+        fs::remove_dir_all(&local).unwrap();
+    }
+
+    fn write(dir: &str, name: &str, content: &str) {
+        File::create(Path::new(dir).join(name)).unwrap().write_all(content.as_bytes()).unwrap();
+    }
+
+    fn read(dir: &str, name: &str) -> String {
+        let mut s = String::new();
+        File::open(Path::new(dir).join(name)).unwrap().read_to_string(&mut s).unwrap();
+        s
+    }
+
+    fn clone_of(remote: &str) -> (String, GitRemote) {
+        let local = temp_dir("clone");
+        let ga = GitAccess::new(remote.to_string(), local.clone(), "a.txt".to_string(), CredentialProvider::none());
+        let storage = GitRemote::new(ga);
+        storage.prepare().unwrap();
+        let repo = Repository::open(Path::new(&local)).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Tester").unwrap();
+        config.set_str("user.email", "tester@example.com").unwrap();
+        (local, storage)
+    }
+
+    #[test]
+    fn pulls_keep_local_changes_and_deletions(){
+        let remote = temp_dir("remote");
+        let bare = Repository::init_bare(Path::new(&remote)).unwrap();
+        let (first, one) = clone_of(&remote);
+        write(&first, "a.txt", "one\ntwo\nthree\n");
+        write(&first, "b.txt", "bye\n");
+        one.save(&["a.txt".to_string(), "b.txt".to_string()], "first").unwrap();
+        let (second, two) = clone_of(&remote);
+
+        write(&first, "a.txt", "one\ntwo\nTHREE\n");
+        fs::remove_file(Path::new(&first).join("b.txt")).unwrap();
+        {
+            let repo = Repository::open(Path::new(&first)).unwrap();
+            let mut index = repo.index().unwrap();
+            index.remove_path(Path::new("b.txt")).unwrap();
+            index.write().unwrap();
+        }
+        one.save(&["a.txt".to_string()], "second").unwrap();
+
+        // Not committed, not open in any session.
+        write(&second, "a.txt", "ONE\ntwo\nthree\n");
+        let pull = two.pull().unwrap().unwrap();
+        assert_eq!(pull.kind, PullKind::FastForward);
+        assert_eq!(read(&second, "a.txt"), "ONE\ntwo\nTHREE\n");
+        assert!(!Path::new(&second).join("b.txt").exists());
+        assert!(pull.files.iter().any(|f| f.path == "b.txt" && f.deleted));
+        assert!(pull.conflicts().is_empty());
+
+        // Pushed over a newer origin, the sha is the merge that went out.
+        write(&first, "c.txt", "c\n");
+        one.save(&["c.txt".to_string()], "third").unwrap();
+        write(&second, "d.txt", "d\n");
+        let saved = two.save(&["d.txt".to_string()], "fourth").unwrap();
+        assert!(saved.pushed && saved.pulled.is_some());
+        let pushed = format!("{}", bare.refname_to_id("refs/heads/master").unwrap());
+        assert_eq!(saved.sha, Some(pushed));
+        assert_eq!(read(&second, "a.txt"), "ONE\ntwo\nTHREE\n");
+
+        fs::remove_dir_all(&remote).unwrap();
+        fs::remove_dir_all(&first).unwrap();
+        fs::remove_dir_all(&second).unwrap();
+    }
+
+    #[test]
+    fn plain_directory_lists_text_files(){
+        let root = temp_dir("plain");
+        let storage = PlainDirectory::new(root.clone());
+        storage.prepare().unwrap();
+        fs::create_dir_all(Path::new(&root).join("src")).unwrap();
+        File::create(Path::new(&root).join("src/main.c")).unwrap().write_all(b"int main(){}\n").unwrap();
+        File::create(Path::new(&root).join("blob.bin")).unwrap().write_all(b"\0\0").unwrap();
+        File::create(Path::new(&root).join(".hidden")).unwrap().write_all(b"x").unwrap();
+        assert_eq!(storage.files().unwrap(), vec!["src/main.c".to_string()]);
+        assert!(storage.save(&["src/main.c".to_string()], "save").unwrap().sha.is_none());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::{BTreeMap, BTreeSet};
+use crust::PeerId;
+
+/// A git identity, as announced by a peer when it joins.
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Author {
+    pub name: String,
+    pub email: String,
+}
+
+impl Author {
+    pub fn new(name: &str, email: &str) -> Author {
+        Author {
+            name: name.to_string(),
+            email: email.to_string(),
+        }
+    }
+
+    pub fn trailer(&self) -> String {
+        format!("Co-authored-by: {} <{}>", self.name, self.email)
+    }
+}
+
+/// The identities of the peers in the session.
+pub struct Authors {
+    known: BTreeMap<PeerId, Author>,
+    // Peers that proved their identity, the others only claim it.
+    verified: BTreeSet<PeerId>,
+}
+
+impl Authors {
+    pub fn new() -> Authors {
+        Authors { known: BTreeMap::new(), verified: BTreeSet::new() }
+    }
+
+    /// Returns true the first time we hear from `peer`.
+    pub fn insert(&mut self, peer: PeerId, author: Author) -> bool {
+        self.known.insert(peer, author).is_none()
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<&Author> {
+        self.known.get(peer)
+    }
+
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.known.remove(peer);
+        self.verified.remove(peer);
+    }
+
+    pub fn set_verified(&mut self, peer: PeerId, verified: bool) {
+        if verified {
+            self.verified.insert(peer);
+        } else {
+            self.verified.remove(&peer);
+        }
+    }
+
+    pub fn is_verified(&self, peer: &PeerId) -> bool {
+        self.verified.contains(peer)
+    }
+
+    /// The authors of `peers`, skipping the ones that never announced themselves.
+    pub fn lookup(&self, peers: &[PeerId]) -> Vec<Author> {
+        peers.iter().filter_map(|p| self.get(p)).cloned().collect()
+    }
+}
+
+/// `message` followed by a trailer for every contributor other than the
+/// committer, each email only once.
+pub fn with_trailers(message: &str, committer: &Author, contributors: &[Author]) -> String {
+    let mut emails = vec![committer.email.clone()];
+    let mut trailers = vec![];
+    for author in contributors {
+        if !emails.contains(&author.email) {
+            emails.push(author.email.clone());
+            trailers.push(author.trailer());
+        }
+    }
+    let message = message.trim();
+    if trailers.is_empty() {
+        return message.to_string();
+    }
+    format!("{}\n\n{}\n", message, trailers.join("\n"))
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+
+    #[test]
+    fn trailers_skip_committer_and_duplicates(){
+        let alice = Author::new("Alice", "alice@example.com");
+        let bob = Author::new("Bob", "bob@example.com");
+        let msg = with_trailers("Fix parser\n", &alice, &[alice.clone(), bob.clone(), bob.clone()]);
+        assert_eq!(msg, "Fix parser\n\nCo-authored-by: Bob <bob@example.com>\n");
+        assert_eq!(with_trailers("Solo", &alice, &[alice.clone()]), "Solo");
+    }
+}
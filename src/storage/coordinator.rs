@@ -0,0 +1,155 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use crust::PeerId;
+use storage::storage_helper::Pull;
+use storage::backend::{StorageBackend, StorageError};
+use storage::workspace::{Workspace, SyncStatus};
+
+/// Makes sure only one node of the session commits and pushes at a time.
+///
+/// The committer is the peer with the lowest id among the connected ones that
+/// can push, so every peer agrees on it without an extra round of messages.
+/// Other peers forward their commit requests to it, and it reports back the
+/// new sha.
+#[derive(Clone)]
+pub struct CommitCoordinator {
+    in_progress: Arc<Mutex<()>>,
+    // Peers that said they can push and that the ACL lets commit.
+    pushers: Arc<Mutex<BTreeSet<PeerId>>>,
+}
+
+pub struct CommitOutcome {
+    // None when the storage keeps no versions.
+    pub sha: Option<String>,
+    // Files whose outside changes were overwritten.
+    pub overwritten: Vec<String>,
+    // What had to be pulled before the push went through.
+    pub pulled: Option<Pull>,
+    // False when the pull stopped on conflicts, the commit is only local then.
+    pub pushed: bool,
+}
+
+/// The lowest id among `me` and `peers` that is one of the `pushers`, None
+/// when none of them can push.
+pub fn elect_committer(me: &PeerId, peers: &[PeerId], pushers: &BTreeSet<PeerId>) -> Option<PeerId> {
+    let mut committer = None;
+    for p in Some(me).into_iter().chain(peers) {
+        if pushers.contains(p) && committer.as_ref().map_or(true, |c| p < c) {
+            committer = Some(p.clone());
+        }
+    }
+    committer
+}
+
+impl CommitCoordinator {
+    pub fn new() -> CommitCoordinator {
+        CommitCoordinator {
+            in_progress: Arc::new(Mutex::new(())),
+            pushers: Arc::new(Mutex::new(BTreeSet::new())),
+        }
+    }
+
+    /// Records whether `peer` can push, only those are elected.
+    pub fn set_can_push(&self, peer: PeerId, can_push: bool) {
+        let mut pushers = self.pushers.lock().unwrap();
+        if can_push {
+            pushers.insert(peer);
+        } else {
+            pushers.remove(&peer);
+        }
+    }
+
+    pub fn committer(&self, me: &PeerId, peers: &[PeerId]) -> Option<PeerId> {
+        elect_committer(me, peers, &self.pushers.lock().unwrap())
+    }
+
+    pub fn is_committer(&self, me: &PeerId, peers: &[PeerId]) -> bool {
+        self.committer(me, peers).as_ref() == Some(me)
+    }
+
+    /// Pulls changes made elsewhere, without racing a commit.
+    pub fn pull(&self, storage: &StorageBackend) -> Result<Option<Pull>, StorageError> {
+        let _guard = self.in_progress.lock().unwrap();
+        storage.pull()
+    }
+
+    /// Commits and pushes the session's config file, without racing a commit.
+    pub fn share_config(&self, storage: &StorageBackend, path: &str, message: &str) -> Result<(), StorageError> {
+        let _guard = self.in_progress.lock().unwrap();
+        storage.share_config(path, message)
+    }
+
+    /// Flushes every open document to the working tree and saves them.
+    pub fn commit(&self, storage: &StorageBackend, files: &[(String, String, Arc<Mutex<Workspace>>)], message: &str)
+        -> Result<CommitOutcome, StorageError>
+    {
+        let _guard = self.in_progress.lock().unwrap();
+
+        let mut overwritten = vec![];
+        let mut paths = vec![];
+        for &(ref path, ref content, ref workspace) in files {
+            // What the session agreed on wins over edits made behind its back.
+            match workspace.lock().unwrap().flush(content, true) {
+                Ok(SyncStatus::OverwroteExternalChange) => overwritten.push(path.clone()),
+                Ok(_) => {},
+                Err(e) => return Err(StorageError::Io(e)),
+            }
+            paths.push(path.clone());
+        }
+
+        let saved = try!(storage.save(&paths, message));
+        Ok(CommitOutcome {
+            sha: saved.sha,
+            overwritten: overwritten,
+            pulled: saved.pulled,
+            pushed: saved.pushed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::collections::BTreeSet;
+    use crust::PeerId;
+    use rand::random;
+
+    #[test]
+    fn everyone_elects_the_same_committer(){
+        let ids: Vec<PeerId> = (0..5).map(|_| random()).collect();
+        let pushers: BTreeSet<PeerId> = ids.iter().cloned().collect();
+        let elected: Vec<Option<PeerId>> = ids.iter().map(|me| {
+            let others: Vec<PeerId> = ids.iter().filter(|p| *p != me).cloned().collect();
+            elect_committer(me, &others, &pushers)
+        }).collect();
+        for e in elected.iter() {
+            assert_eq!(*e, elected[0]);
+        }
+        let coordinator = CommitCoordinator::new();
+        for id in ids.iter() {
+            coordinator.set_can_push(id.clone(), true);
+        }
+        assert!(coordinator.is_committer(elected[0].as_ref().unwrap(), &ids));
+    }
+
+    #[test]
+    fn alone_we_commit(){
+        let me: PeerId = random();
+        let coordinator = CommitCoordinator::new();
+        coordinator.set_can_push(me.clone(), true);
+        assert!(coordinator.is_committer(&me, &[]));
+    }
+
+    #[test]
+    fn peers_that_cant_push_are_never_elected(){
+        let mut ids: Vec<PeerId> = (0..3).map(|_| random()).collect();
+        ids.sort();
+        let coordinator = CommitCoordinator::new();
+        assert_eq!(coordinator.committer(&ids[0], &ids[1..]), None);
+        coordinator.set_can_push(ids[2].clone(), true);
+        coordinator.set_can_push(ids[1].clone(), true);
+        assert_eq!(coordinator.committer(&ids[0], &ids[1..]), Some(ids[1].clone()));
+        coordinator.set_can_push(ids[1].clone(), false);
+        assert_eq!(coordinator.committer(&ids[2], &ids[..2]), Some(ids[2].clone()));
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+
+/// Lines of `old` from `old_start` replaced by lines of `new` from `new_start`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+}
+
+/// The result of a three-way merge. Conflicting regions are kept in the text
+/// between git style markers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Merged {
+    pub text: String,
+    pub conflicts: usize,
+}
+
+/// Splits after every newline, so joining the lines gives back `s`.
+pub fn split_lines(s: &str) -> Vec<&str> {
+    let mut lines = vec![];
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        if c == '\n' {
+            lines.push(&s[start..i + 1]);
+            start = i + 1;
+        }
+    }
+    if start < s.len() {
+        lines.push(&s[start..]);
+    }
+    lines
+}
+
+// Past this many edits between two stretches of lines, they are left
+// unmatched rather than searched further.
+const MAX_EDIT_COST: isize = 4096;
+
+// For every line of `a`, the line of `b` it is matched with in a longest
+// common subsequence. Lines only one side has can't be part of it and are
+// set aside, the rest is compared as numbers with Myers' bisection, in memory
+// linear in the number of lines.
+fn matches(a: &[&str], b: &[&str]) -> Vec<Option<usize>> {
+    let mut ids = HashMap::new();
+    let a_ids: Vec<usize> = a.iter().map(|line| intern(&mut ids, line)).collect();
+    let from_a = ids.len();
+    let b_ids: Vec<usize> = b.iter().map(|line| intern(&mut ids, line)).collect();
+    let mut in_b = vec![false; from_a];
+    for &id in &b_ids {
+        if id < from_a {
+            in_b[id] = true;
+        }
+    }
+    let a_kept: Vec<usize> = (0..a.len()).filter(|&i| in_b[a_ids[i]]).collect();
+    let b_kept: Vec<usize> = (0..b.len()).filter(|&j| b_ids[j] < from_a).collect();
+    let a_shared: Vec<usize> = a_kept.iter().map(|&i| a_ids[i]).collect();
+    let b_shared: Vec<usize> = b_kept.iter().map(|&j| b_ids[j]).collect();
+    let mut shared = vec![None; a_shared.len()];
+    match_range(&a_shared, &b_shared, 0, 0, &mut shared);
+
+    let mut result = vec![None; a.len()];
+    for (k, m) in shared.into_iter().enumerate() {
+        result[a_kept[k]] = m.map(|j| b_kept[j]);
+    }
+    result
+}
+
+fn intern<'a>(ids: &mut HashMap<&'a str, usize>, line: &'a str) -> usize {
+    let next = ids.len();
+    *ids.entry(line).or_insert(next)
+}
+
+// Matches `a` against `b`, where `a` starts at line `a0` and `b` at `b0` of
+// the whole files.
+fn match_range(a: &[usize], b: &[usize], a0: usize, b0: usize, result: &mut Vec<Option<usize>>) {
+    let mut start = 0;
+    while start < a.len() && start < b.len() && a[start] == b[start] {
+        result[a0 + start] = Some(b0 + start);
+        start += 1;
+    }
+    let mut end = 0;
+    while end < a.len() - start && end < b.len() - start && a[a.len() - 1 - end] == b[b.len() - 1 - end] {
+        result[a0 + a.len() - 1 - end] = Some(b0 + b.len() - 1 - end);
+        end += 1;
+    }
+    let (a, b) = (&a[start..a.len() - end], &b[start..b.len() - end]);
+    if a.is_empty() || b.is_empty() {
+        return;
+    }
+    if let Some((x, y)) = bisect(a, b) {
+        match_range(&a[..x], &b[..y], a0 + start, b0 + start, result);
+        match_range(&a[x..], &b[y..], a0 + start + x, b0 + start + y, result);
+    }
+}
+
+// Where the forward and backward searches for a shortest edit script meet,
+// None if `a` and `b` have no line in common or are too far apart.
+fn bisect(a: &[usize], b: &[usize]) -> Option<(usize, usize)> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max_d = ::std::cmp::min((n + m + 1) / 2, MAX_EDIT_COST);
+    let offset = max_d;
+    let width = 2 * max_d + 2;
+    // Furthest x reached on each diagonal k = x - y, from the start and from the end.
+    let mut forward = vec![-1isize; width as usize];
+    let mut backward = vec![-1isize; width as usize];
+    forward[(offset + 1) as usize] = 0;
+    backward[(offset + 1) as usize] = 0;
+    let delta = n - m;
+    // With an odd delta the paths meet while extending the forward one.
+    let odd = delta % 2 != 0;
+    let (mut k1_start, mut k1_end, mut k2_start, mut k2_end) = (0, 0, 0, 0);
+    for d in 0..max_d {
+        let mut k1 = -d + k1_start;
+        while k1 <= d - k1_end {
+            let i = (offset + k1) as usize;
+            let mut x = if k1 == -d || (k1 != d && forward[i - 1] < forward[i + 1]) {
+                forward[i + 1]
+            } else {
+                forward[i - 1] + 1
+            };
+            let mut y = x - k1;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[i] = x;
+            if x > n {
+                k1_end += 2;
+            } else if y > m {
+                k1_start += 2;
+            } else if odd {
+                let j = offset + delta - k1;
+                if j >= 0 && j < width && backward[j as usize] != -1 && x >= n - backward[j as usize] {
+                    return Some((x as usize, y as usize));
+                }
+            }
+            k1 += 2;
+        }
+        let mut k2 = -d + k2_start;
+        while k2 <= d - k2_end {
+            let i = (offset + k2) as usize;
+            let mut x = if k2 == -d || (k2 != d && backward[i - 1] < backward[i + 1]) {
+                backward[i + 1]
+            } else {
+                backward[i - 1] + 1
+            };
+            let mut y = x - k2;
+            while x < n && y < m && a[(n - x - 1) as usize] == b[(m - y - 1) as usize] {
+                x += 1;
+                y += 1;
+            }
+            backward[i] = x;
+            if x > n {
+                k2_end += 2;
+            } else if y > m {
+                k2_start += 2;
+            } else if !odd {
+                let j = offset + delta - k2;
+                if j >= 0 && j < width && forward[j as usize] != -1 {
+                    let fx = forward[j as usize];
+                    let fy = offset + fx - j;
+                    if fx >= n - x {
+                        return Some((fx as usize, fy as usize));
+                    }
+                }
+            }
+            k2 += 2;
+        }
+    }
+    None
+}
+
+/// The line hunks turning `old` into `new`, in order.
+pub fn diff_lines(old: &str, new: &str) -> Vec<Hunk> {
+    let a = split_lines(old);
+    let b = split_lines(new);
+    let m = matches(&a, &b);
+    let mut hunks = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && m[i] == Some(j) {
+            i += 1;
+            j += 1;
+            continue;
+        }
+        let (start_i, start_j) = (i, j);
+        while i < a.len() && m[i].is_none() {
+            i += 1;
+        }
+        j = if i < a.len() { m[i].unwrap() } else { b.len() };
+        hunks.push(Hunk { old_start: start_i, old_len: i - start_i, new_start: start_j, new_len: j - start_j });
+    }
+    hunks
+}
+
+/// Merges the changes `ours` and `theirs` made to `base`, line by line.
+pub fn merge3(base: &str, ours: &str, theirs: &str) -> Merged {
+    let b = split_lines(base);
+    let o = split_lines(ours);
+    let t = split_lines(theirs);
+    let mo = matches(&b, &o);
+    let mt = matches(&b, &t);
+
+    let mut text = String::new();
+    let mut conflicts = 0;
+    let (mut i, mut oi, mut ti) = (0, 0, 0);
+    while i < b.len() || oi < o.len() || ti < t.len() {
+        if i < b.len() && mo[i] == Some(oi) && mt[i] == Some(ti) {
+            text.push_str(b[i]);
+            i += 1;
+            oi += 1;
+            ti += 1;
+            continue;
+        }
+        // The next base line both sides kept ends the unstable chunk.
+        let mut end = i;
+        while end < b.len() && !(mo[end].is_some() && mt[end].is_some()) {
+            end += 1;
+        }
+        let o_end = if end < b.len() { mo[end].unwrap() } else { o.len() };
+        let t_end = if end < b.len() { mt[end].unwrap() } else { t.len() };
+        let (base_chunk, ours_chunk, theirs_chunk) = (&b[i..end], &o[oi..o_end], &t[ti..t_end]);
+        if ours_chunk == base_chunk || ours_chunk == theirs_chunk {
+            text.push_str(&theirs_chunk.concat());
+        } else if theirs_chunk == base_chunk {
+            text.push_str(&ours_chunk.concat());
+        } else {
+            conflicts += 1;
+            let ours_text = ours_chunk.concat();
+            let theirs_text = theirs_chunk.concat();
+            text.push_str("<<<<<<< ours\n");
+            text.push_str(&ours_text);
+            if !ours_text.is_empty() && !ours_text.ends_with('\n') {
+                text.push('\n');
+            }
+            text.push_str("=======\n");
+            text.push_str(&theirs_text);
+            if !theirs_text.is_empty() && !theirs_text.ends_with('\n') {
+                text.push('\n');
+            }
+            text.push_str(">>>>>>> theirs\n");
+        }
+        i = end;
+        oi = o_end;
+        ti = t_end;
+    }
+    Merged { text: text, conflicts: conflicts }
+}
+
+const CONTEXT: usize = 3;
+
+fn push_line(out: &mut String, prefix: char, line: &str) {
+    out.push(prefix);
+    out.push_str(line);
+    if !line.ends_with('\n') {
+        out.push('\n');
+    }
+}
+
+/// `diff -u` style text of the changes from `old` to `new`.
+pub fn unified(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    let a = split_lines(old);
+    let b = split_lines(new);
+    let hunks = diff_lines(old, new);
+    let mut out = String::new();
+    if hunks.is_empty() {
+        return out;
+    }
+    out.push_str(&format!("--- {}\n+++ {}\n", old_name, new_name));
+    let mut g = 0;
+    while g < hunks.len() {
+        // Hunks whose context would touch are shown together.
+        let mut last = g;
+        while last + 1 < hunks.len() &&
+            hunks[last + 1].old_start <= hunks[last].old_start + hunks[last].old_len + 2 * CONTEXT {
+            last += 1;
+        }
+        let first = &hunks[g];
+        let start = first.old_start.saturating_sub(CONTEXT);
+        let end = ::std::cmp::min(a.len(), hunks[last].old_start + hunks[last].old_len + CONTEXT);
+        let new_start = start + first.new_start - first.old_start;
+        let removed: usize = hunks[g..last + 1].iter().map(|h| h.old_len).sum();
+        let added: usize = hunks[g..last + 1].iter().map(|h| h.new_len).sum();
+        let old_count = end - start;
+        out.push_str(&format!("@@ -{},{} +{},{} @@\n", start + 1, old_count, new_start + 1, old_count - removed + added));
+        let mut i = start;
+        for h in &hunks[g..last + 1] {
+            for line in &a[i..h.old_start] {
+                push_line(&mut out, ' ', line);
+            }
+            for line in &a[h.old_start..h.old_start + h.old_len] {
+                push_line(&mut out, '-', line);
+            }
+            for line in &b[h.new_start..h.new_start + h.new_len] {
+                push_line(&mut out, '+', line);
+            }
+            i = h.old_start + h.old_len;
+        }
+        for line in &a[i..end] {
+            push_line(&mut out, ' ', line);
+        }
+        g = last + 1;
+    }
+    out
+}
+
+/// `current` with the lines `start..end` (0 based, end exclusive) brought back
+/// to how they are in `old`. Changes elsewhere in the file are kept.
+pub fn revert_lines(current: &str, old: &str, start: usize, end: usize) -> String {
+    let c = split_lines(current);
+    let o = split_lines(old);
+    let mut out = String::new();
+    let mut i = 0;
+    for h in diff_lines(current, old) {
+        out.push_str(&c[i..h.old_start].concat());
+        let touches = if h.old_len == 0 {
+            start <= h.old_start && h.old_start <= end
+        } else {
+            h.old_start < end && start < h.old_start + h.old_len
+        };
+        if touches {
+            out.push_str(&o[h.new_start..h.new_start + h.new_len].concat());
+        } else {
+            out.push_str(&c[h.old_start..h.old_start + h.old_len].concat());
+        }
+        i = h.old_start + h.old_len;
+    }
+    out.push_str(&c[i..].concat());
+    out
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+
+    #[test]
+    fn diff_finds_changed_lines(){
+        let hunks = diff_lines("a\nb\nc\n", "a\nB\nc\nd\n");
+        assert_eq!(hunks, vec![
+            Hunk { old_start: 1, old_len: 1, new_start: 1, new_len: 1 },
+            Hunk { old_start: 3, old_len: 0, new_start: 3, new_len: 1 },
+        ]);
+        assert!(diff_lines("same\n", "same\n").is_empty());
+    }
+
+    #[test]
+    fn merge_takes_both_sides(){
+        let merged = merge3("a\nb\nc\n", "A\nb\nc\n", "a\nb\nC\n");
+        assert_eq!(merged, Merged { text: "A\nb\nC\n".to_string(), conflicts: 0 });
+    }
+
+    #[test]
+    fn merge_marks_conflicts(){
+        let merged = merge3("a\nb\nc\n", "a\nours\nc\n", "a\ntheirs\nc\n");
+        assert_eq!(merged.conflicts, 1);
+        assert_eq!(merged.text, "a\n<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\nc\n");
+    }
+
+    #[test]
+    fn unified_shows_context(){
+        let text = unified("a\nb\nc\n", "a\nB\nc\n", "HEAD", "live");
+        assert_eq!(text, "--- HEAD\n+++ live\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n");
+        assert_eq!(unified("same\n", "same\n", "HEAD", "live"), "");
+    }
+
+    #[test]
+    fn revert_only_touches_the_range(){
+        let old = "one\ntwo\nthree\n";
+        let current = "ONE\ntwo\nTHREE\n";
+        assert_eq!(revert_lines(current, old, 2, 3), "ONE\ntwo\nthree\n");
+        assert_eq!(revert_lines(current, old, 0, 3), old);
+    }
+
+    #[test]
+    fn long_files_diff_quickly(){
+        let old: String = (0..20000).map(|i| format!("line {}\n", i)).collect();
+        let new = old.replace("line 10000\n", "changed\n");
+        assert_eq!(diff_lines(&old, &new), vec![Hunk { old_start: 10000, old_len: 1, new_start: 10000, new_len: 1 }]);
+        let rewritten: String = (0..20000).map(|i| format!("other {}\n", i)).collect();
+        assert_eq!(diff_lines(&old, &rewritten).len(), 1);
+    }
+}
@@ -0,0 +1,81 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use git2;
+use git2::Repository;
+
+// Same heuristic as git: a NUL byte early in the file means binary.
+const TEXT_PROBE_LEN: usize = 8000;
+
+pub fn is_text_file(path: &Path) -> bool {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return false,
+    };
+    let mut probe = Vec::new();
+    match file.take(TEXT_PROBE_LEN as u64).read_to_end(&mut probe) {
+        Ok(_) => !probe.contains(&0),
+        Err(_) => false,
+    }
+}
+
+/// The tracked text files of the clone at `local_url`, relative to its root
+/// and sorted, skipping anything that matches the ignore rules.
+pub fn list_files(local_url: &str) -> Result<Vec<String>, git2::Error> {
+    let repo = try!(Repository::open(Path::new(local_url)));
+    let index = try!(repo.index());
+    let root = Path::new(local_url);
+    let mut files = vec![];
+    for entry in index.iter() {
+        let path = String::from_utf8_lossy(&entry.path).into_owned();
+        if try!(repo.is_path_ignored(Path::new(&path))) {
+            continue;
+        }
+        if is_text_file(&root.join(&path)) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::path::Path;
+    use git2::Repository;
+    use rand::random;
+
+    fn write(dir: &Path, name: &str, content: &[u8]) {
+        File::create(dir.join(name)).unwrap().write_all(content).unwrap();
+    }
+
+    #[test]
+    fn lists_tracked_text_files(){
+        let n: u32 = random();
+        let dir = env::temp_dir().join(format!("p2p3_tree_{}", n));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        let repo = Repository::init(&dir).unwrap();
+        write(&dir, "src/main.c", b"int main(){}\n");
+        write(&dir, "README.md", b"# hi\n");
+        write(&dir, "logo.png", b"\x89PNG\r\n\x1a\n\0\0\0");
+        write(&dir, "notes.log", b"ignored\n");
+        write(&dir, "untracked.c", b"int x;\n");
+        write(&dir, ".gitignore", b"*.log\n");
+        {
+            let mut index = repo.index().unwrap();
+            for p in &["src/main.c", "README.md", "logo.png", "notes.log", ".gitignore"] {
+                index.add_path(Path::new(p)).unwrap();
+            }
+            index.write().unwrap();
+        }
+        let local_url = format!("{}/", dir.display());
+        let files = list_files(&local_url).unwrap();
+        assert_eq!(files, vec![".gitignore".to_string(), "README.md".to_string(), "src/main.c".to_string()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+use std::path::Path;
+use git2;
+use git2::{Commit, Oid, Repository, Sort, Tree};
+use storage::storage_helper::{GitAccess, blob_text};
+
+/// A commit that changed the file being looked at.
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct LogEntry {
+    pub sha: String,
+    pub summary: String,
+    pub author: String,
+    // Seconds since the epoch.
+    pub time: i64,
+}
+
+fn blob_id(tree: &Tree, path: &str) -> Option<Oid> {
+    tree.get_path(Path::new(path)).ok().map(|e| e.id())
+}
+
+// Only the committer's HEAD follows the session, the other peers see the
+// session's commits through origin. Whichever is newer is the tip.
+fn tip<'r>(repo: &'r Repository, ga: &GitAccess) -> Result<Commit<'r>, git2::Error> {
+    let head = try!(try!(repo.head()).peel_to_commit());
+    let remote = match repo.revparse_single(&format!("origin/{}", ga.branch)) {
+        Ok(obj) => try!(obj.peel_to_commit()),
+        Err(_) => return Ok(head),
+    };
+    if try!(repo.graph_descendant_of(remote.id(), head.id())) {
+        Ok(remote)
+    } else {
+        Ok(head)
+    }
+}
+
+fn resolve<'r>(repo: &'r Repository, ga: &GitAccess, rev: &str) -> Result<Commit<'r>, git2::Error> {
+    if rev.is_empty() || rev == "HEAD" {
+        return tip(repo, ga);
+    }
+    let obj = try!(repo.revparse_single(rev));
+    obj.peel_to_commit()
+}
+
+/// Up to `limit` commits that changed `path`, newest first.
+pub fn file_log(ga: &GitAccess, path: &str, limit: usize) -> Result<Vec<LogEntry>, git2::Error> {
+    let repo = try!(Repository::open(Path::new(&ga.local_url)));
+    let start = try!(tip(&repo, ga));
+    let mut walk = try!(repo.revwalk());
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME);
+    try!(walk.push(start.id()));
+    let mut entries = vec![];
+    for oid in walk {
+        let commit = try!(repo.find_commit(try!(oid)));
+        let here = blob_id(&try!(commit.tree()), path);
+        let before = match commit.parent(0) {
+            Ok(parent) => blob_id(&try!(parent.tree()), path),
+            Err(_) => None,
+        };
+        if here == before {
+            continue;
+        }
+        entries.push(LogEntry {
+            sha: format!("{}", commit.id()),
+            summary: commit.summary().unwrap_or("").to_string(),
+            author: commit.author().name().unwrap_or("").to_string(),
+            time: commit.time().seconds(),
+        });
+        if entries.len() >= limit {
+            break;
+        }
+    }
+    Ok(entries)
+}
+
+/// The content of `path` at `rev`, empty if it did not exist then. An empty
+/// `rev` or "HEAD" means the latest commit of the session.
+pub fn file_at(ga: &GitAccess, rev: &str, path: &str) -> Result<String, git2::Error> {
+    let repo = try!(Repository::open(Path::new(&ga.local_url)));
+    let commit = try!(resolve(&repo, ga, rev));
+    let tree = try!(commit.tree());
+    blob_text(&repo, &tree, path)
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::path::Path;
+    use git2::{Repository, Signature};
+    use rand::random;
+    use storage::storage_helper::GitAccess;
+    use storage::credentials::CredentialProvider;
+
+    fn commit_file(repo: &Repository, dir: &Path, name: &str, content: &str, message: &str) {
+        File::create(dir.join(name)).unwrap().write_all(content.as_bytes()).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(name)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = Signature::now("Tester", "tester@example.com").unwrap();
+        let parent = repo.head().ok().map(|h| h.peel_to_commit().unwrap());
+        let parents: Vec<_> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents).unwrap();
+    }
+
+    #[test]
+    fn log_lists_commits_touching_the_file(){
+        let n: u32 = random();
+        let dir = env::temp_dir().join(format!("p2p3_history_{}", n));
+        fs::create_dir_all(&dir).unwrap();
+        let repo = Repository::init(&dir).unwrap();
+        commit_file(&repo, &dir, "a.c", "one\n", "first");
+        commit_file(&repo, &dir, "b.c", "other\n", "unrelated");
+        commit_file(&repo, &dir, "a.c", "two\n", "second");
+        let ga = GitAccess::new(String::new(), format!("{}/", dir.display()), "a.c".to_string(), CredentialProvider::none());
+
+        let log = file_log(&ga, "a.c", 10).unwrap();
+        let summaries: Vec<&str> = log.iter().map(|e| &e.summary[..]).collect();
+        assert_eq!(summaries, vec!["second", "first"]);
+        assert_eq!(file_at(&ga, "HEAD", "a.c").unwrap(), "two\n");
+        assert_eq!(file_at(&ga, &log[1].sha, "a.c").unwrap(), "one\n");
+        assert_eq!(file_at(&ga, &log[1].sha, "b.c").unwrap(), "");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
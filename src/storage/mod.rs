@@ -1 +1,9 @@
 pub mod storage_helper;
+pub mod credentials;
+pub mod coordinator;
+pub mod workspace;
+pub mod file_tree;
+pub mod diff;
+pub mod coauthors;
+pub mod history;
+pub mod backend;
@@ -1,49 +1,257 @@
 #![allow(dead_code)]
 use git2;
-use git2::{PushOptions,FileFavor, MergeOptions,
-    FetchOptions,RemoteCallbacks, Cred, Repository, Error};
-use git2::build::CheckoutBuilder;
+use git2::{PushOptions, MergeOptions,
+    FetchOptions, Repository, Error, ErrorCode, Oid, BranchType, Tree, Commit, RemoteCallbacks};
+use git2::build::{CheckoutBuilder, RepoBuilder};
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
 use std::path::Path;
+use storage::credentials::CredentialProvider;
+use storage::coauthors::Author;
+use storage::diff::merge3;
+
+pub const DEFAULT_BRANCH: &'static str = "master";
+
+/// `pair/<date>`, with a counter appended if a session already used that name.
+pub fn pair_branch_name(date: &str, existing: &[String]) -> String {
+    let name = format!("pair/{}", date);
+    if !existing.contains(&name) {
+        return name;
+    }
+    let mut n = 2;
+    while existing.contains(&format!("{}-{}", name, n)) {
+        n += 1;
+    }
+    format!("{}-{}", name, n)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PullKind {
+    UpToDate,
+    FastForward,
+    Merged,
+    // Stopped mid-merge, the conflicted files hold conflict markers.
+    Conflicts,
+}
+
+/// A file origin changed since our histories split.
+#[derive(Clone, Debug)]
+pub struct PulledFile {
+    pub path: String,
+    // Content at the merge base and on origin, empty when the file is absent.
+    pub base: String,
+    pub theirs: String,
+    // Gone from origin.
+    pub deleted: bool,
+    pub conflicted: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Pull {
+    pub kind: PullKind,
+    pub files: Vec<PulledFile>,
+}
+
+impl Pull {
+    pub fn conflicts(&self) -> Vec<String> {
+        self.files.iter().filter(|f| f.conflicted).map(|f| f.path.clone()).collect()
+    }
+}
+
+fn changed_paths(repo: &Repository, old: &Tree, new: &Tree) -> Result<Vec<String>, git2::Error> {
+    let diff = try!(repo.diff_tree_to_tree(Some(old), Some(new), None));
+    Ok(diff.deltas()
+        .filter_map(|d| d.new_file().path().or(d.old_file().path()).map(|p| p.to_string_lossy().into_owned()))
+        .collect())
+}
+
+pub fn blob_text(repo: &Repository, tree: &Tree, path: &str) -> Result<String, git2::Error> {
+    Ok(try!(blob_if_exists(repo, tree, path)).unwrap_or(String::new()))
+}
+
+fn blob_if_exists(repo: &Repository, tree: &Tree, path: &str) -> Result<Option<String>, git2::Error> {
+    let entry = match tree.get_path(Path::new(path)) {
+        Ok(entry) => entry,
+        Err(ref e) if e.code() == ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let blob = try!(repo.find_blob(entry.id()));
+    Ok(Some(String::from_utf8_lossy(blob.content()).into_owned()))
+}
+
+fn io_error(e: io::Error) -> git2::Error {
+    Error::from_str(&e.to_string())
+}
+
+fn read_local(path: &Path) -> Result<Option<String>, git2::Error> {
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(io_error(e)),
+    };
+    let mut s = String::new();
+    try!(file.read_to_string(&mut s).map_err(io_error));
+    Ok(Some(s))
+}
+
+fn write_local(path: &Path, text: &str) -> Result<(), git2::Error> {
+    if let Some(dir) = path.parent() {
+        try!(fs::create_dir_all(dir).map_err(io_error));
+    }
+    let mut file = try!(File::create(path).map_err(io_error));
+    file.write_all(text.as_bytes()).map_err(io_error)
+}
+
+/// A file whose working copy differs from HEAD when a pull starts.
+struct LocalChange {
+    path: String,
+    // Content at HEAD and in the working tree, None when absent.
+    head: Option<String>,
+    local: Option<String>,
+}
+
+/// Puts back the uncommitted changes a checkout went over, merged with what
+/// the checkout brought. A file gone from origin but changed here is kept.
+fn restore_local_changes(root: &Path, changes: Vec<LocalChange>, files: &mut [PulledFile]) -> Result<(), git2::Error> {
+    for LocalChange { path, head, local } in changes {
+        let full = root.join(&path);
+        let now = try!(read_local(&full));
+        let (text, conflicted) = match (now, local) {
+            (None, None) => continue,
+            (None, Some(local)) => (local, true),
+            (Some(now), local) => {
+                let head = head.unwrap_or(String::new());
+                let merged = merge3(&head, &local.unwrap_or(String::new()), &now);
+                (merged.text, merged.conflicts > 0)
+            },
+        };
+        try!(write_local(&full, &text));
+        for f in files.iter_mut().filter(|f| f.path == path) {
+            f.conflicted = f.conflicted || conflicted;
+        }
+    }
+    Ok(())
+}
 
 #[derive(Clone,PartialEq,Debug)]
 pub struct GitAccess {
     pub file_url: String,
     pub repo_url: String,
     pub local_url: String,
-    username: String,
-    password: String
+    // The branch the session commits to, pushes and pulls.
+    pub branch: String,
+    credentials: CredentialProvider,
 }
 
 impl GitAccess {
-    pub fn new(repo: String, local_path: String, file_path: String, usern: String, passwd: String) -> GitAccess {
+    pub fn new(repo: String, local_path: String, file_path: String, credentials: CredentialProvider) -> GitAccess {
         GitAccess {
             repo_url: repo,
             local_url: local_path,
             file_url: file_path,
-            username: usern,
-            password: passwd
+            branch: DEFAULT_BRANCH.to_string(),
+            credentials: credentials,
         }
     }
 
+    pub fn set_branch(&mut self, branch: String) {
+        self.branch = branch;
+    }
+
+    fn branch_ref(&self) -> String {
+        format!("refs/heads/{}", self.branch)
+    }
+
     pub fn default() -> GitAccess {
         GitAccess {
             repo_url: String::new(),
             local_url: String::new(),
             file_url: String::new(),
-            username: String::new(),
-            password: String::new()
+            branch: DEFAULT_BRANCH.to_string(),
+            credentials: CredentialProvider::none(),
         }
     }
 
     pub fn clone_repo(&self) -> Result<(), git2::Error> {
-        match Repository::clone(&self.repo_url, self.local_url.clone()) {
-            Ok(repo) => repo,
-            Err(e) => return Err(e)
-        };
+        let mut fo = FetchOptions::new();
+        fo.remote_callbacks(self.credentials.callbacks());
+        let mut builder = RepoBuilder::new();
+        builder.fetch_options(fo);
+        try!(builder.clone(&self.repo_url, Path::new(&self.local_url)));
+        Ok(())
+    }
+
+    /// Callbacks answering the remote's credential requests.
+    pub fn callbacks<'a>(&'a self) -> RemoteCallbacks<'a> {
+        self.credentials.callbacks()
+    }
+
+    /// The name and email git commits with on this machine.
+    pub fn identity(&self) -> Result<Author, git2::Error> {
+        let repo = try!(Repository::open(Path::new(&self.local_url)));
+        let sig = try!(repo.signature());
+        Ok(Author::new(sig.name().unwrap_or(""), sig.email().unwrap_or("")))
+    }
+
+    /// Updates the remote tracking branches without touching the working tree.
+    pub fn fetch(&self) -> Result<(), git2::Error> {
+        let repo = try!(Repository::open(Path::new(&self.local_url)));
+        let mut remote = try!(repo.find_remote("origin"));
+        let mut fo = FetchOptions::new();
+        fo.remote_callbacks(self.credentials.callbacks());
+        let default_refspecs: &[&str] = &[];
+        remote.fetch(default_refspecs, Some(&mut fo), None)
+    }
+
+    /// Names of the branches on origin, without the `origin/` prefix.
+    pub fn remote_branches(&self) -> Result<Vec<String>, git2::Error> {
+        let repo = try!(Repository::open(Path::new(&self.local_url)));
+        let mut names = vec![];
+        for branch in try!(repo.branches(Some(BranchType::Remote))) {
+            let (branch, _) = try!(branch);
+            if let Some(name) = try!(branch.name()) {
+                if name.starts_with("origin/") && name != "origin/HEAD" {
+                    names.push(name["origin/".len()..].to_string());
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    /// Checks out the session branch. If we don't have it yet it is started
+    /// from origin's copy when another peer already pushed it, otherwise from `base`.
+    pub fn checkout_branch(&self, base: &str) -> Result<(), git2::Error> {
+        let repo = try!(Repository::open(Path::new(&self.local_url)));
+        if repo.find_branch(&self.branch, BranchType::Local).is_err() {
+            let start = {
+                let candidates = [format!("origin/{}", self.branch), format!("origin/{}", base), base.to_string()];
+                let found = candidates.iter().filter_map(|c| repo.revparse_single(c).ok()).next();
+                match found {
+                    Some(obj) => try!(obj.peel_to_commit()),
+                    None => return Err(Error::from_str(&format!("no branch {} to start {} from", base, self.branch))),
+                }
+            };
+            let mut branch = try!(repo.branch(&self.branch, &start, false));
+            // Only exists once someone pushed the branch.
+            let _ = branch.set_upstream(Some(&format!("origin/{}", self.branch)));
+        }
+        let refname = self.branch_ref();
+        let tree = try!(repo.revparse_single(&refname));
+        let mut co = CheckoutBuilder::new();
+        co.safe();
+        try!(repo.checkout_tree(&tree, Some(&mut co)));
+        try!(repo.set_head(&refname));
+        println!("On branch {}", self.branch);
         Ok(())
     }
 
-    pub fn commit_path(&self, commit_message: &str) -> Result<(), Error>  {
+    pub fn commit_path(&self, commit_message: &str) -> Result<Oid, Error>  {
+        let file_url = self.file_url.clone();
+        self.commit_paths(&[file_url], commit_message)
+    }
+
+    pub fn commit_paths(&self, paths: &[String], commit_message: &str) -> Result<Oid, Error>  {
         println!("repo open {}", &self.local_url);
         let repo = match Repository::open(Path::new(&self.local_url)) {
             Ok(repo) => repo,
@@ -52,22 +260,31 @@ impl GitAccess {
         let sig = try!(repo.signature());
         let tree_id = {
             let mut index = try!(repo.index());
-            println!("adding path {}", &self.file_url);
-            try!(index.add_path(Path::new(&self.file_url)));
+            for path in paths {
+                println!("adding path {}", path);
+                try!(index.add_path(Path::new(path)));
+            }
+            try!(index.write());
             try!(index.write_tree_to(&repo))
         };
 
         let tree = try!(repo.find_tree(tree_id));
-        // lookup current HEAD commit
-        let head_ref = match repo.head() {
-            Ok(head_ref) =>  head_ref,
-            Err(e) => return Err(e)
-        };
-        let head_oid = head_ref.target().unwrap();
-        let commit = try!(repo.find_commit(head_oid));
-        // make that parent of new commit
-        try!(repo.commit(Some("HEAD"), &sig, &sig, commit_message, &tree, &[&commit]));
-        Ok(())
+        // The first commit of a fresh repo has no parent.
+        let mut parents = vec![];
+        if let Ok(head_ref) = repo.head() {
+            parents.push(try!(head_ref.peel_to_commit()));
+        }
+        // A pull that stopped on conflicts is concluded by this commit.
+        let merging = repo.refname_to_id("MERGE_HEAD").ok();
+        if let Some(merge_oid) = merging {
+            parents.push(try!(repo.find_commit(merge_oid)));
+        }
+        let parent_refs: Vec<&Commit> = parents.iter().collect();
+        let oid = try!(repo.commit(Some("HEAD"), &sig, &sig, commit_message, &tree, &parent_refs));
+        if merging.is_some() {
+            try!(repo.cleanup_state());
+        }
+        Ok(oid)
     }
 
     pub fn push(&self) -> Result<(), git2::Error> {
@@ -76,17 +293,13 @@ impl GitAccess {
             Err(e) => return Err(e)
         };
 
-        let mut cb = RemoteCallbacks::new();
-        cb.credentials(|_, _, _| {  // |repoName, options, cred_type|
-            // get credentials from user
-            Cred::userpass_plaintext(&self.username, &self.password)
-        });
         let remote = "origin";
         let mut remote = try!(repo.find_remote(remote));
         let mut opt_push = PushOptions::new();
-        opt_push.remote_callbacks(cb);
+        opt_push.remote_callbacks(self.credentials.callbacks());
         let x: Option<&mut PushOptions> = Some(&mut opt_push);
-        match remote.push(&["refs/heads/master"], x) {
+        let refspec = format!("{0}:{0}", self.branch_ref());
+        match remote.push(&[&refspec[..]], x) {
             Ok(p) => p,
             Err(e) => return Err(e)
         };
@@ -94,76 +307,105 @@ impl GitAccess {
         Ok(())
     }
 
-    pub fn pull_repo(&self) -> Result<(), git2::Error> {
-        let repo = match Repository::open(Path::new(&self.local_url)) {
-            Ok(repo) => repo,
-            Err(e) => return Err(e)
-        };
-
-        // Fetch from origin
-        let remote = "origin";
-        let mut remote = try!(repo.find_remote(remote));
-        let mut fo = FetchOptions::new();
-        match remote.fetch(&[], Some(&mut fo), Some("")) {
-            Ok(()) => println!("Fetch successful"),
-            Err(e) => println!("fetch error {}", e),
-        };
+    /// Brings the session branch up to date with origin. Fast-forwards when
+    /// possible, otherwise merges. Conflicts leave the repo mid-merge with
+    /// markers in the files, the next commit concludes the merge.
+    pub fn pull_repo(&self) -> Result<Pull, git2::Error> {
+        let repo = try!(Repository::open(Path::new(&self.local_url)));
+        {
+            let mut remote = try!(repo.find_remote("origin"));
+            let mut fo = FetchOptions::new();
+            fo.remote_callbacks(self.credentials.callbacks());
+            try!(remote.fetch(&[&self.branch[..]], Some(&mut fo), None));
+        }
+        let fetch_head = try!(repo.find_reference("FETCH_HEAD"));
+        let their_commit = try!(fetch_head.peel_to_commit());
+        let anno_commit = try!(repo.reference_to_annotated_commit(&fetch_head));
+        let (analysis, _) = try!(repo.merge_analysis(&[&anno_commit]));
+        if analysis.is_up_to_date() {
+            return Ok(Pull { kind: PullKind::UpToDate, files: vec![] });
+        }
 
-        // Merge with master
-        let mut mo = MergeOptions::new();
-        mo.file_favor(FileFavor::Theirs);
+        let head_commit = try!(try!(repo.head()).peel_to_commit());
+        let base_oid = try!(repo.merge_base(head_commit.id(), their_commit.id()));
+        let base_tree = try!(try!(repo.find_commit(base_oid)).tree());
+        let their_tree = try!(their_commit.tree());
+        let head_tree = try!(head_commit.tree());
+        let root = Path::new(&self.local_url);
+        let mut files = vec![];
+        // Uncommitted changes to the files that come in are set aside, the
+        // checkout below overwrites them and they are merged back after it.
+        let mut changes = vec![];
+        for path in try!(changed_paths(&repo, &base_tree, &their_tree)) {
+            let theirs = try!(blob_if_exists(&repo, &their_tree, &path));
+            let head = try!(blob_if_exists(&repo, &head_tree, &path));
+            let local = try!(read_local(&root.join(&path)));
+            if local != head {
+                changes.push(LocalChange { path: path.clone(), head: head, local: local });
+            }
+            files.push(PulledFile {
+                base: try!(blob_text(&repo, &base_tree, &path)),
+                deleted: theirs.is_none(),
+                theirs: theirs.unwrap_or(String::new()),
+                path: path,
+                conflicted: false,
+            });
+        }
         let mut co = CheckoutBuilder::new();
         co.force();
-        let fetch_head_ref = repo.find_reference("FETCH_HEAD").unwrap();
-        let anno_commit = repo.reference_to_annotated_commit(&fetch_head_ref).unwrap();
-        match repo.merge(&[&anno_commit], Some(&mut mo), Some(&mut co)) {
-            Ok(()) => println!("Merge successful"),
-            Err(e) => println!("Merge error {} {}", e, e.raw_class()),
-        };
+        for f in &files {
+            co.path(&f.path[..]);
+        }
 
-        // conclude merge by commit with two parents ORIG_HEAD and MERGE_HEAD
+        if analysis.is_fast_forward() {
+            try!(repo.reference(&self.branch_ref(), their_commit.id(), true, "pull: fast-forward"));
+            try!(repo.checkout_head(Some(&mut co)));
+            for f in files.iter().filter(|f| f.deleted) {
+                let full = root.join(&f.path);
+                if full.exists() {
+                    try!(fs::remove_file(&full).map_err(io_error));
+                }
+            }
+            try!(restore_local_changes(root, changes, &mut files));
+            return Ok(Pull { kind: PullKind::FastForward, files: files });
+        }
+
+        co.allow_conflicts(true);
+        co.conflict_style_merge(true);
+        try!(repo.merge(&[&anno_commit], Some(&mut MergeOptions::new()), Some(&mut co)));
+        let mut index = try!(repo.index());
+        if index.has_conflicts() {
+            for conflict in try!(index.conflicts()) {
+                let conflict = try!(conflict);
+                let entry = conflict.our.or(conflict.their).or(conflict.ancestor);
+                if let Some(entry) = entry {
+                    let path = String::from_utf8_lossy(&entry.path).into_owned();
+                    for f in files.iter_mut().filter(|f| f.path == path) {
+                        f.conflicted = true;
+                    }
+                }
+            }
+            try!(restore_local_changes(root, changes, &mut files));
+            return Ok(Pull { kind: PullKind::Conflicts, files: files });
+        }
         let sig = try!(repo.signature());
-        let tree_id = {
-            let mut index = try!(repo.index());
-            try!(index.write_tree_to(&repo))
-        };
-        let tree = try!(repo.find_tree(tree_id));
-        //find orig head
-        let head_oid = repo.refname_to_id("ORIG_HEAD").unwrap();
-        let head_commit = repo.find_commit(head_oid).unwrap();
-        //find remote head
-        let merge_head_oid = repo.refname_to_id("MERGE_HEAD").unwrap();
-        let merge_head_commit = repo.find_commit(merge_head_oid).unwrap();
-        // make that parent of new commit
-        try!(repo.commit(Some("HEAD"), &sig, &sig, "conclude merge", &tree, &[&head_commit, &merge_head_commit]));
+        let tree = try!(repo.find_tree(try!(index.write_tree_to(&repo))));
+        let message = format!("Merge origin/{}", self.branch);
+        try!(repo.commit(Some("HEAD"), &sig, &sig, &message, &tree, &[&head_commit, &their_commit]));
+        try!(repo.cleanup_state());
+        try!(restore_local_changes(root, changes, &mut files));
+        Ok(Pull { kind: PullKind::Merged, files: files })
+    }
 
-        Ok(())
+    /// The commit the session branch points at.
+    pub fn head_sha(&self) -> Result<String, git2::Error> {
+        let repo = try!(Repository::open(Path::new(&self.local_url)));
+        let head = try!(try!(repo.head()).peel_to_commit());
+        Ok(format!("{}", head.id()))
     }
 
     pub fn commit_config(&self, commit_message: &str, config_path: &str) -> Result<(), Error>  {
-        println!("repo open {}", &self.local_url);
-        let repo = match Repository::open(Path::new(&self.local_url)) {
-            Ok(repo) => repo,
-            Err(e) =>return Err(e)
-        };
-        let sig = try!(repo.signature());
-        let tree_id = {
-            let mut index = try!(repo.index());
-            println!("adding path {}", config_path);
-            try!(index.add_path(Path::new(&config_path)));
-            try!(index.write_tree_to(&repo))
-        };
-
-        let tree = try!(repo.find_tree(tree_id));
-        // lookup current HEAD commit
-        let head_ref = match repo.head() {
-            Ok(head_ref) =>  head_ref,
-            Err(e) => return Err(e)
-        };
-        let head_oid = head_ref.target().unwrap();
-        let commit = try!(repo.find_commit(head_oid));
-        // make that parent of new commit
-        try!(repo.commit(Some("HEAD"), &sig, &sig, commit_message, &tree, &[&commit]));
+        try!(self.commit_paths(&[config_path.to_string()], commit_message));
         Ok(())
     }
 
@@ -191,3 +433,15 @@ pub fn testing_func() {
     }
 }
 */
+
+#[cfg(test)]
+mod test{
+    use super::*;
+
+    #[test]
+    fn pair_branches_do_not_collide(){
+        assert_eq!(pair_branch_name("2016-05-01", &[]), "pair/2016-05-01");
+        let taken = vec!["master".to_string(), "pair/2016-05-01".to_string(), "pair/2016-05-01-2".to_string()];
+        assert_eq!(pair_branch_name("2016-05-01", &taken), "pair/2016-05-01-3");
+    }
+}
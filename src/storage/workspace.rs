@@ -0,0 +1,161 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use woot::site::Site;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SyncStatus {
+    // The file already holds the live content.
+    Clean,
+    Written,
+    // Someone else changed the file since we last synced, it was left alone.
+    ExternalChange,
+    // Same as above, but the live content was written over it anyway.
+    OverwroteExternalChange,
+}
+
+/// Keeps a live document and its file in the working tree in step. Remembers
+/// what was last read or written, so edits made outside the session are
+/// noticed instead of silently lost.
+pub struct Workspace {
+    path: PathBuf,
+    last_synced: Option<String>,
+}
+
+fn read_if_exists(path: &Path) -> io::Result<Option<String>> {
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut s = String::new();
+    try!(file.read_to_string(&mut s));
+    Ok(Some(s))
+}
+
+impl Workspace {
+    pub fn new<P: AsRef<Path>>(path: P) -> Workspace {
+        Workspace {
+            path: path.as_ref().to_path_buf(),
+            last_synced: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the file to seed the live document. A missing file is empty.
+    pub fn load(&mut self) -> io::Result<String> {
+        let content = try!(read_if_exists(&self.path)).unwrap_or(String::new());
+        self.last_synced = Some(content.clone());
+        Ok(content)
+    }
+
+    /// True if the file on disk differs from what we last read or wrote.
+    pub fn changed_externally(&self) -> io::Result<bool> {
+        let on_disk = try!(read_if_exists(&self.path));
+        Ok(match (on_disk, self.last_synced.as_ref()) {
+            (Some(ref disk), Some(synced)) => disk != synced,
+            (Some(_), None) => true,
+            (None, Some(synced)) => !synced.is_empty(),
+            (None, None) => false,
+        })
+    }
+
+    /// Writes `content` to the file. With `force` an external change is
+    /// overwritten, otherwise the file is left untouched.
+    pub fn flush(&mut self, content: &str, force: bool) -> io::Result<SyncStatus> {
+        let on_disk = try!(read_if_exists(&self.path));
+        if on_disk.as_ref().map(|d| &d[..]) == Some(content) {
+            self.last_synced = Some(content.to_string());
+            return Ok(SyncStatus::Clean);
+        }
+        let external = try!(self.changed_externally());
+        if external && !force {
+            return Ok(SyncStatus::ExternalChange);
+        }
+        let mut file = try!(File::create(&self.path));
+        try!(file.write_all(content.as_bytes()));
+        self.last_synced = Some(content.to_string());
+        Ok(if external { SyncStatus::OverwroteExternalChange } else { SyncStatus::Written })
+    }
+}
+
+/// Flushes every document returned by `documents` each `interval`. External
+/// changes are passed to `report` and never overwritten by an autosave.
+pub fn spawn_autosave<D, F>(documents: D, interval: Duration, report: F)
+    where D: Fn() -> Vec<(Arc<Mutex<Site>>, Arc<Mutex<Workspace>>)> + Send + 'static,
+          F: Fn(&Path) + Send + 'static
+{
+    thread::spawn(move || {
+        let mut reported: HashSet<PathBuf> = HashSet::new();
+        loop {
+            thread::sleep(interval);
+            for (site, workspace) in documents() {
+                let content = site.lock().unwrap().content();
+                let mut ws = workspace.lock().unwrap();
+                match ws.flush(&content, false) {
+                    Ok(SyncStatus::ExternalChange) => {
+                        // Only tell the user once per change.
+                        if reported.insert(ws.path().to_path_buf()) {
+                            report(ws.path());
+                        }
+                    },
+                    Ok(_) => {
+                        reported.remove(ws.path());
+                    },
+                    Err(e) => println!("Autosave of {} failed: {}", ws.path().display(), e),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::path::PathBuf;
+    use rand::random;
+
+    fn temp_file() -> PathBuf {
+        let n: u32 = random();
+        env::temp_dir().join(format!("p2p3_workspace_{}.txt", n))
+    }
+
+    #[test]
+    fn missing_file_loads_empty(){
+        let path = temp_file();
+        let mut ws = Workspace::new(&path);
+        assert_eq!(ws.load().unwrap(), "");
+        assert_eq!(ws.flush("hello", false).unwrap(), SyncStatus::Written);
+        assert_eq!(ws.flush("hello", false).unwrap(), SyncStatus::Clean);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn external_change_is_detected(){
+        let path = temp_file();
+        let mut ws = Workspace::new(&path);
+        ws.load().unwrap();
+        ws.flush("ours", false).unwrap();
+        File::create(&path).unwrap().write_all(b"theirs").unwrap();
+        assert!(ws.changed_externally().unwrap());
+        assert_eq!(ws.flush("ours again", false).unwrap(), SyncStatus::ExternalChange);
+        let mut s = String::new();
+        File::open(&path).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "theirs");
+        assert_eq!(ws.flush("ours again", true).unwrap(), SyncStatus::OverwroteExternalChange);
+        assert!(!ws.changed_externally().unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -10,6 +10,12 @@ use url::Url;
 use ws::{listen, Handler, Sender, Result, Message, Handshake, CloseCode, Error};
 use ws::util::Token;
 use crust::PeerId;
+use storage::history::LogEntry;
+use permission::grants::Grant;
+use compile::RunEvent;
+use compile::languages::Language;
+use compile::project::BuildTarget;
+use compile::diagnostics::Diagnostic;
 
 pub fn open_url(url: &str) -> IoRes<Child> {
     let (browser, args) = if cfg!(target_os = "linux") {
@@ -45,12 +51,68 @@ pub enum Command{
     InsertChar(usize, char),
     DeleteChar(usize),
     Output(String),
-    Commit,
+    // commit message
+    Commit(String),
     Compile,
+    // stops the running program
+    CancelRun,
+    // name of a build target to run in the repo
+    Build(String),
+    // the builds the repo offers
+    BuildTargets(Vec<BuildTarget>),
+    // text typed for the running program
+    Stdin(String),
+    // end of the running program's input
+    CloseStdin,
+    // runner, run id, what happened in the run, ours or a peer's
+    RunUpdate(PeerId, u64, RunEvent),
+    // peer, run id, mode, path: a peer without the toolchain asks us to run it
+    RunRequested(PeerId, u64, String, String),
+    // requester and run id of a request to run it or not
+    AcceptRun(PeerId, u64),
+    DeclineRun(PeerId, u64),
+    // what the last finished run found wrong, at live buffer positions
+    Diagnostics(Vec<Diagnostic>),
     DisableEditing(String),
     Mode(String),
+    // what the mode list offers
+    Languages(Vec<Language>),
     UpdateCursor(u32, u32),
     UpdatePeerCursor(PeerId, u32, u32),
+    Committed(String),
+    Pull,
+    // files left with conflicts by the pull
+    Pulled(Vec<String>),
+    // path of a file that was changed outside of the session
+    ExternalChange(String),
+    // paths of the text files tracked in the repo
+    FileTree(Vec<String>),
+    ListFiles,
+    OpenFile(String),
+    // file the editor now shows
+    ActiveFile(String),
+    // peer, file it has open
+    PeerFile(PeerId, String),
+    // log of the open file
+    History,
+    HistoryEntries(Vec<LogEntry>),
+    // diff of the live buffer against a commit, empty for HEAD
+    Diff(String),
+    DiffOutput(String),
+    // commit to go back to, optionally only lines start..end of the buffer
+    Revert(String, Option<(usize, usize)>),
+    // our role on the open file: none, viewer, editor or owner
+    Role(String),
+    EnableEditing,
+    // ask the editors for edit rights
+    RequestEdit,
+    // peer asking us for edit rights
+    EditRequested(PeerId),
+    // peer, minutes until the rights expire, None to keep them until revoked
+    GrantEdit(PeerId, Option<i64>),
+    RevokeEdit(PeerId),
+    // everyone holding granted edit rights
+    EditRights(Vec<Grant>),
 }
 
 pub type FnCommand = Box<Fn(&Command)->Res<String, String> + Send + Sync>;
@@ -1,7 +1,11 @@
 use std::sync::{Arc, Mutex, Once, ONCE_INIT};
 use std::mem;
 use storage::storage_helper::GitAccess;
-use compile::CompileMode;
+use storage::backend::{SharedStorage, PlainDirectory};
+use compile::languages::Languages;
+use permission::acl::Acl;
+use permission::permissions_handler::PermissionLevel;
+use permission::grants::Grants;
 use crust::PeerId;
 use rand::random;
 
@@ -16,7 +20,14 @@ pub struct P2P3Values {
     port: u16,
     url: String,
     git_access: GitAccess,
-    mode: CompileMode,
+    storage: SharedStorage,
+    // editor mode picked in the UI
+    mode: String,
+    languages: Languages,
+    session_id: u64,
+    acl: Acl,
+    permission: PermissionLevel,
+    grants: Grants,
 }
 
 impl P2P3Values {
@@ -25,7 +36,8 @@ impl P2P3Values {
         self.port = port;
         self.url = url;
         self.git_access = git_access;
-        self.mode = CompileMode::None;
+        self.mode = String::new();
+        self.session_id = random();
     }
 
     pub fn get_site_id(&self) -> PeerId {
@@ -48,14 +60,62 @@ impl P2P3Values {
         self.git_access.clone()
     }
 
-    pub fn get_compile_mode(&self) -> CompileMode {
+    pub fn get_storage(&self) -> SharedStorage {
+        self.storage.clone()
+    }
+
+    pub fn set_storage(&mut self, storage: SharedStorage) {
+        self.storage = storage;
+    }
+
+    pub fn get_compile_mode(&self) -> String {
         self.mode.clone()
     }
 
-    pub fn set_compile_mode(&mut self, mode: CompileMode) {
+    pub fn set_compile_mode(&mut self, mode: String) {
         self.mode = mode;
     }
 
+    pub fn get_languages(&self) -> Languages {
+        self.languages.clone()
+    }
+
+    pub fn set_languages(&mut self, languages: Languages) {
+        self.languages = languages;
+    }
+
+    pub fn get_session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    pub fn set_session_id(&mut self, session_id: u64) {
+        self.session_id = session_id;
+    }
+
+    pub fn get_acl(&self) -> Acl {
+        self.acl.clone()
+    }
+
+    pub fn set_acl(&mut self, acl: Acl) {
+        self.acl = acl;
+    }
+
+    pub fn get_permission_level(&self) -> PermissionLevel {
+        self.permission.clone()
+    }
+
+    pub fn set_permission_level(&mut self, level: PermissionLevel) {
+        self.permission = level;
+    }
+
+    pub fn get_grants(&self) -> Grants {
+        self.grants.clone()
+    }
+
+    pub fn set_grants(&mut self, grants: Grants) {
+        self.grants = grants;
+    }
+
 }
 
 pub fn p2p3_globals() -> P2P3Globals {
@@ -72,7 +132,13 @@ pub fn p2p3_globals() -> P2P3Globals {
                 port: 8080,
                 url: String::new(),
                 git_access: GitAccess::default(),
-                mode: CompileMode::None,
+                storage: Arc::new(PlainDirectory::new(String::new())),
+                mode: String::new(),
+                languages: Languages::builtin(),
+                session_id: random(),
+                acl: Acl::open(),
+                permission: PermissionLevel::Editor,
+                grants: Grants::new(),
             };
             let singleton = P2P3Globals {
                 inner: Arc::new((Mutex::new(globals)))
@@ -0,0 +1,329 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::path::{Component, Path};
+use std::sync::{Arc, Mutex};
+use super::site::{Site, UISend};
+use super::woot_char::WootChar;
+use super::operation::Operation;
+use network::MessagePasserT;
+use storage::workspace::Workspace;
+use storage::storage_helper::Pull;
+use storage::diff::merge3;
+use crust::PeerId;
+use msg::Msg;
+use permission::signing::Signer;
+
+/// A file of the repo that is open in the session.
+#[derive(Clone)]
+pub struct Document {
+    pub site: Arc<Mutex<Site>>,
+    pub workspace: Arc<Mutex<Workspace>>,
+}
+
+/// Whether `path` names a file inside the repo: relative, without `..`.
+pub fn is_repo_path(path: &str) -> bool {
+    !path.is_empty() && Path::new(path).components().all(|c| match c {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    })
+}
+
+/// Every document this peer has open, which one is shown in its editor, and
+/// which files the other peers are looking at.
+pub struct Documents<M: MessagePasserT<Msg> + Clone + 'static> {
+    mp: M,
+    local_url: String,
+    ui_send: Arc<UISend>,
+    docs: HashMap<String, Document>,
+    active: Arc<Mutex<String>>,
+    peer_files: BTreeMap<PeerId, String>,
+    // Every file each peer told us it has open, shown or not.
+    peer_docs: BTreeMap<PeerId, BTreeSet<String>>,
+    // Documents asked from a peer: who was asked, and the operations on
+    // them that arrived before the answer.
+    requested: BTreeMap<String, (PeerId, Vec<Operation>)>,
+    signer: Option<Arc<Signer>>,
+}
+
+impl<M: MessagePasserT<Msg> + Clone + 'static> Documents<M> {
+    pub fn new(mp: M, local_url: String, ui_send: Arc<UISend>) -> Documents<M> {
+        Documents {
+            mp: mp,
+            local_url: local_url,
+            ui_send: ui_send,
+            docs: HashMap::new(),
+            active: Arc::new(Mutex::new(String::new())),
+            peer_files: BTreeMap::new(),
+            peer_docs: BTreeMap::new(),
+            requested: BTreeMap::new(),
+            signer: None,
+        }
+    }
+
+    /// Every document opened from now on signs its operations with `signer`.
+    pub fn set_signer(&mut self, signer: Arc<Signer>) {
+        self.signer = Some(signer);
+    }
+
+    // Only the document shown in the editor may talk to the UI.
+    fn create(&mut self, path: &str) -> Document {
+        let ui_send = self.ui_send.clone();
+        let active = self.active.clone();
+        let doc = path.to_string();
+        let filtered: UISend = Box::new(move |comm| {
+            if *active.lock().unwrap() == doc {
+                (*ui_send)(comm);
+            }
+        });
+        let mut site = Site::new_document(self.mp.get_id().clone(), path.to_string(),
+                                          Box::new(self.mp.clone()), Arc::new(filtered));
+        if let Some(ref signer) = self.signer {
+            site.set_signer(signer.clone());
+        }
+        let document = Document {
+            site: Arc::new(Mutex::new(site)),
+            workspace: Arc::new(Mutex::new(Workspace::new(self.local_url.clone() + path))),
+        };
+        self.docs.insert(path.to_string(), document.clone());
+        document
+    }
+
+    pub fn get(&self, path: &str) -> Option<Document> {
+        self.docs.get(path).cloned()
+    }
+
+    pub fn all(&self) -> Vec<(String, Document)> {
+        self.docs.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    pub fn active(&self) -> String {
+        self.active.lock().unwrap().clone()
+    }
+
+    pub fn active_document(&self) -> Option<Document> {
+        self.get(&self.active())
+    }
+
+    pub fn set_active(&mut self, path: &str) {
+        *self.active.lock().unwrap() = path.to_string();
+    }
+
+    /// Opens `path` from the working tree without showing it.
+    pub fn open_in_background(&mut self, path: &str) -> io::Result<Document> {
+        if let Some(doc) = self.get(path) {
+            return Ok(doc);
+        }
+        let doc = self.create(path);
+        let content = try!(doc.workspace.lock().unwrap().load());
+        doc.site.lock().unwrap().parse_given_string(&content);
+        Ok(doc)
+    }
+
+    /// Opens `path` from the working tree, or just switches to it if it is
+    /// already open.
+    pub fn open_from_disk(&mut self, path: &str) -> io::Result<Document> {
+        let doc = try!(self.open_in_background(path));
+        self.set_active(path);
+        Ok(doc)
+    }
+
+    /// Remembers that `peer` was asked for `path`, only its answer is taken.
+    pub fn request_from_peer(&mut self, path: &str, peer: PeerId) {
+        self.requested.insert(path.to_string(), (peer, vec![]));
+    }
+
+    /// Keeps an operation on a document we are still waiting for, to apply
+    /// once it arrives. False if we are not waiting for it.
+    pub fn hold(&mut self, path: &str, operation: Operation) -> bool {
+        match self.requested.get_mut(path) {
+            Some(&mut (_, ref mut held)) => {
+                held.push(operation);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Opens `path` with the sequence sent by a peer that already had it open,
+    /// then applies the operations held since it was asked for. Refused
+    /// unless `sender` is the peer we asked.
+    pub fn open_from_peer(&mut self, path: &str, sender: &PeerId, chars: Vec<WootChar>) -> io::Result<Document> {
+        if !is_repo_path(path) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a path inside the repo"));
+        }
+        let asked = self.requested.get(path).map_or(false, |&(ref peer, _)| peer == sender);
+        if !asked {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "it was not asked for"));
+        }
+        let (_, held) = self.requested.remove(path).unwrap();
+        let doc = match self.get(path) {
+            Some(doc) => doc,
+            None => self.create(path),
+        };
+        try!(doc.workspace.lock().unwrap().load());
+        {
+            let mut site = doc.site.lock().unwrap();
+            site.adopt(chars);
+            for operation in held {
+                site.implement_operation(operation);
+            }
+        }
+        Ok(doc)
+    }
+
+    /// Feeds what a pull brought in into the open documents, merged with the
+    /// live content, and writes every document back over the checked out
+    /// files. Files left with conflicts are opened so they get resolved and
+    /// committed with the session. A document deleted on origin is closed
+    /// unless it was edited here, which counts as a conflict. Returns the
+    /// paths with conflicts.
+    pub fn merge_pulled(&mut self, pull: &Pull) -> Vec<String> {
+        let mut conflicts = vec![];
+        let mut gone = vec![];
+        for file in &pull.files {
+            match self.get(&file.path) {
+                Some(doc) => {
+                    let mut site = doc.site.lock().unwrap();
+                    let live = site.content();
+                    if file.deleted {
+                        if live == file.base {
+                            site.apply_text("");
+                            gone.push(file.path.clone());
+                        } else {
+                            conflicts.push(file.path.clone());
+                        }
+                        continue;
+                    }
+                    let merged = merge3(&file.base, &live, &file.theirs);
+                    site.apply_text(&merged.text);
+                    if merged.conflicts > 0 {
+                        conflicts.push(file.path.clone());
+                    }
+                },
+                None if file.conflicted => {
+                    if let Err(e) = self.open_in_background(&file.path) {
+                        println!("could not open {}: {}", file.path, e);
+                    }
+                    conflicts.push(file.path.clone());
+                },
+                None => {},
+            }
+        }
+        for path in gone {
+            self.docs.remove(&path);
+        }
+        for (path, doc) in self.all() {
+            let content = doc.site.lock().unwrap().content();
+            if let Err(e) = doc.workspace.lock().unwrap().flush(&content, true) {
+                println!("could not write {}: {}", path, e);
+            }
+        }
+        conflicts
+    }
+
+    /// Everyone who edited any open document since the last commit.
+    pub fn contributors(&self) -> Vec<PeerId> {
+        let mut all: Vec<PeerId> = vec![];
+        for doc in self.docs.values() {
+            for peer in doc.site.lock().unwrap().contributors() {
+                if !all.contains(&peer) {
+                    all.push(peer);
+                }
+            }
+        }
+        all
+    }
+
+    pub fn clear_contributors(&self) {
+        for doc in self.docs.values() {
+            doc.site.lock().unwrap().clear_contributors();
+        }
+    }
+
+    pub fn set_peer_file(&mut self, peer: PeerId, path: String) {
+        if path.is_empty() {
+            self.peer_files.remove(&peer);
+        } else {
+            self.peer_docs.entry(peer.clone()).or_insert(BTreeSet::new()).insert(path.clone());
+            self.peer_files.insert(peer, path);
+        }
+    }
+
+    /// The peers that have `path` open, to ask for its state.
+    pub fn peers_with(&self, path: &str) -> Vec<PeerId> {
+        self.peer_docs.iter().filter(|&(_, paths)| paths.contains(path)).map(|(peer, _)| peer.clone()).collect()
+    }
+
+    pub fn peer_files(&self) -> Vec<(PeerId, String)> {
+        self.peer_files.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::env;
+    use std::sync::Arc;
+    use crust::PeerId;
+    use rand::random;
+    use msg::Msg;
+    use network::faulty::MpRecorder;
+    use woot::site::UISend;
+
+    fn documents() -> Documents<MpRecorder<Msg>> {
+        let ui_send: UISend = Box::new(move|_| {});
+        let n: u32 = random();
+        let dir = env::temp_dir().join(format!("p2p3_docs_{}/", n));
+        Documents::new(MpRecorder::new(random()), dir.to_string_lossy().into_owned(), Arc::new(ui_send))
+    }
+
+    #[test]
+    fn only_requested_state_is_adopted(){
+        let mut docs = documents();
+        let asked: PeerId = random();
+        let other: PeerId = random();
+        assert!(docs.open_from_peer("a.rs", &asked, vec![]).is_err());
+        docs.request_from_peer("a.rs", asked.clone());
+        assert!(docs.open_from_peer("a.rs", &other, vec![]).is_err());
+        assert!(docs.open_from_peer("a.rs", &asked, vec![]).is_ok());
+        // Answered once, a second state is not taken.
+        assert!(docs.open_from_peer("a.rs", &asked, vec![]).is_err());
+
+        docs.request_from_peer("../a.rs", asked.clone());
+        assert!(docs.open_from_peer("../a.rs", &asked, vec![]).is_err());
+        docs.request_from_peer("/etc/passwd", asked.clone());
+        assert!(docs.open_from_peer("/etc/passwd", &asked, vec![]).is_err());
+        assert!(docs.get("../a.rs").is_none() && docs.get("/etc/passwd").is_none());
+    }
+
+    #[test]
+    fn held_operations_follow_the_state(){
+        let mut source = documents();
+        source.open_in_background("a.rs").unwrap();
+        let site = source.get("a.rs").unwrap().site;
+        site.lock().unwrap().generate_insert(0, 'a', false);
+        let chars = site.lock().unwrap().chars();
+        site.lock().unwrap().generate_insert(1, 'b', true);
+        let late = match source.mp.messages().pop() {
+            Some(Msg::WootOperation(_, signed)) => signed.operation,
+            other => panic!("expected an operation, got {:?}", other),
+        };
+
+        let mut docs = documents();
+        let peer: PeerId = random();
+        assert!(!docs.hold("a.rs", late.clone()));
+        docs.request_from_peer("a.rs", peer.clone());
+        assert!(docs.hold("a.rs", late));
+        let doc = docs.open_from_peer("a.rs", &peer, chars).unwrap();
+        assert_eq!(doc.site.lock().unwrap().content(), "ab");
+    }
+
+    #[test]
+    fn repo_paths(){
+        assert!(is_repo_path("src/main.rs"));
+        assert!(is_repo_path("./README"));
+        assert!(!is_repo_path(""));
+        assert!(!is_repo_path("/etc/passwd"));
+        assert!(!is_repo_path("src/../../x"));
+    }
+}
@@ -7,4 +7,5 @@ pub mod clock;
 pub mod operation;
 pub mod sequence;
 pub mod static_site;
+pub mod documents;
 pub mod operation_thread;
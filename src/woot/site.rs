@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 use rustc_serialize::json;
-use std::collections::VecDeque;
+use std::collections::{BTreeSet, VecDeque};
 use super::clock::Clock;
 use super::sequence::Sequence;
 use super::operation::Operation;
@@ -8,9 +8,11 @@ use super::woot_char::WootChar;
 use super::char_id::CharId;
 use super::char_id::create_char_id;
 use network::MessagePasserT;
+use storage::diff::{diff_lines, split_lines};
 use crust::PeerId;
 use ui::Command;
 use msg::Msg;
+use permission::signing::Signer;
 use std::sync::{Arc, Mutex};
 
 pub type UISend = Box<Fn(Command) + Send + Sync>;
@@ -18,24 +20,42 @@ pub type UISend = Box<Fn(Command) + Send + Sync>;
 #[derive(Clone)]
 pub struct Site {
     site_id: PeerId,
+    // Path of the file this site edits, relative to the repo root.
+    doc: String,
     logical_clock: Clock,
     sequence: Sequence,
     pub pool: VecDeque<Operation>,
+    // Sites that inserted or deleted characters since the last commit.
+    contributors: BTreeSet<PeerId>,
     message_passer: Arc<Mutex<Box<MessagePasserT<Msg>>>>,
+    // Signs what we broadcast so the other peers can check it came from us.
+    signer: Arc<Signer>,
     ui_send: Arc<UISend>
 }
 
 impl Site {
     pub fn new(site_id: PeerId, mp: Box<MessagePasserT<Msg>>, ui_send: Arc<UISend>) -> Site {
+        Site::new_document(site_id, String::new(), mp, ui_send)
+    }
+
+    pub fn new_document(site_id: PeerId, doc: String, mp: Box<MessagePasserT<Msg>>, ui_send: Arc<UISend>) -> Site {
         Site {
             site_id: site_id,
+            doc: doc,
             logical_clock: Clock::new(),
             sequence: Sequence::new(),
             pool: VecDeque::default(),
+            contributors: BTreeSet::new(),
             message_passer: Arc::new(Mutex::new(mp)),
+            signer: Arc::new(Signer::generate()),
             ui_send: ui_send}
     }
 
+    /// Signs with the session's key instead of the one made for this site.
+    pub fn set_signer(&mut self, signer: Arc<Signer>) {
+        self.signer = signer;
+    }
+
     pub fn implement_pool(&mut self) {
         loop {
             match self.pool.pop_front() {
@@ -57,6 +77,52 @@ impl Site {
         return self.sequence.content();
     }
 
+    pub fn document(&self) -> &str {
+        &self.doc
+    }
+
+    /// The whole sequence including hidden characters, to hand to a peer that
+    /// opens the document after us.
+    pub fn chars(&self) -> Vec<WootChar> {
+        self.sequence.list.clone()
+    }
+
+    /// Takes over the sequence of a peer instead of parsing the file, so that
+    /// both sides share the same character ids.
+    pub fn adopt(&mut self, chars: Vec<WootChar>) {
+        self.sequence.list = chars;
+    }
+
+    /// The ids of the visible characters, in order, so a spot in the content
+    /// can be found again after later edits.
+    pub fn visible_ids(&self) -> Vec<CharId> {
+        self.sequence.list.iter().filter(|c| c.visible).map(|c| c.id.clone()).collect()
+    }
+
+    /// Where the character `id` is in the content now. A deleted or unknown
+    /// one gives the spot where it was, the next visible character.
+    pub fn current_index(&self, id: &CharId) -> usize {
+        let mut index = 0;
+        for c in &self.sequence.list {
+            if c.id == *id {
+                return index;
+            }
+            if c.visible {
+                index += 1;
+            }
+        }
+        index
+    }
+
+    pub fn contributors(&self) -> Vec<PeerId> {
+        self.contributors.iter().cloned().collect()
+    }
+
+    /// Called once the contributions made so far are committed.
+    pub fn clear_contributors(&mut self) {
+        self.contributors.clear();
+    }
+
     pub fn generate_insert(&mut self, pos: usize, alpha: char, broadcast: bool) {
         self.logical_clock.increment();
         let mut position = !0;
@@ -75,6 +141,7 @@ impl Site {
         let cloned_wchar = new_wchar.clone();
         self.sequence.integrate_ins(new_wchar, cloned_wchar.prev_id.clone(), cloned_wchar.next_id.clone());
         if broadcast {
+            self.contributors.insert(self.site_id.clone());
             self.broadcast(Operation::Insert { w_char: cloned_wchar, from_site: self.site_id })
         }
     }
@@ -89,11 +156,35 @@ impl Site {
             None => false
         };
         if value_present {
+            self.contributors.insert(self.site_id.clone());
             self.sequence.integrate_del(&new_wchar);
             self.broadcast(Operation::Delete{ w_char: new_wchar.clone(), from_site: self.site_id })
         }
     }
 
+    /// Turns the content into `text` with ordinary insert and delete operations,
+    /// so every peer and the editor follow along.
+    pub fn apply_text(&mut self, text: &str) {
+        let old = self.content();
+        let old_lines = split_lines(&old);
+        let new_lines = split_lines(text);
+        // Back to front, so the positions of earlier hunks stay valid.
+        for hunk in diff_lines(&old, text).iter().rev() {
+            let start: usize = old_lines[..hunk.old_start].iter().map(|l| l.chars().count()).sum();
+            let removed: usize = old_lines[hunk.old_start..hunk.old_start + hunk.old_len].iter()
+                .map(|l| l.chars().count()).sum();
+            for _ in 0..removed {
+                self.generate_del(start);
+                (*self.ui_send)(Command::DeleteChar(start));
+            }
+            let inserted = new_lines[hunk.new_start..hunk.new_start + hunk.new_len].concat();
+            for (i, c) in inserted.chars().enumerate() {
+                self.generate_insert(start + i, c, true);
+                (*self.ui_send)(Command::InsertChar(start + i, c));
+            }
+        }
+    }
+
     pub fn implement_operation(&mut self, operation: Operation) {
         println!("Trying to implement_operation");
         let given_operation = operation.clone();
@@ -109,6 +200,9 @@ impl Site {
                     if self.can_integrate_id(&w_char.prev_id) && self.can_integrate_id(&w_char.next_id) {
                         println!("Can integrate");
                         self.sequence.integrate_ins(new_value, prev_id, next_id);
+                        if let CharId::Regular { site_id, .. } = id.clone() {
+                            self.contributors.insert(site_id);
+                        }
                         let visible_index = self.sequence.visible_index_of_id(&id);
                         (*self.ui_send)(Command::InsertChar(visible_index, w_char.value));
                     } else {
@@ -118,10 +212,11 @@ impl Site {
                     }
                 }
             },
-            Operation::Delete {w_char, from_site:_} => {
+            Operation::Delete {w_char, from_site} => {
                 let exists = self.sequence.exists(&w_char.id);
                 let visible_index = self.sequence.visible_index_of_id(&w_char.id);
                 if exists {
+                    self.contributors.insert(from_site);
                     // let can_integrate = self.can_integrate_id(&w_char.prev_id) && self.can_integrate_id(&w_char.next_id);
                     // if can_integrate {
                         self.sequence.integrate_del(&w_char);
@@ -137,7 +232,8 @@ impl Site {
 
     fn broadcast(&self, operation: Operation) {
         // Call network manager to broadcast
-        unwrap_result!(self.message_passer.lock()).broadcast(Msg::WootOperation(operation));
+        let signed = self.signer.sign(&self.doc, operation);
+        unwrap_result!(self.message_passer.lock()).broadcast(Msg::WootOperation(self.doc.clone(), signed));
     }
 
     pub fn reception(&mut self, encoded: String) {
@@ -163,6 +259,7 @@ mod test{
     use msg::Msg;
     use rand::random;
     use network::{MessagePasserT, Packet};
+    use network::faulty::{FaultyPasser, FaultPlan, MpRecorder};
     use woot::operation::Operation;
     use woot::woot_char::WootChar;
     use woot::char_id::CharId;
@@ -281,4 +378,63 @@ mod test{
         let value = site.content();
         assert_eq!(value, file_contents);
     }
+
+    #[test]
+    fn test_converges_over_faulty_network() {
+        let id1: PeerId = random();
+        let rec = MpRecorder::new(id1.clone());
+        let mut plan = FaultPlan::reliable(2016);
+        plan.duplicate_rate = 0.3;
+        plan.delay_rate = 0.3;
+        plan.max_delay = 4;
+        plan.reorder_rate = 0.3;
+        let faulty = FaultyPasser::new(rec.clone(), plan);
+        let ui_send: UISend = Box::new(move|_| {});
+        let mut site = Site::new(id1, Box::new(faulty.clone()), Arc::new(ui_send));
+        let mut site2 = create_test_site();
+        for (i, c) in "pair programming".chars().enumerate() {
+            site.generate_insert(i, c, true);
+        }
+        faulty.flush();
+        for msg in rec.messages() {
+            if let Msg::WootOperation(_, signed) = msg {
+                site2.implement_operation(signed.operation);
+            }
+        }
+        site2.implement_pool();
+        assert_eq!(site2.content(), site.content(), "injected faults: {:?}", faulty.events());
+    }
+
+    #[test]
+    fn test_current_index_follows_edits() {
+        let mut site = create_test_site();
+        site.parse_given_string("abc");
+        let ids = site.visible_ids();
+        site.generate_insert(0, 'x', false);
+        site.generate_del(2);
+        assert_eq!(site.content(), "xac");
+        assert_eq!(site.current_index(&ids[0]), 1);
+        assert_eq!(site.current_index(&ids[1]), 2);
+        assert_eq!(site.current_index(&ids[2]), 2);
+    }
+
+    #[test]
+    fn test_apply_text() {
+        let mut site = create_test_site();
+        site.parse_given_string("int a;\nint b;\nint c;\n");
+        site.apply_text("int a;\nlong b;\nint c;\nint d;\n");
+        assert_eq!(site.content(), "int a;\nlong b;\nint c;\nint d;\n");
+    }
+
+    #[test]
+    fn test_contributors() {
+        let id1: PeerId = random();
+        let mut site = create_test_site_with_id(id1.clone());
+        site.parse_given_string("loaded from disk");
+        assert!(site.contributors().is_empty());
+        site.generate_insert(0, '!', true);
+        assert_eq!(site.contributors(), vec![id1]);
+        site.clear_contributors();
+        assert!(site.contributors().is_empty());
+    }
 }